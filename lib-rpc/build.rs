@@ -1,4 +1,14 @@
+use std::env;
+use std::path::PathBuf;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("user-service.proto")?;
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+    tonic_build::configure()
+        // Дескрипторы нужны сервису рефлексии, см. lib_rpc::FILE_DESCRIPTOR_SET
+        .file_descriptor_set_path(out_dir.join("user_service_descriptor.bin"))
+        .compile_protos(
+            &["user-service.proto", "admin.proto"],
+            &["."],
+        )?;
     Ok(())
 }
@@ -1,3 +1,11 @@
 pub mod userpb {
     tonic::include_proto!("userpb");
 }
+
+pub mod adminpb {
+    tonic::include_proto!("adminpb");
+}
+
+/// Дескрипторы всех proto-файлов для сервиса рефлексии
+pub const FILE_DESCRIPTOR_SET: &[u8] =
+    tonic::include_file_descriptor_set!("user_service_descriptor");
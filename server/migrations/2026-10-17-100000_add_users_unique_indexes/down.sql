@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS users_email_lower_key;
+
+DROP INDEX IF EXISTS users_username_lower_key;
@@ -0,0 +1,28 @@
+-- Имена пользователей и почта уникальны без учёта регистра.
+-- Индексы покрывают и мягко удалённых пользователей, чтобы их можно было восстановить.
+-- Существующие дубли ("Alice" и "alice") нужно сначала переименовать вручную:
+-- миграция перечисляет их и откатывается, не оставляя индексов
+DO $$
+DECLARE
+    conflicts TEXT;
+BEGIN
+    SELECT string_agg(field || ' ' || names, '; ') INTO conflicts
+    FROM (
+        SELECT 'username' AS field, string_agg(format('%s (%s)', username, id), ', ' ORDER BY id) AS names
+        FROM users
+        GROUP BY lower(username)
+        HAVING count(*) > 1
+        UNION ALL
+        SELECT 'email', string_agg(format('%s (%s)', email, id), ', ' ORDER BY id)
+        FROM users
+        GROUP BY lower(email)
+        HAVING count(*) > 1
+    ) AS duplicates;
+    IF conflicts IS NOT NULL THEN
+        RAISE EXCEPTION 'Case-insensitive duplicates must be resolved before adding unique indexes: %', conflicts;
+    END IF;
+END $$;
+
+CREATE UNIQUE INDEX users_username_lower_key ON users (lower(username));
+
+CREATE UNIQUE INDEX users_email_lower_key ON users (lower(email));
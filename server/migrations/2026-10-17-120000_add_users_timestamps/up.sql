@@ -0,0 +1,21 @@
+ALTER TABLE users ADD COLUMN created_at TIMESTAMPTZ;
+
+ALTER TABLE users ADD COLUMN updated_at TIMESTAMPTZ;
+
+-- Для существующих строк время регистрации берётся из первых 48 бит UUIDv7 (миллисекунды Unix),
+-- для UUID других версий используется время миграции
+UPDATE users
+SET created_at = CASE
+        WHEN substr(id::text, 15, 1) = '7' THEN to_timestamp(
+            ('x' || lpad(replace(substr(id::text, 1, 13), '-', ''), 16, '0'))::bit(64)::bigint / 1000.0
+        )
+        ELSE now()
+    END;
+
+UPDATE users SET updated_at = created_at;
+
+ALTER TABLE users ALTER COLUMN created_at SET NOT NULL, ALTER COLUMN created_at SET DEFAULT now();
+
+ALTER TABLE users ALTER COLUMN updated_at SET NOT NULL, ALTER COLUMN updated_at SET DEFAULT now();
+
+CREATE INDEX users_created_at_idx ON users (created_at);
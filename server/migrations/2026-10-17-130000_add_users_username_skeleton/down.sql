@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS users_username_skeleton_idx;
+
+ALTER TABLE users DROP COLUMN IF EXISTS username_skeleton;
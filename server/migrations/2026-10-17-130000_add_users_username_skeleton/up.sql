@@ -0,0 +1,8 @@
+-- Скелет имени по Unicode TR39: у похожих имён ("admin" латиницей и "аdmin" с кириллической "а")
+-- он совпадает. В SQL его не вычислить, существующие строки заполняет сервер после миграций
+ALTER TABLE users ADD COLUMN username_skeleton VARCHAR;
+
+-- Индекс не уникальный: среди уже зарегистрированных могут быть похожие имена,
+-- новые совпадения отклоняет сервер при регистрации и переименовании
+
+CREATE INDEX users_username_skeleton_idx ON users (username_skeleton);
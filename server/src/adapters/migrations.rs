@@ -0,0 +1,324 @@
+use std::collections::HashSet;
+
+use diesel::migration::{Migration, MigrationSource};
+use diesel::pg::Pg;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel::sql_types::BigInt;
+use diesel::{sql_query, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use log::{error, info, warn};
+use uuid::Uuid;
+
+use crate::adapters::schema::users;
+use crate::app::validation::{normalize_username, username_skeleton};
+use crate::errors::MigrationError;
+
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+/// Ключ advisory-блокировки, чтобы реплики и ручной запуск не меняли схему одновременно
+const MIGRATION_LOCK_KEY: i64 = 0x7573_6572_5f6d_6967;
+
+/// Встроенная миграция и признак того, что она применена
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationState {
+    pub name: String,
+    pub applied: bool,
+}
+
+fn failed(e: impl std::fmt::Display) -> MigrationError {
+    MigrationError::MigrationFailed(e.to_string())
+}
+
+/// Встроенные миграции в порядке применения
+fn embedded() -> Result<Vec<Box<dyn Migration<Pg>>>, MigrationError> {
+    let mut migrations = MigrationSource::<Pg>::migrations(&MIGRATIONS).map_err(failed)?;
+    migrations.sort_by(|a, b| a.name().version().cmp(&b.name().version()));
+    Ok(migrations)
+}
+
+fn name_of(version: &str) -> Result<String, MigrationError> {
+    Ok(embedded()?
+        .iter()
+        .find(|migration| migration.name().version().to_string() == version)
+        .map_or_else(
+            || version.to_string(),
+            |migration| migration.name().to_string(),
+        ))
+}
+
+pub fn status(conn: &mut PgConnection) -> Result<Vec<MigrationState>, MigrationError> {
+    let applied: HashSet<String> = conn
+        .applied_migrations()
+        .map_err(failed)?
+        .iter()
+        .map(|version| version.to_string())
+        .collect();
+    Ok(embedded()?
+        .iter()
+        .map(|migration| MigrationState {
+            name: migration.name().to_string(),
+            applied: applied.contains(&migration.name().version().to_string()),
+        })
+        .collect())
+}
+
+/// Применяет все ожидающие миграции, возвращает имена применённых
+pub fn up(conn: &mut PgConnection) -> Result<Vec<String>, MigrationError> {
+    with_lock(conn, |conn| {
+        let versions: Vec<String> = conn
+            .run_pending_migrations(MIGRATIONS)
+            .map_err(failed)?
+            .iter()
+            .map(|version| version.to_string())
+            .collect();
+        backfill_usernames(conn)?;
+        versions.iter().map(|version| name_of(version)).collect()
+    })
+}
+
+/// Скелет и NFKC не вычислить в SQL, поэтому строки без скелета (созданные до его появления,
+/// после redo или старой репликой при обновлении) дополняются здесь: имя приводится к NFKC,
+/// как при записи, и сохраняется его скелет. Вызывается при каждом запуске сервера
+pub fn backfill_usernames(conn: &mut PgConnection) -> Result<(), MigrationError> {
+    let pending: Vec<(Uuid, String)> = users::table
+        .filter(users::username_skeleton.is_null())
+        .select((users::id, users::username))
+        .load(conn)
+        .map_err(failed)?;
+    for (user_id, user_name) in &pending {
+        let target = users::table.filter(users::id.eq(user_id));
+        let normalized = normalize_username(user_name);
+        let result = diesel::update(target)
+            .set((
+                users::username.eq(&normalized),
+                users::username_skeleton.eq(username_skeleton(&normalized)),
+            ))
+            .execute(conn);
+        match result {
+            Ok(_) => {}
+            // Нормализованное имя уже занято, имя остаётся как есть до ручного переименования
+            Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                warn!(
+                    "Username of user {} clashes with another user after NFKC normalization",
+                    user_id
+                );
+                diesel::update(target)
+                    .set(users::username_skeleton.eq(username_skeleton(user_name)))
+                    .execute(conn)
+                    .map_err(failed)?;
+            }
+            Err(e) => return Err(failed(e)),
+        }
+    }
+    if !pending.is_empty() {
+        info!("Normalized usernames of {} users", pending.len());
+    }
+    Ok(())
+}
+
+/// Откатывает до steps последних применённых миграций, возвращает имена откаченных
+pub fn down(conn: &mut PgConnection, steps: u32) -> Result<Vec<String>, MigrationError> {
+    with_lock(conn, |conn| {
+        let mut reverted = Vec::new();
+        for _ in 0..steps {
+            if conn.applied_migrations().map_err(failed)?.is_empty() {
+                break;
+            }
+            let version = conn.revert_last_migration(MIGRATIONS).map_err(failed)?;
+            reverted.push(name_of(&version.to_string())?);
+        }
+        Ok(reverted)
+    })
+}
+
+/// Откатывает и заново применяет последнюю применённую миграцию
+pub fn redo(conn: &mut PgConnection) -> Result<String, MigrationError> {
+    with_lock(conn, |conn| {
+        let version = conn
+            .revert_last_migration(MIGRATIONS)
+            .map_err(failed)?
+            .to_string();
+        let migration = embedded()?
+            .into_iter()
+            .find(|migration| migration.name().version().to_string() == version)
+            .ok_or_else(|| failed(format!("Migration {} is not embedded", version)))?;
+        conn.run_migration(migration.as_ref()).map_err(failed)?;
+        backfill_usernames(conn)?;
+        Ok(migration.name().to_string())
+    })
+}
+
+/// Сессионная блокировка снимается явно, а при обрыве соединения - самим Postgres
+fn with_lock<T>(
+    conn: &mut PgConnection, f: impl FnOnce(&mut PgConnection) -> Result<T, MigrationError>,
+) -> Result<T, MigrationError> {
+    info!("Waiting for the migration lock");
+    sql_query("SELECT pg_advisory_lock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK_KEY)
+        .execute(conn)
+        .map_err(failed)?;
+    let result = f(conn);
+    if let Err(e) = sql_query("SELECT pg_advisory_unlock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK_KEY)
+        .execute(conn)
+    {
+        error!("Failed to release the migration lock: {}", e);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::postgres::DbRepository;
+    use crate::config::PoolConfig;
+    use crate::types::User;
+    use chrono::Utc;
+    use pretty_assertions::assert_eq;
+    use serial_test::serial;
+    use std::env;
+
+    #[test]
+    #[serial]
+    fn test_status_and_redo() {
+        dotenv::dotenv().ok();
+        let database_url = env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
+        let repo = DbRepository::new(database_url, &PoolConfig::default()).unwrap();
+        let conn = &mut repo.get_conn().unwrap();
+
+        up(conn).unwrap();
+        let states = status(conn).unwrap();
+        assert!(states.iter().all(|state| state.applied));
+        let last = states.last().unwrap().name.clone();
+
+        assert_eq!(redo(conn).unwrap(), last);
+        assert_eq!(status(conn).unwrap(), states);
+
+        assert_eq!(down(conn, 1).unwrap(), vec![last.clone()]);
+        let states = status(conn).unwrap();
+        assert_eq!(states.last().unwrap().applied, false);
+        assert_eq!(up(conn).unwrap(), vec![last]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_pending_migrations_without_auto_migrate() {
+        dotenv::dotenv().ok();
+        let database_url = env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
+        let repo = DbRepository::new(database_url, &PoolConfig::default()).unwrap();
+        let conn = &mut repo.get_conn().unwrap();
+        up(conn).unwrap();
+        repo.manage_migration(false).unwrap();
+
+        down(conn, 1).unwrap();
+        assert!(matches!(
+            repo.manage_migration(false),
+            Err(MigrationError::Pending(1))
+        ));
+        repo.manage_migration(true).unwrap();
+        assert!(status(conn).unwrap().iter().all(|state| state.applied));
+    }
+
+    #[test]
+    #[serial]
+    fn test_backfill_normalizes_usernames() {
+        dotenv::dotenv().ok();
+        let database_url = env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
+        let repo = DbRepository::new(database_url, &PoolConfig::default()).unwrap();
+        let conn = &mut repo.get_conn().unwrap();
+        up(conn).unwrap();
+
+        // Записи без скелета, как от старой реплики: полноширинные буквы NFKC сводит к ASCII
+        let user = |username: &str, email: &str| User {
+            id: Uuid::now_v7(),
+            username: username.to_string(),
+            email: email.to_string(),
+            deleted_at: None,
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        let legacy = [
+            user("ｂａｃｋｆｉｌｌ", "backfill@example.com"),
+            user("backfill_taken", "backfill_taken@example.com"),
+            user("ｂａｃｋｆｉｌｌ_ｔａｋｅｎ", "backfill_clash@example.com"),
+        ];
+        diesel::insert_into(users::table)
+            .values(&legacy[..])
+            .execute(conn)
+            .unwrap();
+
+        backfill_usernames(conn).unwrap();
+        let stored: Vec<(String, Option<String>)> = legacy
+            .iter()
+            .map(|user| {
+                users::table
+                    .filter(users::id.eq(user.id))
+                    .select((users::username, users::username_skeleton))
+                    .first(conn)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            stored,
+            vec![
+                ("backfill".to_string(), Some(username_skeleton("backfill"))),
+                (
+                    "backfill_taken".to_string(),
+                    Some(username_skeleton("backfill_taken"))
+                ),
+                // Нормализованное имя занято, остаётся прежним, но скелет появляется
+                (
+                    "ｂａｃｋｆｉｌｌ_ｔａｋｅｎ".to_string(),
+                    Some(username_skeleton("ｂａｃｋｆｉｌｌ_ｔａｋｅｎ"))
+                ),
+            ]
+        );
+
+        let ids: Vec<Uuid> = legacy.iter().map(|user| user.id).collect();
+        diesel::delete(users::table.filter(users::id.eq_any(ids)))
+            .execute(conn)
+            .unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_unique_indexes_report_duplicates() {
+        dotenv::dotenv().ok();
+        let database_url = env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
+        let repo = DbRepository::new(database_url, &PoolConfig::default()).unwrap();
+        let conn = &mut repo.get_conn().unwrap();
+        up(conn).unwrap();
+        sql_query("DELETE FROM users").execute(conn).unwrap();
+
+        // Откат до схемы без уникальных индексов, где дубли по регистру ещё возможны
+        let states = status(conn).unwrap();
+        let position = states
+            .iter()
+            .position(|state| state.name.ends_with("add_users_unique_indexes"))
+            .unwrap();
+        down(conn, (states.len() - position) as u32).unwrap();
+        sql_query(
+            "INSERT INTO users (id, username, email) VALUES \
+             ('0189a30a-60c7-7135-b683-7d7f3783d4b1', 'Alice', 'alice@example.com'), \
+             ('0189a30a-60c7-7135-b683-7d7f3783d4b2', 'alice', 'other@example.com')",
+        )
+        .execute(conn)
+        .unwrap();
+
+        let error = up(conn).unwrap_err().to_string();
+        assert!(
+            error.contains(
+                "Case-insensitive duplicates must be resolved before adding unique indexes: \
+                 username Alice (0189a30a-60c7-7135-b683-7d7f3783d4b1), \
+                 alice (0189a30a-60c7-7135-b683-7d7f3783d4b2)"
+            ),
+            "{}",
+            error
+        );
+        assert!(!status(conn).unwrap()[position].applied);
+
+        sql_query("DELETE FROM users").execute(conn).unwrap();
+        assert_eq!(up(conn).unwrap().len(), states.len() - position);
+    }
+}
@@ -1,3 +1,5 @@
+pub mod migrations;
 pub mod postgres;
+pub mod retry;
 
 pub mod schema;
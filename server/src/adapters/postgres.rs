@@ -1,117 +1,196 @@
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::Instant;
+
 use diesel::r2d2::{ConnectionManager, PooledConnection};
-use diesel::{r2d2, sql_query, PgConnection, RunQueryDsl};
-use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use diesel::{r2d2, PgConnection};
 use log::{debug, error, info, warn};
+use tokio::sync::watch;
 
-use crate::errors::{DbError, MigrationError};
-
-pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+use crate::adapters::migrations;
+use crate::adapters::retry::{backoff_delay, RetryStatus};
+use crate::config::{PoolConfig, RetryConfig};
+use crate::errors::{DbError, MigrationError, RepoError};
+use crate::metrics;
 
 /// Определение алиаса Pool для библиотечного типа Pool, который принимает структуру для подключения к БД PostgreSQL
 pub(crate) type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
 pub struct DbRepository {
     pub(crate) pool: Pool,
+    /// Последняя ошибка получения соединения из пула, см. checkout_failures
+    checkout_failures: Arc<watch::Sender<String>>,
 }
 
 impl DbRepository {
-    pub fn new(database_url: String) -> Result<Self, DbError> {
+    /// Блокирующий вызов с единственной попыткой подключения,
+    /// при старте сервиса используется connect_with_retry.
+    /// Миграции не применяются, см. manage_migration
+    pub fn new(database_url: String, pool_config: &PoolConfig) -> Result<Self, DbError> {
         debug!(
-            "Creating new DbRepository with database URL: {}",
-            &database_url
+            "Creating new DbRepository with database URL: {}, pool: {:?}",
+            &database_url, pool_config
         );
 
-        ///Переделать это
-        let mut first_attempt = true;
-        let pool = loop {
-            let manager = ConnectionManager::<PgConnection>::new(database_url.clone());
-            match Pool::builder().build(manager) {
-                Ok(p) => {
-                    if !first_attempt {
-                        info!("Connection restored");
+        let manager = ConnectionManager::<PgConnection>::new(database_url);
+        let pool = Pool::builder()
+            .max_size(pool_config.max_size)
+            .connection_timeout(pool_config.connection_timeout)
+            .idle_timeout(pool_config.idle_timeout)
+            .build(manager)
+            .map_err(|e| {
+                error!("Failed to create pool: {}", e);
+                DbError::PoolError(e)
+            })?;
+        Ok(DbRepository::from_pool(pool))
+    }
+
+    pub(crate) fn from_pool(pool: Pool) -> Self {
+        DbRepository {
+            pool,
+            checkout_failures: Arc::new(watch::channel(String::new()).0),
+        }
+    }
+
+    /// Ошибки получения соединения из пула в запросах, по ним задача готовности
+    /// переводит сервис в NOT_SERVING, не дожидаясь очередной проверки
+    pub fn checkout_failures(&self) -> watch::Receiver<String> {
+        self.checkout_failures.subscribe()
+    }
+
+    /// Подключение с повторными попытками по RetryConfig. Перед каждой паузой
+    /// вызывается on_retry, чтобы состояние ожидания было видно снаружи
+    pub async fn connect_with_retry(
+        database_url: String, pool_config: PoolConfig, retry_config: &RetryConfig,
+        on_retry: impl Fn(&RetryStatus),
+    ) -> Result<Self, DbError> {
+        let started = Instant::now();
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let database_url = database_url.clone();
+            let pool_config = pool_config.clone();
+            let result =
+                tokio::task::spawn_blocking(move || DbRepository::new(database_url, &pool_config))
+                    .await
+                    .map_err(DbError::ConnectionError)
+                    .and_then(|result| result);
+            let last_error = match result {
+                Ok(repo) => {
+                    if attempt > 1 {
+                        info!("Connected to the database on attempt {}", attempt);
                     }
-                    break p;
-                }
-                Err(e) => {
-                    error!("Failed to create pool: {}", e);
-                    warn!("Retrying to connect in 5 seconds...");
-                    std::thread::sleep(Duration::from_secs(5));
-                    first_attempt = false;
+                    return Ok(repo);
                 }
+                Err(e) => e,
+            };
+
+            let delay = backoff_delay(retry_config, attempt, &mut rand::thread_rng());
+            if attempt >= retry_config.max_attempts
+                || started.elapsed() + delay > retry_config.max_elapsed
+            {
+                return Err(DbError::RetriesExhausted {
+                    attempts: attempt,
+                    elapsed: started.elapsed(),
+                    last_error: Box::new(last_error),
+                });
             }
-        };
-        let repo = DbRepository { pool };
 
-        // Применение миграций при создании нового репозитория
-        repo.manage_migration()?;
+            let status = RetryStatus {
+                attempt,
+                max_attempts: retry_config.max_attempts,
+                delay,
+                last_error: last_error.to_string(),
+            };
+            warn!(
+                "Database connection attempt {}/{} failed: {}. Retrying in {:?}",
+                status.attempt, status.max_attempts, status.last_error, status.delay
+            );
+            on_retry(&status);
+            tokio::time::sleep(delay).await;
+        }
+    }
 
-        Ok(repo)
+    /// Выполняет синхронный запрос diesel в пуле блокирующих потоков tokio,
+    /// чтобы ожидание соединения и запроса не занимало рабочие потоки рантайма
+    pub(crate) async fn run<T, F>(&self, query: F) -> Result<T, RepoError>
+    where
+        F: FnOnce(&mut PgConnection) -> Result<T, RepoError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool = self.pool.clone();
+        let checkout_failures = self.checkout_failures.clone();
+        // Контекст трассировки привязан к потоку, переносим его в блокирующий поток для логов
+        let otel_cx = opentelemetry::Context::current();
+        tokio::task::spawn_blocking(move || {
+            let _guard = otel_cx.attach();
+            let conn = &mut get_conn(&pool).inspect_err(|e| {
+                checkout_failures.send_replace(e.to_string());
+            })?;
+            query(conn)
+        })
+        .await
+        .map_err(|e| {
+            error!("Database task failed: {}", e);
+            RepoError::TaskFailed(e)
+        })?
     }
 
     pub(crate) fn get_conn(
         &self,
     ) -> Result<PooledConnection<ConnectionManager<PgConnection>>, DbError> {
-        debug!("Attempting to get a connection from the pool");
-        match self.pool.get() {
-            Ok(conn) => {
-                debug!("Successfully obtained a connection from the pool");
-                Ok(conn)
-            }
-            Err(e) => {
-                error!("Failed to obtain a connection from the pool: {}", e);
-                Err(DbError::ConnectionError(e.to_string()))
-            }
-        }
+        get_conn(&self.pool)
     }
 
-    pub fn manage_migration(&self) -> Result<(), MigrationError> {
+    /// При auto_migrate применяет ожидающие миграции, иначе возвращает MigrationError::Pending,
+    /// чтобы схему обновлял отдельный запуск `migrate up`. На актуальной схеме дополняет
+    /// имена без скелета, см. migrations::backfill_usernames
+    pub fn manage_migration(&self, auto_migrate: bool) -> Result<(), MigrationError> {
         info!("Checking for pending migrations");
-        debug!("Attempting to get a connection for checking migrations");
         let conn = &mut self.get_conn().map_err(|e| {
             error!("Failed to get a connection for checking migrations: {}", e);
             MigrationError::MigrationFailed(e.to_string())
         })?;
 
-        debug!("Successfully obtained a connection for checking migrations");
-
-        // Check if the database is initialized
-        let is_initialized =
-            sql_query("SELECT 1 FROM information_schema.tables WHERE table_name = 'users'")
-                .execute(conn)
-                .is_ok();
-
-        if !is_initialized {
-            info!("Database is not initialized. Running initial setup migrations.");
-            conn.run_pending_migrations(MIGRATIONS).map_err(|e| {
-                error!("Failed to run initial setup migrations: {}", e);
-                MigrationError::MigrationFailed(e.to_string())
-            })?;
-            info!("Initial setup migrations complete");
-        } else {
-            let pending_migrations = conn.pending_migrations(MIGRATIONS).map_err(|e| {
-                error!("Failed to check for pending migrations: {}", e);
-                MigrationError::MigrationFailed(e.to_string())
-            })?;
-
-            if pending_migrations.is_empty() {
-                info!("No pending migrations found");
-                return Ok(());
-            }
-
-            info!("Running pending migrations");
-            let applied_migrations = conn.run_pending_migrations(MIGRATIONS).map_err(|e| {
-                error!("Failed to run migrations: {}", e);
-                MigrationError::MigrationFailed(e.to_string())
-            })?;
-
-            for migration in applied_migrations {
-                info!("Applied migration: {}", migration);
+        if !auto_migrate {
+            let pending = migrations::status(conn)?
+                .into_iter()
+                .filter(|migration| !migration.applied)
+                .count();
+            if pending > 0 {
+                return Err(MigrationError::Pending(pending));
             }
+            return migrations::backfill_usernames(conn);
+        }
 
-            info!("Migrations complete");
+        let applied_migrations = migrations::up(conn).map_err(|e| {
+            error!("Failed to run migrations: {}", e);
+            e
+        })?;
+        if applied_migrations.is_empty() {
+            info!("No pending migrations found");
+        }
+        for migration in applied_migrations {
+            info!("Applied migration: {}", migration);
         }
 
         Ok(())
     }
 }
+
+fn get_conn(pool: &Pool) -> Result<PooledConnection<ConnectionManager<PgConnection>>, DbError> {
+    debug!("Attempting to get a connection from the pool");
+    let started = Instant::now();
+    let conn = pool.get();
+    metrics::DB_POOL_CHECKOUT_DURATION.observe(started.elapsed().as_secs_f64());
+    match conn {
+        Ok(conn) => {
+            debug!("Successfully obtained a connection from the pool");
+            Ok(conn)
+        }
+        Err(e) => {
+            metrics::DB_POOL_CHECKOUT_FAILURES.inc();
+            error!("Failed to obtain a connection from the pool: {}", e);
+            Err(DbError::PoolError(e))
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use crate::config::RetryConfig;
+use rand::Rng;
+use std::time::Duration;
+
+/// Состояние повторных попыток, передаётся наружу перед каждой паузой
+#[derive(Debug, Clone)]
+pub struct RetryStatus {
+    pub attempt: u32,
+    pub max_attempts: u32,
+    pub delay: Duration,
+    pub last_error: String,
+}
+
+/// Экспоненциальная задержка с полным джиттером: случайное значение
+/// от 0 до min(max_backoff, initial_backoff * 2^(attempt - 1))
+pub fn backoff_delay(config: &RetryConfig, attempt: u32, rng: &mut impl Rng) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+    let ceiling = config
+        .initial_backoff
+        .saturating_mul(factor)
+        .min(config.max_backoff);
+    let millis = u64::try_from(ceiling.as_millis()).unwrap_or(u64::MAX);
+    Duration::from_millis(rng.gen_range(0..=millis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn config() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 10,
+            max_elapsed: Duration::from_secs(60),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn test_backoff_delay_bounds() {
+        let config = config();
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..100 {
+            assert!(backoff_delay(&config, 1, &mut rng) <= Duration::from_millis(100));
+            assert!(backoff_delay(&config, 3, &mut rng) <= Duration::from_millis(400));
+            assert!(backoff_delay(&config, 30, &mut rng) <= config.max_backoff);
+            assert!(backoff_delay(&config, u32::MAX, &mut rng) <= config.max_backoff);
+        }
+    }
+}
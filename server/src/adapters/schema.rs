@@ -5,5 +5,10 @@ table! {
         id -> Uuid,
         username -> Varchar,
         email -> Varchar,
+        deleted_at -> Nullable<Timestamptz>,
+        version -> Int8,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+        username_skeleton -> Nullable<Varchar>,
     }
 }
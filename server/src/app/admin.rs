@@ -0,0 +1,115 @@
+use async_trait::async_trait;
+use log::info;
+use tonic::{Request, Response, Status};
+
+use lib_rpc::adminpb::admin_service_server::AdminService;
+use lib_rpc::adminpb::{GetLogLevelRequest, LogLevelResponse, SetLogLevelRequest};
+
+use crate::app::authorization::authorize;
+use crate::errors::GrpcError;
+use crate::logging::{LogLevelHandle, LogLevels};
+
+/// Служебный сервис на основном порту за AuthInterceptor, все методы требуют SCOPE_ADMIN
+pub struct AdminServiceCore {
+    pub log_levels: LogLevelHandle,
+}
+
+#[async_trait]
+impl AdminService for AdminServiceCore {
+    async fn get_log_level(
+        &self, request: Request<GetLogLevelRequest>,
+    ) -> Result<Response<LogLevelResponse>, Status> {
+        authorize(&request)?;
+        Ok(Response::new(LogLevelResponse {
+            spec: self.log_levels.get().to_string(),
+        }))
+    }
+
+    async fn set_log_level(
+        &self, request: Request<SetLogLevelRequest>,
+    ) -> Result<Response<LogLevelResponse>, Status> {
+        authorize(&request)?;
+        let levels: LogLevels = request
+            .into_inner()
+            .spec
+            .parse()
+            .map_err(|e| GrpcError::invalid_argument("spec", "INVALID_LOG_LEVEL", e))?;
+        // Пишется до смены уровня, чтобы запись попала в лог и при понижении
+        info!(
+            "Log level changed from {} to {}",
+            self.log_levels.get(),
+            levels
+        );
+        self.log_levels.set(levels.clone());
+        Ok(Response::new(LogLevelResponse {
+            spec: levels.to_string(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::authorization::tests::{as_admin, with_principal};
+    use crate::app::authorization::SCOPE_WRITE;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn set_log_level_validates_and_applies_spec() {
+        let service = AdminServiceCore {
+            log_levels: LogLevelHandle::new("debug".parse().unwrap()),
+        };
+
+        let status = service
+            .set_log_level(as_admin(SetLogLevelRequest {
+                spec: "info,user_service_server=noisy".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let response = service
+            .set_log_level(as_admin(SetLogLevelRequest {
+                spec: "warn,user_service_server::repo=trace".to_string(),
+            }))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.into_inner().spec,
+            "warn,user_service_server::repo=trace"
+        );
+
+        let response = service
+            .get_log_level(as_admin(GetLogLevelRequest {}))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.into_inner().spec,
+            "warn,user_service_server::repo=trace"
+        );
+    }
+
+    #[tokio::test]
+    async fn log_level_requires_admin_scope() {
+        let service = AdminServiceCore {
+            log_levels: LogLevelHandle::new("debug".parse().unwrap()),
+        };
+
+        let status = service
+            .get_log_level(Request::new(GetLogLevelRequest {}))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let request = with_principal(
+            "writer",
+            &[SCOPE_WRITE],
+            SetLogLevelRequest {
+                spec: "trace".to_string(),
+            },
+        );
+        let status = service.set_log_level(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        assert_eq!(service.log_levels.get().to_string(), "debug");
+    }
+}
@@ -0,0 +1,194 @@
+//! Права на методы UserService и AdminService. Правило объявляется для типа запроса,
+//! поэтому метод без правила не скомпилируется, а проверка одна для всех методов.
+//!
+//! Пользователь (sub токена - его UUID) работает только со своей записью.
+//! Модераторам, администраторам и внутренним сервисам права выдаются scope'ами
+use tonic::Request;
+use uuid::Uuid;
+
+use lib_rpc::adminpb::{GetLogLevelRequest, SetLogLevelRequest};
+use lib_rpc::userpb::{
+    CreateUserRequest, DeleteUserRequest, GetAllUsersRequest, GetUserByIdRequest,
+    GetUserIdByNicknameRequest, GetUserRequest, GetUsersByIdsRequest, ResolveUsernamesRequest,
+    RestoreUserRequest, UpdateUserRequest,
+};
+
+use crate::app::auth::{Authentication, Principal};
+use crate::errors::GrpcError;
+
+/// Чтение чужих записей, в том числе почты
+pub const SCOPE_READ: &str = "users:read";
+/// Создание и изменение чужих записей
+pub const SCOPE_WRITE: &str = "users:write";
+/// Только поиск UUID по именам, для внутренних сервисов
+pub const SCOPE_RESOLVE: &str = "users:resolve";
+/// Все методы, включая удаление и восстановление чужих записей и AdminService
+pub const SCOPE_ADMIN: &str = "users:admin";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// Доступно без токена: метод не возвращает персональных данных и ничего не меняет
+    Public,
+    /// Нужен scope (или SCOPE_ADMIN)
+    Scope(&'static str),
+    /// Своя запись или scope (или SCOPE_ADMIN)
+    OwnerOrScope(&'static str),
+}
+
+/// Правило доступа для запроса к UserService или AdminService
+pub trait Protected {
+    const RULE: Rule;
+
+    /// UUID записи, к которой обращается запрос, для Rule::OwnerOrScope
+    fn owner(&self) -> Option<&str> {
+        None
+    }
+}
+
+macro_rules! protected {
+    ($request:ty, $rule:expr) => {
+        impl Protected for $request {
+            const RULE: Rule = $rule;
+        }
+    };
+    ($request:ty, $rule:expr, owner) => {
+        impl Protected for $request {
+            const RULE: Rule = $rule;
+
+            fn owner(&self) -> Option<&str> {
+                Some(&self.uuid)
+            }
+        }
+    };
+}
+
+protected!(GetUserRequest, Rule::Public);
+protected!(GetUserIdByNicknameRequest, Rule::Public);
+protected!(ResolveUsernamesRequest, Rule::Scope(SCOPE_RESOLVE));
+protected!(CreateUserRequest, Rule::OwnerOrScope(SCOPE_WRITE), owner);
+protected!(GetUserByIdRequest, Rule::OwnerOrScope(SCOPE_READ), owner);
+protected!(UpdateUserRequest, Rule::OwnerOrScope(SCOPE_WRITE), owner);
+protected!(DeleteUserRequest, Rule::OwnerOrScope(SCOPE_ADMIN), owner);
+protected!(RestoreUserRequest, Rule::OwnerOrScope(SCOPE_ADMIN), owner);
+protected!(GetAllUsersRequest, Rule::Scope(SCOPE_READ));
+protected!(GetUsersByIdsRequest, Rule::Scope(SCOPE_READ));
+protected!(GetLogLevelRequest, Rule::Scope(SCOPE_ADMIN));
+protected!(SetLogLevelRequest, Rule::Scope(SCOPE_ADMIN));
+
+impl Principal {
+    fn has_scope(&self, scope: &str) -> bool {
+        self.scopes
+            .iter()
+            .any(|granted| granted == scope || granted == SCOPE_ADMIN)
+    }
+
+    /// UUID сравниваются после разбора, чтобы не зависеть от регистра
+    fn owns(&self, owner: Option<&str>) -> bool {
+        match (Uuid::parse_str(&self.subject), owner.map(Uuid::parse_str)) {
+            (Ok(subject), Some(Ok(owner))) => subject == owner,
+            _ => false,
+        }
+    }
+}
+
+/// Проверка доступа, вызывается в начале каждого метода UserService и AdminService.
+/// Запрос без Authentication (мимо interceptor) считается неаутентифицированным
+pub fn authorize<T: Protected>(request: &Request<T>) -> Result<(), GrpcError> {
+    if T::RULE == Rule::Public {
+        return Ok(());
+    }
+    let principal = match request.extensions().get::<Authentication>() {
+        Some(Authentication {
+            principal: Some(principal),
+            ..
+        }) => principal,
+        Some(Authentication {
+            required: false, ..
+        }) => return Ok(()),
+        _ => {
+            return Err(GrpcError::Unauthenticated(
+                "Bearer token is required".to_string(),
+            ))
+        }
+    };
+
+    match T::RULE {
+        Rule::Public => Ok(()),
+        Rule::Scope(scope) if principal.has_scope(scope) => Ok(()),
+        Rule::OwnerOrScope(scope)
+            if principal.owns(request.get_ref().owner()) || principal.has_scope(scope) =>
+        {
+            Ok(())
+        }
+        Rule::Scope(scope) => Err(GrpcError::PermissionDenied {
+            scope,
+            message: format!("Scope {} is required", scope),
+        }),
+        Rule::OwnerOrScope(scope) => Err(GrpcError::PermissionDenied {
+            scope,
+            message: format!("Only the user or a caller with scope {} can do this", scope),
+        }),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Запрос с действующим токеном, как после interceptor
+    pub(crate) fn with_principal<T>(subject: &str, scopes: &[&str], message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request.extensions_mut().insert(Authentication {
+            principal: Some(Principal {
+                subject: subject.to_string(),
+                scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+            }),
+            required: true,
+        });
+        request
+    }
+
+    /// Запрос администратора, которому доступны все методы
+    pub(crate) fn as_admin<T>(message: T) -> Request<T> {
+        with_principal("admin", &[SCOPE_ADMIN], message)
+    }
+
+    #[test]
+    fn test_owner_or_scope() {
+        let user_id = Uuid::now_v7();
+        let other_id = Uuid::now_v7().to_string();
+        let own = |uuid: &str| GetUserByIdRequest {
+            uuid: uuid.to_string(),
+        };
+        let subject = user_id.to_string();
+
+        let uppercase = user_id.to_string().to_uppercase();
+        assert!(authorize(&with_principal(&subject, &[], own(&uppercase))).is_ok());
+        assert!(matches!(
+            authorize(&with_principal(&subject, &[], own(&other_id))),
+            Err(GrpcError::PermissionDenied { .. })
+        ));
+        assert!(authorize(&with_principal("moderator", &[SCOPE_READ], own(&other_id))).is_ok());
+        assert!(authorize(&with_principal("admin", &[SCOPE_ADMIN], own(&other_id))).is_ok());
+        assert!(matches!(
+            authorize(&Request::new(own(&other_id))),
+            Err(GrpcError::Unauthenticated(_))
+        ));
+    }
+
+    #[test]
+    fn test_scope_only() {
+        let resolve = || ResolveUsernamesRequest {
+            usernames: vec!["test".to_string()],
+        };
+        assert!(authorize(&with_principal("chat", &[SCOPE_RESOLVE], resolve())).is_ok());
+        assert!(authorize(&with_principal("chat", &[SCOPE_READ], resolve())).is_err());
+        assert!(authorize(&with_principal(
+            "chat",
+            &[SCOPE_RESOLVE],
+            GetUsersByIdsRequest::default()
+        ))
+        .is_err());
+        assert!(authorize(&Request::new(GetUserRequest::default())).is_ok());
+    }
+}
@@ -0,0 +1,459 @@
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::http;
+use axum::http::HeaderValue;
+use log::{error, info};
+use tokio::sync::watch;
+use tonic::server::NamedService;
+use tonic::transport::Endpoint;
+use tower::Service;
+
+use tonic_health::pb::health_check_response::ServingStatus;
+use tonic_health::pb::health_client::HealthClient;
+use tonic_health::pb::health_server::{Health, HealthServer};
+use tonic_health::pb::HealthCheckRequest;
+use tonic_health::server::{health_reporter, HealthReporter};
+use tonic_health::ServingStatus as ReportedStatus;
+
+use crate::adapters::retry::RetryStatus;
+use crate::app::shutdown::Shutdown;
+use crate::repo::UserRepository;
+
+/// Имя сервиса в запросе Check, пустое имя означает весь сервер
+const USER_SERVICE_NAME: &str = "userpb.UserService";
+/// Ключ метаданных ответа с описанием состояния сервиса
+const HEALTH_STATUS_KEY: &str = "health-status";
+
+#[derive(Debug, Clone)]
+pub enum HealthStatus {
+    Starting,
+    /// Ожидание перед очередной попыткой подключения к БД
+    Retrying {
+        attempt: u32,
+        max_attempts: u32,
+        delay: Duration,
+        last_error: String,
+    },
+    /// Автоматические миграции отключены, ожидание `migrate up`
+    MigrationsPending(usize),
+    /// Миграции применены и пул выдаёт соединения
+    Serving,
+    /// БД перестала отвечать после успешного запуска
+    DatabaseUnavailable(String),
+    /// Получен сигнал остановки, новые запросы лучше направлять на другие экземпляры
+    ShuttingDown,
+}
+
+impl From<&RetryStatus> for HealthStatus {
+    fn from(status: &RetryStatus) -> Self {
+        HealthStatus::Retrying {
+            attempt: status.attempt,
+            max_attempts: status.max_attempts,
+            delay: status.delay,
+            last_error: status.last_error.clone(),
+        }
+    }
+}
+
+impl fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthStatus::Starting => write!(f, "starting"),
+            HealthStatus::Retrying {
+                attempt,
+                max_attempts,
+                delay,
+                last_error,
+            } => write!(
+                f,
+                "database connection attempt {}/{} failed, retrying in {:?}: {}",
+                attempt, max_attempts, delay, last_error
+            ),
+            HealthStatus::MigrationsPending(pending) => {
+                write!(f, "{} pending migrations, waiting for `migrate up`", pending)
+            }
+            HealthStatus::Serving => write!(f, "serving"),
+            HealthStatus::DatabaseUnavailable(error) => {
+                write!(f, "database unavailable: {}", error)
+            }
+            HealthStatus::ShuttingDown => write!(f, "shutting down"),
+        }
+    }
+}
+
+impl HealthStatus {
+    fn serving_status(&self) -> ReportedStatus {
+        match self {
+            HealthStatus::Serving => ReportedStatus::Serving,
+            _ => ReportedStatus::NotServing,
+        }
+    }
+
+    /// Описание без текста ошибок для основного порта, подробности остаются в логах
+    fn summary(&self) -> &'static str {
+        match self {
+            HealthStatus::Starting => "starting",
+            HealthStatus::MigrationsPending(_) => "migrations pending",
+            HealthStatus::Retrying { .. } | HealthStatus::DatabaseUnavailable(_) => {
+                "database unavailable"
+            }
+            HealthStatus::Serving => "serving",
+            HealthStatus::ShuttingDown => "shutting down",
+        }
+    }
+
+    /// Описание для метаданных ответа. В метаданных допустимы только видимые ASCII-символы,
+    /// а текст ошибки от БД может содержать переводы строк и локализованные сообщения
+    fn description(&self) -> String {
+        self.to_string()
+            .chars()
+            .map(|c| {
+                if c.is_ascii() && !c.is_ascii_control() {
+                    c
+                } else {
+                    ' '
+                }
+            })
+            .collect()
+    }
+}
+
+/// Общее состояние сервиса, обновляется из main и задачи проверки готовности,
+/// читается сервисом здоровья
+#[derive(Debug, Clone)]
+pub struct HealthState {
+    status: Arc<watch::Sender<HealthStatus>>,
+}
+
+impl HealthState {
+    pub fn new() -> Self {
+        HealthState {
+            status: Arc::new(watch::channel(HealthStatus::Starting).0),
+        }
+    }
+
+    /// ShuttingDown - конечное состояние, последующие обновления игнорируются
+    pub fn set(&self, status: HealthStatus) {
+        self.status.send_if_modified(|current| {
+            if matches!(current, HealthStatus::ShuttingDown) {
+                return false;
+            }
+            *current = status;
+            true
+        });
+    }
+
+    pub fn get(&self) -> HealthStatus {
+        self.status.borrow().clone()
+    }
+}
+
+/// Фоновая задача, периодически проверяющая доступность БД после запуска.
+/// Ошибка получения соединения в любом запросе сразу переводит сервис в NOT_SERVING,
+/// обратно в SERVING он возвращается после успешной проверки
+pub async fn run_readiness_task<R: UserRepository>(
+    repository: Arc<R>, mut checkout_failures: watch::Receiver<String>, state: HealthState,
+    interval: Duration, shutdown: Shutdown,
+) {
+    info!("Starting readiness task: interval {:?}", interval);
+    let mut ticker = tokio::time::interval(interval);
+    let shutdown = shutdown.wait();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            _ = ticker.tick() => {}
+            Ok(()) = checkout_failures.changed() => {
+                let e = checkout_failures.borrow_and_update().clone();
+                if matches!(state.get(), HealthStatus::Serving) {
+                    error!("Failed to obtain a database connection, not serving: {}", e);
+                }
+                state.set(HealthStatus::DatabaseUnavailable(e));
+                continue;
+            }
+        }
+        let was_serving = matches!(state.get(), HealthStatus::Serving);
+        match repository.ping().await {
+            Ok(()) => {
+                if !was_serving {
+                    info!("Database is available again, serving");
+                }
+                state.set(HealthStatus::Serving);
+            }
+            Err(e) => {
+                if was_serving {
+                    error!("Database became unavailable: {}", e);
+                }
+                state.set(HealthStatus::DatabaseUnavailable(e.to_string()));
+            }
+        }
+    }
+    info!("Readiness task stopped");
+}
+
+/// Клиентская проверка для подкоманды healthcheck: true, если сервер отвечает SERVING
+pub async fn check_health(addr: String, timeout: Duration) -> Result<bool, Box<dyn Error>> {
+    let request = async {
+        let channel = Endpoint::from_shared(addr)?.connect().await?;
+        let mut client = HealthClient::new(channel);
+        let response = client.check(HealthCheckRequest::default()).await?;
+        if let Some(description) = response.metadata().get(HEALTH_STATUS_KEY) {
+            println!("{}", description.to_str().unwrap_or_default());
+        }
+        Ok::<_, Box<dyn Error>>(response.into_inner().status() == ServingStatus::Serving)
+    };
+    tokio::time::timeout(timeout, request).await?
+}
+
+/// Стандартный сервис grpc.health.v1 из tonic-health с Check и Watch. Статус всего сервера ("")
+/// и UserService следует за HealthState: SERVING только в состоянии Serving
+pub async fn health_service(state: &HealthState) -> HealthStatusService<HealthServer<impl Health>> {
+    let (mut reporter, server) = health_reporter();
+    let mut statuses = state.status.subscribe();
+    let status = statuses.borrow_and_update().serving_status();
+    report(&mut reporter, status).await;
+    tokio::spawn(async move {
+        while statuses.changed().await.is_ok() {
+            let status = statuses.borrow_and_update().serving_status();
+            report(&mut reporter, status).await;
+        }
+    });
+    HealthStatusService {
+        inner: server,
+        state: state.clone(),
+        detailed: true,
+    }
+}
+
+async fn report(reporter: &mut HealthReporter, status: ReportedStatus) {
+    reporter.set_service_status("", status).await;
+    reporter
+        .set_service_status(USER_SERVICE_NAME, status)
+        .await;
+}
+
+/// Добавляет к ответам сервиса здоровья описание состояния в метаданных health-status:
+/// по нему видно, например, что сервис ждёт очередной попытки подключения к БД
+#[derive(Debug, Clone)]
+pub struct HealthStatusService<S> {
+    inner: S,
+    state: HealthState,
+    /// false - только общее состояние без текста ошибок БД
+    detailed: bool,
+}
+
+impl<S: Clone> HealthStatusService<S> {
+    /// Для основного порта, открытого клиентам без аутентификации
+    pub fn without_details(&self) -> Self {
+        HealthStatusService {
+            detailed: false,
+            ..self.clone()
+        }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for HealthStatusService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        let status = self.state.get();
+        let description = if self.detailed {
+            HeaderValue::try_from(status.description())
+        } else {
+            HeaderValue::try_from(status.summary())
+        };
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let mut response = response.await?;
+            if let Ok(description) = description {
+                response
+                    .headers_mut()
+                    .insert(HEALTH_STATUS_KEY, description);
+            }
+            Ok(response)
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for HealthStatusService<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::shutdown;
+    use crate::repo::internal::InternalRepository;
+    use pretty_assertions::assert_eq;
+
+    async fn serve(
+        service: HealthStatusService<HealthServer<impl Health>>,
+    ) -> HealthClient<tonic::transport::Channel> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+        let channel = Endpoint::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        HealthClient::new(channel)
+    }
+
+    fn check_request(service: &str) -> HealthCheckRequest {
+        HealthCheckRequest {
+            service: service.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn check_reports_health_status() {
+        let state = HealthState::new();
+        let mut client = serve(health_service(&state).await).await;
+
+        state.set(HealthStatus::from(&RetryStatus {
+            attempt: 2,
+            max_attempts: 5,
+            delay: Duration::from_secs(1),
+            last_error: "connection refused\n\tIs the server running?".to_string(),
+        }));
+        let response = client.check(check_request("")).await.unwrap();
+        assert_eq!(
+            response.metadata().get(HEALTH_STATUS_KEY).unwrap(),
+            "database connection attempt 2/5 failed, retrying in 1s: connection refused  Is the server running?"
+        );
+        assert_eq!(response.into_inner().status(), ServingStatus::NotServing);
+
+        state.set(HealthStatus::Serving);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let response = client.check(check_request(USER_SERVICE_NAME)).await.unwrap();
+        assert_eq!(response.into_inner().status(), ServingStatus::Serving);
+
+        let status = client.check(check_request("unknown")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        state.set(HealthStatus::ShuttingDown);
+        state.set(HealthStatus::Serving);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let response = client.check(check_request("")).await.unwrap();
+        assert_eq!(
+            response.metadata().get(HEALTH_STATUS_KEY).unwrap(),
+            "shutting down"
+        );
+        assert_eq!(response.into_inner().status(), ServingStatus::NotServing);
+    }
+
+    #[tokio::test]
+    async fn public_check_hides_error_details() {
+        let state = HealthState::new();
+        let service = health_service(&state).await;
+        let mut client = serve(service.without_details()).await;
+
+        state.set(HealthStatus::DatabaseUnavailable(
+            "timed out waiting for connection to db.internal:5432".to_string(),
+        ));
+        let response = client.check(check_request("")).await.unwrap();
+        assert_eq!(
+            response.metadata().get(HEALTH_STATUS_KEY).unwrap(),
+            "database unavailable"
+        );
+        assert_eq!(response.into_inner().status(), ServingStatus::NotServing);
+    }
+
+    #[tokio::test]
+    async fn watch_streams_status_changes() {
+        let state = HealthState::new();
+        let mut client = serve(health_service(&state).await).await;
+
+        let mut stream = client
+            .watch(check_request(USER_SERVICE_NAME))
+            .await
+            .unwrap()
+            .into_inner();
+        let next = stream.message().await.unwrap().unwrap();
+        assert_eq!(next.status(), ServingStatus::NotServing);
+
+        state.set(HealthStatus::Serving);
+        let next = tokio::time::timeout(Duration::from_secs(1), stream.message())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(next.status(), ServingStatus::Serving);
+    }
+
+    #[tokio::test]
+    async fn readiness_task_marks_serving() {
+        let state = HealthState::new();
+        let repository = Arc::new(InternalRepository::new());
+        let (trigger, shutdown) = shutdown::channel();
+        let (_failures, checkout_failures) = watch::channel(String::new());
+        let task = tokio::spawn(run_readiness_task(
+            repository,
+            checkout_failures,
+            state.clone(),
+            Duration::from_millis(10),
+            shutdown,
+        ));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(matches!(state.get(), HealthStatus::Serving));
+
+        trigger.trigger();
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn readiness_task_reacts_to_checkout_failures() {
+        let state = HealthState::new();
+        let repository = Arc::new(InternalRepository::new());
+        let (trigger, shutdown) = shutdown::channel();
+        let (failures, checkout_failures) = watch::channel(String::new());
+        let task = tokio::spawn(run_readiness_task(
+            repository,
+            checkout_failures,
+            state.clone(),
+            Duration::from_secs(3600),
+            shutdown,
+        ));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(matches!(state.get(), HealthStatus::Serving));
+
+        failures.send_replace("timed out waiting for connection".to_string());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            state.get().to_string(),
+            "database unavailable: timed out waiting for connection"
+        );
+
+        trigger.trigger();
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use axum::http;
+use axum::http::header::CONTENT_TYPE;
+use axum::routing::get;
+use axum::Router;
+use log::info;
+use tonic::Code;
+use tower::{Layer, Service};
+
+use crate::app::rate_limit::USER_SERVICE_METHODS;
+use crate::app::shutdown::Shutdown;
+use crate::metrics;
+
+/// Префикс пути gRPC-методов, для которых собираются метрики и спаны
+pub(crate) const USER_SERVICE_PATH: &str = "/userpb.UserService/";
+/// Метка для путей UserService, которых нет в USER_SERVICE_METHODS
+pub(crate) const UNKNOWN_METHOD: &str = "unknown";
+
+/// Имя метода UserService по пути запроса для меток метрик и имён спанов. Путь задаёт
+/// клиент, поэтому неизвестные методы сводятся к UNKNOWN_METHOD и число рядов ограничено
+pub(crate) fn user_service_method(path: &str) -> Option<&'static str> {
+    let method = path.strip_prefix(USER_SERVICE_PATH)?;
+    Some(
+        USER_SERVICE_METHODS
+            .iter()
+            .find(|known| **known == method)
+            .copied()
+            .unwrap_or(UNKNOWN_METHOD),
+    )
+}
+
+/// Код ответа унарного вызова. Ошибку tonic отдаёт в заголовке grpc-status
+/// (ответ без тела), успешный ответ - в трейлерах, поэтому отсутствие заголовка означает OK
+pub(crate) fn response_code<B, E>(response: &Result<http::Response<B>, E>) -> Code {
+    match response {
+        Ok(response) => response
+            .headers()
+            .get("grpc-status")
+            .and_then(|status| status.to_str().ok())
+            .and_then(|status| status.parse::<i32>().ok())
+            .map_or(Code::Ok, Code::from_i32),
+        Err(_) => Code::Unknown,
+    }
+}
+
+/// Слой tonic-сервера: количество, коды ответа и длительность вызовов UserService
+#[derive(Debug, Clone, Default)]
+pub struct RpcMetricsLayer;
+
+impl<S> Layer<S> for RpcMetricsLayer {
+    type Service = RpcMetrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcMetrics { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RpcMetrics<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for RpcMetrics<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        let method = user_service_method(request.uri().path());
+        let started = Instant::now();
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let response = response.await;
+            if let Some(method) = method {
+                metrics::observe_rpc(method, response_code(&response), started.elapsed());
+            }
+            response
+        })
+    }
+}
+
+/// HTTP-сервер с эндпоинтом /metrics. pool_state возвращает
+/// (всего соединений, простаивающих соединений) на момент запроса
+pub async fn run_metrics_server(
+    addr: SocketAddr, pool_state: impl Fn() -> (u32, u32) + Send + Sync + 'static,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    let pool_state = Arc::new(pool_state);
+    let app = Router::new().route(
+        "/metrics",
+        get(move || {
+            let (connections, idle_connections) = pool_state();
+            metrics::set_pool_state(connections, idle_connections);
+            async move { ([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], metrics::encode()) }
+        }),
+    );
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Metrics listening on {}", addr);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.wait())
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn layer_records_grpc_status() {
+        let service =
+            RpcMetricsLayer.layer(tower::service_fn(|request: http::Request<()>| async move {
+                let mut response = http::Response::new(());
+                if request.uri().path().ends_with("DeleteUser") {
+                    response
+                        .headers_mut()
+                        .insert("grpc-status", (Code::NotFound as i32).into());
+                }
+                Ok::<_, Infallible>(response)
+            }));
+
+        for path in [
+            "/userpb.UserService/DeleteUser",
+            "/userpb.UserService/RestoreUser",
+            "/userpb.UserService/NoSuchMethod",
+            "/grpc.health.v1.Health/Check",
+        ] {
+            let request = http::Request::builder().uri(path).body(()).unwrap();
+            service.clone().oneshot(request).await.unwrap();
+        }
+
+        let text = metrics::encode();
+        assert!(text.contains(
+            "user_service_rpc_requests_total{code=\"NotFound\",method=\"DeleteUser\"} 1"
+        ));
+        assert!(
+            text.contains("user_service_rpc_requests_total{code=\"Ok\",method=\"RestoreUser\"} 1")
+        );
+        assert!(!text.contains("method=\"Check\""));
+        assert!(!text.contains("method=\"NoSuchMethod\""));
+        assert!(text.contains(
+            "user_service_rpc_requests_total{code=\"Ok\",method=\"unknown\"} 1"
+        ));
+    }
+}
@@ -1,2 +1,15 @@
+pub mod admin;
+pub mod auth;
+pub mod authorization;
+pub mod health;
+pub mod metrics;
+mod pagination;
+pub mod purge;
+pub mod rate_limit;
+pub mod request_id;
+pub mod shutdown;
+pub mod tls;
+pub mod trace;
+mod update_mask;
 pub mod user_service;
-mod validation;
+pub mod validation;
@@ -0,0 +1,212 @@
+use crate::errors::GrpcError;
+use chrono::{DateTime, Utc};
+use lib_rpc::userpb::SortOrder;
+use log::trace;
+use prost_types::Timestamp;
+use uuid::Uuid;
+
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+const OLDEST_FIRST_PREFIX: char = 'o';
+const NEWEST_FIRST_PREFIX: char = 'n';
+
+/// 0 - размер страницы по умолчанию, слишком большие значения урезаются до MAX_PAGE_SIZE
+pub fn page_size(requested: u32) -> usize {
+    match requested as usize {
+        0 => DEFAULT_PAGE_SIZE,
+        size => size.min(MAX_PAGE_SIZE),
+    }
+}
+
+pub fn is_newest_first(sort_order: i32) -> Result<bool, GrpcError> {
+    match SortOrder::try_from(sort_order) {
+        Ok(SortOrder::Unspecified) | Ok(SortOrder::OldestFirst) => Ok(false),
+        Ok(SortOrder::NewestFirst) => Ok(true),
+        Err(_) => {
+            trace!("Invalid sort order: {}", sort_order);
+            Err(GrpcError::invalid_argument(
+                "sort_order",
+                "INVALID_SORT_ORDER",
+                "Invalid sort order",
+            ))
+        }
+    }
+}
+
+/// Токен привязан к направлению сортировки, чтобы курсор нельзя было
+/// применить к выборке в обратном порядке
+pub fn encode_page_token(last_id: &Uuid, newest_first: bool) -> String {
+    let prefix = if newest_first {
+        NEWEST_FIRST_PREFIX
+    } else {
+        OLDEST_FIRST_PREFIX
+    };
+    format!("{}{}", prefix, last_id.simple())
+}
+
+pub fn decode_page_token(token: &str, newest_first: bool) -> Result<Option<Uuid>, GrpcError> {
+    if token.is_empty() {
+        return Ok(None);
+    }
+    let invalid = || {
+        trace!("Invalid page token: {}", token);
+        GrpcError::invalid_argument("page_token", "INVALID_PAGE_TOKEN", "Invalid page token")
+    };
+
+    let mut chars = token.chars();
+    let newest_first_token = match chars.next() {
+        Some(NEWEST_FIRST_PREFIX) => true,
+        Some(OLDEST_FIRST_PREFIX) => false,
+        _ => return Err(invalid()),
+    };
+    if newest_first_token != newest_first {
+        trace!("Page token {} does not match sort order", token);
+        return Err(GrpcError::invalid_argument(
+            "page_token",
+            "INVALID_PAGE_TOKEN",
+            "Page token does not match sort order",
+        ));
+    }
+    Uuid::try_parse(chars.as_str())
+        .map(Some)
+        .map_err(|_| invalid())
+}
+
+/// Пустая строка означает отсутствие фильтра
+pub fn normalize_filter(value: &str) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+pub fn normalize_email_domain(value: &str) -> Option<String> {
+    normalize_filter(value.trim_start_matches('@')).map(|domain| domain.to_lowercase())
+}
+
+/// Незаданная граница означает отсутствие фильтра
+pub fn time_filter(
+    field: &str, timestamp: Option<Timestamp>,
+) -> Result<Option<DateTime<Utc>>, GrpcError> {
+    timestamp
+        .map(|timestamp| to_date_time(field, timestamp))
+        .transpose()
+}
+
+pub fn validate_time_range(
+    after: Option<DateTime<Utc>>, before: Option<DateTime<Utc>>,
+) -> Result<(), GrpcError> {
+    if let (Some(after), Some(before)) = (after, before) {
+        if after >= before {
+            trace!("Empty time range: {} - {}", after, before);
+            return Err(GrpcError::invalid_argument(
+                "created_after",
+                "INVALID_TIME_RANGE",
+                "created_after must be earlier than created_before",
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn to_date_time(field: &str, timestamp: Timestamp) -> Result<DateTime<Utc>, GrpcError> {
+    u32::try_from(timestamp.nanos)
+        .ok()
+        .filter(|nanos| *nanos < 1_000_000_000)
+        .and_then(|nanos| DateTime::from_timestamp(timestamp.seconds, nanos))
+        .ok_or_else(|| {
+            trace!("Invalid timestamp: {:?}", timestamp);
+            GrpcError::invalid_argument(field, "INVALID_TIMESTAMP", "Invalid timestamp")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_page_size() {
+        assert_eq!(page_size(0), DEFAULT_PAGE_SIZE);
+        assert_eq!(page_size(10), 10);
+        assert_eq!(page_size(u32::MAX), MAX_PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_page_token_round_trip() {
+        let last_id = Uuid::now_v7();
+
+        let token = encode_page_token(&last_id, false);
+        assert_eq!(decode_page_token(&token, false).unwrap(), Some(last_id));
+
+        let token = encode_page_token(&last_id, true);
+        assert_eq!(decode_page_token(&token, true).unwrap(), Some(last_id));
+
+        assert_eq!(decode_page_token("", true).unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalid_page_token() {
+        let result = decode_page_token("garbage", false);
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "Invalid argument: Invalid page token"
+        );
+
+        let token = encode_page_token(&Uuid::now_v7(), true);
+        let result = decode_page_token(&token, false);
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "Invalid argument: Page token does not match sort order"
+        );
+    }
+
+    #[test]
+    fn test_normalize_filters() {
+        assert_eq!(normalize_filter("  "), None);
+        assert_eq!(normalize_filter(" test "), Some("test".to_string()));
+        assert_eq!(
+            normalize_email_domain("@Example.COM"),
+            Some("example.com".to_string())
+        );
+        assert_eq!(normalize_email_domain("@"), None);
+    }
+
+    #[test]
+    fn test_time_filter() {
+        let timestamp = Timestamp {
+            seconds: 1_700_000_000,
+            nanos: 0,
+        };
+        let time = time_filter("created_after", Some(timestamp))
+            .unwrap()
+            .unwrap();
+        assert_eq!(time.timestamp(), 1_700_000_000);
+        assert_eq!(time_filter("created_after", None).unwrap(), None);
+
+        let invalid = Timestamp {
+            seconds: 0,
+            nanos: -1,
+        };
+        let result = time_filter("created_before", Some(invalid));
+        assert!(matches!(
+            result,
+            Err(GrpcError::InvalidArgument { field, reason: "INVALID_TIMESTAMP", .. })
+                if field == "created_before"
+        ));
+    }
+
+    #[test]
+    fn test_validate_time_range() {
+        let after = DateTime::from_timestamp(1_700_000_000, 0);
+        let before = DateTime::from_timestamp(1_700_000_100, 0);
+
+        assert!(validate_time_range(after, before).is_ok());
+        assert!(validate_time_range(after, None).is_ok());
+
+        let result = validate_time_range(before, after);
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "Invalid argument: created_after must be earlier than created_before"
+        );
+    }
+}
@@ -0,0 +1,45 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{debug, error, info};
+
+use crate::app::shutdown::Shutdown;
+use crate::repo::UserRepository;
+
+/// Фоновая задача, окончательно удаляющая пользователей,
+/// у которых истёк период восстановления после мягкого удаления
+pub async fn run_purge_task<R: UserRepository>(
+    repository: Arc<R>, grace_period: Duration, interval: Duration, shutdown: Shutdown,
+) {
+    info!(
+        "Starting purge task: grace period {:?}, interval {:?}",
+        grace_period, interval
+    );
+    let grace_period = match chrono::Duration::from_std(grace_period) {
+        Ok(grace_period) => grace_period,
+        Err(e) => {
+            error!("Invalid purge grace period {:?}: {}", grace_period, e);
+            return;
+        }
+    };
+
+    let mut ticker = tokio::time::interval(interval);
+    let shutdown = shutdown.wait();
+    tokio::pin!(shutdown);
+    loop {
+        // Начатая очистка доводится до конца, остановка проверяется только между запусками
+        tokio::select! {
+            _ = &mut shutdown => break,
+            _ = ticker.tick() => {}
+        }
+        let deleted_before = Utc::now() - grace_period;
+        debug!("Purging users deleted before {}", deleted_before);
+        match repository.purge_deleted_users(deleted_before).await {
+            Ok(0) => debug!("No deleted users to purge"),
+            Ok(purged) => info!("Purged {} deleted users", purged),
+            Err(e) => error!("Failed to purge deleted users: {}", e),
+        }
+    }
+    info!("Purge task stopped");
+}
@@ -0,0 +1,537 @@
+//! Ограничение нагрузки на UserService: token bucket на каждого клиента
+//! (sub вызывающего, без него - IP) с отдельными квотами для тяжёлых методов
+//! и общий предел одновременных запросов, сверх которого запросы сразу отклоняются
+use std::fmt;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use axum::http;
+use dashmap::DashMap;
+use log::debug;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tonic::body::BoxBody;
+use tonic::server::NamedService;
+use tonic::transport::server::{TcpConnectInfo, TlsConnectInfo};
+use tonic::Status;
+use tower::{Layer, Service};
+
+use crate::app::auth::Authentication;
+use crate::app::metrics::{user_service_method, UNKNOWN_METHOD};
+use crate::config::RateLimitConfig;
+use crate::errors::GrpcError;
+use crate::metrics;
+
+/// Методы UserService, для которых можно задать отдельную квоту
+pub const USER_SERVICE_METHODS: &[&str] = &[
+    "GetUser",
+    "CreateUser",
+    "GetUserDataById",
+    "UpdateUserData",
+    "GetAllUsers",
+    "DeleteUser",
+    "RestoreUser",
+    "GetUserIdByNickname",
+    "ResolveUsernames",
+    "GetUsersByIds",
+];
+
+/// Предел числа корзин, сверх него новые клиенты делят корзину OVERFLOW_CLIENT
+const MAX_TRACKED_BUCKETS: usize = 100_000;
+/// Как часто убирать корзины, которые успели наполниться заново
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+const OVERFLOW_CLIENT: &str = "overflow";
+/// Через сколько повторить запрос, отклонённый из-за перегрузки
+const OVERLOAD_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Квота в формате "<запросов в секунду>/<размер всплеска>", например "5/10"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid quota \"{}\": expected <requests per second>/<burst>",
+                s.trim()
+            )
+        };
+        let (per_second, burst) = s.trim().split_once('/').ok_or_else(invalid)?;
+        let per_second: f64 = per_second.trim().parse().map_err(|_| invalid())?;
+        let burst: u32 = burst.trim().parse().map_err(|_| invalid())?;
+        if !(per_second.is_finite() && per_second > 0.0) || burst == 0 {
+            return Err(format!("quota \"{}\" must be positive", s.trim()));
+        }
+        Ok(Quota { per_second, burst })
+    }
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.per_second, self.burst)
+    }
+}
+
+/// Квоты методов в формате "CreateUser=5/10,GetAllUsers=2/5"
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MethodQuotas(Vec<(String, Quota)>);
+
+impl MethodQuotas {
+    fn get(&self, method: &str) -> Option<Quota> {
+        self.0
+            .iter()
+            .find(|(name, _)| name == method)
+            .map(|&(_, quota)| quota)
+    }
+}
+
+impl FromStr for MethodQuotas {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut quotas: Vec<(String, Quota)> = Vec::new();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let (method, quota) = directive
+                .split_once('=')
+                .ok_or_else(|| format!("expected <method>=<quota>, got \"{}\"", directive))?;
+            let method = method.trim();
+            if !USER_SERVICE_METHODS.contains(&method) {
+                return Err(format!("unknown method \"{}\"", method));
+            }
+            let quota = quota.parse()?;
+            quotas.retain(|(existing, _)| existing != method);
+            quotas.push((method.to_string(), quota));
+        }
+        Ok(MethodQuotas(quotas))
+    }
+}
+
+impl fmt::Display for MethodQuotas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (method, quota)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}={}", method, quota)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, quota: Quota, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * quota.per_second).min(quota.burst as f64);
+        self.updated = now;
+    }
+
+    /// Забирает токен или возвращает, через сколько он появится
+    fn take(&mut self, quota: Quota, now: Instant) -> Result<(), Duration> {
+        self.refill(quota, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / quota.per_second,
+            ))
+        }
+    }
+}
+
+/// Корзины клиентов. Методы с отдельной квотой считаются в своей корзине,
+/// остальные делят общую корзину клиента
+#[derive(Debug)]
+struct RateLimiter {
+    default_quota: Quota,
+    method_quotas: MethodQuotas,
+    /// Шардированная карта, запросы разных клиентов не ждут друг друга
+    buckets: DashMap<(String, String), Bucket>,
+    max_buckets: usize,
+    next_sweep: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(default_quota: Quota, method_quotas: MethodQuotas, max_buckets: usize) -> Self {
+        RateLimiter {
+            default_quota,
+            method_quotas,
+            buckets: DashMap::new(),
+            max_buckets,
+            next_sweep: Mutex::new(Instant::now() + SWEEP_INTERVAL),
+        }
+    }
+
+    fn quota(&self, bucket_method: &str) -> Quota {
+        self.method_quotas
+            .get(bucket_method)
+            .unwrap_or(self.default_quota)
+    }
+
+    fn check(&self, client: &str, method: &str, now: Instant) -> Result<(), Duration> {
+        let (quota, bucket_method) = match self.method_quotas.get(method) {
+            Some(quota) => (quota, method),
+            None => (self.default_quota, ""),
+        };
+        self.sweep(now);
+        let mut key = (client.to_string(), bucket_method.to_string());
+        if self.buckets.len() >= self.max_buckets && !self.buckets.contains_key(&key) {
+            debug!("Too many rate limit buckets, {} shares the overflow bucket", client);
+            key.0 = OVERFLOW_CLIENT.to_string();
+        }
+        self.buckets
+            .entry(key)
+            .or_insert_with(|| Bucket {
+                tokens: quota.burst as f64,
+                updated: now,
+            })
+            .take(quota, now)
+    }
+
+    /// Убирает корзины, простоявшие дольше полного пополнения: такая корзина не отличается
+    /// от новой. Выполняется не чаще SWEEP_INTERVAL одним из запросов, остальные не ждут
+    fn sweep(&self, now: Instant) {
+        let Ok(mut next_sweep) = self.next_sweep.try_lock() else {
+            return;
+        };
+        if now < *next_sweep {
+            return;
+        }
+        *next_sweep = now + SWEEP_INTERVAL;
+        drop(next_sweep);
+        self.buckets.retain(|(_, method), bucket| {
+            let quota = self.quota(method);
+            let idle = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + idle * quota.per_second < quota.burst as f64
+        });
+    }
+}
+
+/// Клиент для квоты: sub из токена или сертификата, иначе IP соединения
+fn client_key<B>(request: &http::Request<B>) -> String {
+    let extensions = request.extensions();
+    if let Some(principal) = extensions
+        .get::<Authentication>()
+        .and_then(|authentication| authentication.principal.as_ref())
+    {
+        return format!("sub:{}", principal.subject);
+    }
+    let peer: Option<IpAddr> = extensions
+        .get::<TcpConnectInfo>()
+        .and_then(TcpConnectInfo::remote_addr)
+        .or_else(|| {
+            extensions
+                .get::<TlsConnectInfo<TcpConnectInfo>>()
+                .and_then(|info| info.get_ref().remote_addr())
+        })
+        .map(|addr| addr.ip());
+    match peer {
+        Some(ip) => format!("ip:{}", ip),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Слой для UserService, ставится внутри AuthInterceptor, чтобы квота
+/// считалась по аутентифицированному вызывающему
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    limiter: Option<Arc<RateLimiter>>,
+    in_flight: Option<Arc<Semaphore>>,
+}
+
+impl RateLimitLayer {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        RateLimitLayer {
+            limiter: config.enabled.then(|| {
+                Arc::new(RateLimiter::new(
+                    config.default_quota,
+                    config.method_quotas.clone(),
+                    MAX_TRACKED_BUCKETS,
+                ))
+            }),
+            in_flight: (config.max_concurrent_requests > 0)
+                .then(|| Arc::new(Semaphore::new(config.max_concurrent_requests))),
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: self.limiter.clone(),
+            in_flight: self.in_flight.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: Option<Arc<RateLimiter>>,
+    in_flight: Option<Arc<Semaphore>>,
+}
+
+impl<S> RateLimit<S> {
+    fn admit<B>(
+        &self, request: &http::Request<B>, method: &str,
+    ) -> Result<Option<OwnedSemaphorePermit>, GrpcError> {
+        // Разрешение берётся до квоты, чтобы отказ из-за перегрузки не тратил токен клиента
+        let permit = match &self.in_flight {
+            Some(in_flight) => match in_flight.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    debug!("Too many requests in flight, rejecting {}", method);
+                    metrics::observe_rpc_rejected(method, "overload");
+                    return Err(GrpcError::ResourceExhausted {
+                        reason: "SERVER_OVERLOADED",
+                        message: "Server is overloaded".to_string(),
+                        retry_after: OVERLOAD_RETRY_AFTER,
+                    });
+                }
+            },
+            None => None,
+        };
+        if let Some(limiter) = &self.limiter {
+            let client = client_key(request);
+            if let Err(retry_after) = limiter.check(&client, method, Instant::now()) {
+                debug!("Rate limit exceeded by {} on {}", client, method);
+                metrics::observe_rpc_rejected(method, "rate_limit");
+                return Err(GrpcError::ResourceExhausted {
+                    reason: "RATE_LIMIT_EXCEEDED",
+                    message: format!("Rate limit exceeded for {}", method),
+                    retry_after,
+                });
+            }
+        }
+        Ok(permit)
+    }
+}
+
+impl<S: NamedService> NamedService for RateLimit<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, ReqBody> Service<http::Request<ReqBody>> for RateLimit<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        let method = user_service_method(request.uri().path()).unwrap_or(UNKNOWN_METHOD);
+        match self.admit(&request, method) {
+            Ok(permit) => {
+                let response = self.inner.call(request);
+                Box::pin(async move {
+                    let response = response.await;
+                    drop(permit);
+                    response
+                })
+            }
+            Err(e) => {
+                let response = Status::from(e).into_http();
+                Box::pin(async move { Ok(response) })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::auth::Principal;
+    use crate::app::metrics::USER_SERVICE_PATH;
+    use pretty_assertions::assert_eq;
+    use std::convert::Infallible;
+    use tokio::sync::oneshot;
+    use tonic::body::empty_body;
+    use tower::ServiceExt;
+
+    fn config(max_concurrent_requests: usize) -> RateLimitConfig {
+        RateLimitConfig {
+            enabled: true,
+            default_quota: "10/2".parse().unwrap(),
+            method_quotas: "CreateUser=1/1".parse().unwrap(),
+            max_concurrent_requests,
+        }
+    }
+
+    fn request(method: &str, subject: &str) -> http::Request<()> {
+        let mut request = http::Request::builder()
+            .uri(format!("{}{}", USER_SERVICE_PATH, method))
+            .body(())
+            .unwrap();
+        request.extensions_mut().insert(Authentication {
+            principal: Some(Principal {
+                subject: subject.to_string(),
+                scopes: Vec::new(),
+            }),
+            required: true,
+        });
+        request
+    }
+
+    fn grpc_status(response: &http::Response<BoxBody>) -> Option<(i32, String)> {
+        let headers = response.headers();
+        let code = headers.get("grpc-status")?.to_str().ok()?.parse().ok()?;
+        let retry_after = headers.get("retry-after")?.to_str().ok()?.to_string();
+        Some((code, retry_after))
+    }
+
+    #[test]
+    fn test_parse_quotas() {
+        let quotas: MethodQuotas = "CreateUser=0.5/3, GetAllUsers=2/5,CreateUser=1/2"
+            .parse()
+            .unwrap();
+        assert_eq!(quotas.to_string(), "GetAllUsers=2/5,CreateUser=1/2");
+        assert_eq!(
+            "GetUsers=1/1".parse::<MethodQuotas>().unwrap_err(),
+            "unknown method \"GetUsers\""
+        );
+        assert_eq!(
+            "5".parse::<Quota>().unwrap_err(),
+            "invalid quota \"5\": expected <requests per second>/<burst>"
+        );
+        assert_eq!(
+            "0/5".parse::<Quota>().unwrap_err(),
+            "quota \"0/5\" must be positive"
+        );
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(
+            "10/2".parse().unwrap(),
+            "CreateUser=1/1".parse().unwrap(),
+            MAX_TRACKED_BUCKETS,
+        );
+        let now = Instant::now();
+
+        assert!(limiter.check("sub:a", "GetUser", now).is_ok());
+        assert!(limiter.check("sub:a", "GetUsersByIds", now).is_ok());
+        assert_eq!(
+            limiter.check("sub:a", "GetUser", now),
+            Err(Duration::from_millis(100))
+        );
+        // Отдельная квота и другой клиент не зависят от общей корзины
+        assert!(limiter.check("sub:a", "CreateUser", now).is_ok());
+        assert!(limiter.check("sub:b", "GetUser", now).is_ok());
+        assert_eq!(
+            limiter.check("sub:a", "CreateUser", now),
+            Err(Duration::from_secs(1))
+        );
+
+        let later = now + Duration::from_millis(150);
+        assert!(limiter.check("sub:a", "GetUser", later).is_ok());
+        assert!(limiter.check("sub:a", "CreateUser", later).is_err());
+    }
+
+    #[test]
+    fn test_buckets_are_bounded() {
+        let limiter = RateLimiter::new(
+            "10/2".parse().unwrap(),
+            "CreateUser=1/1".parse().unwrap(),
+            2,
+        );
+        let now = Instant::now();
+
+        assert!(limiter.check("sub:a", "CreateUser", now).is_ok());
+        assert!(limiter.check("sub:b", "GetUser", now).is_ok());
+        // Сверх предела новые клиенты делят одну корзину
+        assert!(limiter.check("sub:c", "CreateUser", now).is_ok());
+        assert!(limiter.check("sub:d", "CreateUser", now).is_err());
+        assert_eq!(limiter.buckets.len(), 3);
+
+        // Корзины, наполнившиеся за время простоя, убираются при очистке
+        let later = now + SWEEP_INTERVAL;
+        assert!(limiter.check("sub:b", "GetUser", later).is_ok());
+        assert_eq!(limiter.buckets.len(), 1);
+        assert!(limiter.check("sub:d", "CreateUser", later).is_ok());
+    }
+
+    #[tokio::test]
+    async fn layer_rejects_with_retry_after() {
+        let service = RateLimitLayer::new(&config(0)).layer(tower::service_fn(
+            |_: http::Request<()>| async { Ok::<_, Infallible>(http::Response::new(empty_body())) },
+        ));
+
+        let response = service
+            .clone()
+            .oneshot(request("CreateUser", "a"))
+            .await
+            .unwrap();
+        assert_eq!(grpc_status(&response), None);
+
+        let response = service
+            .clone()
+            .oneshot(request("CreateUser", "a"))
+            .await
+            .unwrap();
+        assert_eq!(
+            grpc_status(&response),
+            Some((tonic::Code::ResourceExhausted as i32, "1".to_string()))
+        );
+
+        let response = service.oneshot(request("CreateUser", "b")).await.unwrap();
+        assert_eq!(grpc_status(&response), None);
+    }
+
+    #[tokio::test]
+    async fn layer_sheds_requests_over_concurrency_limit() {
+        let (release, released) = oneshot::channel::<()>();
+        let released = Arc::new(tokio::sync::Mutex::new(Some(released)));
+        let service =
+            RateLimitLayer::new(&config(1)).layer(tower::service_fn(move |_: http::Request<()>| {
+                let released = released.clone();
+                async move {
+                    if let Some(released) = released.lock().await.take() {
+                        released.await.unwrap();
+                    }
+                    Ok::<_, Infallible>(http::Response::new(empty_body()))
+                }
+            }));
+
+        let slow = tokio::spawn(service.clone().oneshot(request("GetUser", "a")));
+        tokio::task::yield_now().await;
+        let response = service
+            .clone()
+            .oneshot(request("CreateUser", "b"))
+            .await
+            .unwrap();
+        assert_eq!(
+            grpc_status(&response),
+            Some((tonic::Code::ResourceExhausted as i32, "1".to_string()))
+        );
+
+        release.send(()).unwrap();
+        assert_eq!(grpc_status(&slow.await.unwrap().unwrap()), None);
+        // Отказ из-за перегрузки не израсходовал единственный токен CreateUser
+        let response = service.oneshot(request("CreateUser", "b")).await.unwrap();
+        assert_eq!(grpc_status(&response), None);
+    }
+}
@@ -0,0 +1,132 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+
+use axum::http;
+use axum::http::{HeaderName, HeaderValue};
+use opentelemetry::trace::FutureExt;
+use opentelemetry::Context;
+use tower::{Layer, Service};
+use uuid::Uuid;
+
+use crate::telemetry::RequestId;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+/// Длиннее - скорее мусор, чем идентификатор, такой заменяется новым
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Идентификатор клиента, если он пригоден для логов: непустой, без пробелов
+/// и управляющих символов. Иначе новый UUIDv7
+fn request_id<B>(request: &http::Request<B>) -> String {
+    request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| {
+            !value.is_empty()
+                && value.len() <= MAX_REQUEST_ID_LEN
+                && value.bytes().all(|b| b.is_ascii_graphic())
+        })
+        .map_or_else(|| Uuid::now_v7().to_string(), str::to_string)
+}
+
+/// Слой tonic-сервера: x-request-id из запроса (или новый) кладётся в контекст
+/// на время обработки, попадает в строки лога и детали ошибок и возвращается в ответе.
+/// Ставится после RpcTraceLayer, чтобы дополнить контекст спана, а не заменить его
+#[derive(Debug, Clone, Default)]
+pub struct RequestIdLayer;
+
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = RequestIdService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestIdService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestIdService<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for RequestIdService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        let request_id = request_id(&request);
+        // Идентификатор проверен при разборе или сгенерирован, заголовок из него всегда корректен
+        let header = HeaderValue::from_str(&request_id).unwrap();
+        let otel_cx = Context::current().with_value(RequestId(request_id));
+
+        // Interceptor отклоняет запрос уже внутри call, ему тоже нужен контекст
+        let response = {
+            let _guard = otel_cx.clone().attach();
+            self.inner.call(request)
+        };
+        Box::pin(async move {
+            let mut response = response.with_context(otel_cx).await?;
+            response.headers_mut().insert(REQUEST_ID_HEADER, header);
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::current_request_id;
+    use pretty_assertions::assert_eq;
+    use std::convert::Infallible;
+    use tower::ServiceExt;
+
+    async fn call(request_id: Option<&str>) -> (String, String) {
+        let service = RequestIdLayer.layer(tower::service_fn(|_: http::Request<()>| async {
+            // Обработчик запускает запрос к БД в отдельном потоке, как DbRepository::run
+            let otel_cx = Context::current();
+            let seen = tokio::task::spawn_blocking(move || {
+                let _guard = otel_cx.attach();
+                current_request_id().unwrap()
+            })
+            .await
+            .unwrap();
+            Ok::<_, Infallible>(http::Response::new(seen))
+        }));
+
+        let mut request = http::Request::builder().uri("/userpb.UserService/GetUser");
+        if let Some(request_id) = request_id {
+            request = request.header("x-request-id", request_id);
+        }
+        let response = service.oneshot(request.body(()).unwrap()).await.unwrap();
+        let echoed = response.headers()[&REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        (echoed, response.into_body())
+    }
+
+    #[tokio::test]
+    async fn request_id_reaches_handler_and_response() {
+        let (echoed, seen) = call(Some("support-ticket-42")).await;
+        assert_eq!(echoed, "support-ticket-42");
+        assert_eq!(seen, "support-ticket-42");
+
+        let (echoed, seen) = call(None).await;
+        assert_eq!(echoed, seen);
+        assert_eq!(Uuid::parse_str(&echoed).unwrap().get_version_num(), 7);
+
+        let (echoed, _) = call(Some("two words")).await;
+        assert_ne!(echoed, "two words");
+        assert!(Uuid::parse_str(&echoed).is_ok());
+        assert_eq!(current_request_id(), None);
+    }
+}
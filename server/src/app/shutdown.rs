@@ -0,0 +1,89 @@
+use log::info;
+use tokio::signal;
+use tokio::sync::watch;
+
+/// Создаёт сигнал остановки: Shutdown раздаётся серверам и фоновым задачам,
+/// ShutdownTrigger остаётся у main
+pub fn channel() -> (ShutdownTrigger, Shutdown) {
+    let (sender, receiver) = watch::channel(false);
+    (ShutdownTrigger { sender }, Shutdown { receiver })
+}
+
+pub struct ShutdownTrigger {
+    sender: watch::Sender<bool>,
+}
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    receiver: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    /// Завершается после trigger, а также если ShutdownTrigger был удалён
+    pub async fn wait(mut self) {
+        let _ = self.receiver.wait_for(|triggered| *triggered).await;
+    }
+}
+
+/// Ожидание SIGINT или SIGTERM
+pub async fn wait_for_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c().await.expect("Failed to listen for SIGINT");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received SIGINT"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn wait_completes_after_trigger() {
+        let (trigger, shutdown) = channel();
+        let waiting = tokio::spawn(shutdown.clone().wait());
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiting.is_finished());
+
+        trigger.trigger();
+        timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        // Подписавшиеся после срабатывания тоже не ждут
+        timeout(Duration::from_secs(1), shutdown.wait())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn wait_completes_when_trigger_dropped() {
+        let (trigger, shutdown) = channel();
+        drop(trigger);
+        timeout(Duration::from_secs(1), shutdown.wait())
+            .await
+            .unwrap();
+    }
+}
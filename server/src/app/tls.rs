@@ -0,0 +1,597 @@
+//! TLS и mTLS на порту UserService. Сертификаты, CA клиентов и список разрешённых
+//! клиентов перечитываются при изменении файлов, уже открытые соединения не рвутся
+use std::fs;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+use x509_parser::extensions::GeneralName;
+
+use crate::app::auth::Principal;
+use crate::app::shutdown::Shutdown;
+use crate::config::TlsConfig;
+use crate::errors::TlsError;
+
+/// Сколько ждать завершения рукопожатия, прежде чем закрыть соединение
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Разрешённый клиентский сертификат. Совпадение ищется ровно по одному
+/// из полей subject_cn, dns или uri (SAN), identity становится sub вызывающего
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientIdentity {
+    #[serde(default)]
+    pub subject_cn: Option<String>,
+    #[serde(default)]
+    pub dns: Option<String>,
+    #[serde(default)]
+    pub uri: Option<String>,
+    pub identity: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClientIdentitiesFile {
+    #[serde(default)]
+    client: Vec<ClientIdentity>,
+}
+
+/// Имена из сертификата клиента, по которым ищется ClientIdentity
+#[derive(Debug, Default)]
+struct CertificateNames {
+    common_names: Vec<String>,
+    dns: Vec<String>,
+    uris: Vec<String>,
+}
+
+impl CertificateNames {
+    fn parse(cert: &CertificateDer) -> Option<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref()).ok()?;
+        let mut names = CertificateNames {
+            common_names: cert
+                .subject()
+                .iter_common_name()
+                .filter_map(|cn| cn.as_str().ok())
+                .map(str::to_string)
+                .collect(),
+            ..Default::default()
+        };
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(dns) => names.dns.push(dns.to_string()),
+                    GeneralName::URI(uri) => names.uris.push(uri.to_string()),
+                    _ => {}
+                }
+            }
+        }
+        Some(names)
+    }
+}
+
+impl ClientIdentity {
+    fn matches(&self, names: &CertificateNames) -> bool {
+        let contains = |expected: &Option<String>, actual: &[String]| {
+            expected
+                .as_ref()
+                .is_some_and(|expected| actual.iter().any(|name| name == expected))
+        };
+        contains(&self.subject_cn, &names.common_names)
+            || contains(&self.dns, &names.dns)
+            || contains(&self.uri, &names.uris)
+    }
+}
+
+struct TlsState {
+    server_config: Arc<ServerConfig>,
+    identities: Vec<ClientIdentity>,
+}
+
+impl TlsState {
+    fn load(config: &TlsConfig) -> Result<Self, TlsError> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| TlsError::Config(e.to_string()))?;
+        let builder = match &config.client_ca_file {
+            Some(client_ca_file) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(client_ca_file)? {
+                    roots.add(cert).map_err(|e| file_error(client_ca_file, e))?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = if config.client_cert_required {
+                    verifier
+                } else {
+                    verifier.allow_unauthenticated()
+                };
+                builder.with_client_cert_verifier(
+                    verifier
+                        .build()
+                        .map_err(|e| TlsError::Config(e.to_string()))?,
+                )
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut server_config = builder
+            .with_single_cert(read_certs(&config.cert_file)?, read_key(&config.key_file)?)
+            .map_err(|e| TlsError::Config(e.to_string()))?;
+        server_config.alpn_protocols = vec![b"h2".to_vec()];
+
+        let identities = match &config.client_identities_file {
+            Some(path) => read_identities(path)?,
+            None => Vec::new(),
+        };
+        Ok(TlsState {
+            server_config: Arc::new(server_config),
+            identities,
+        })
+    }
+}
+
+fn file_error(path: &Path, error: impl ToString) -> TlsError {
+    TlsError::File {
+        path: path.display().to_string(),
+        error: error.to_string(),
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let file = fs::File::open(path).map_err(|e| file_error(path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| file_error(path, e))?;
+    if certs.is_empty() {
+        return Err(file_error(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+fn read_key(
+    path: &Path,
+) -> Result<tokio_rustls::rustls::pki_types::PrivateKeyDer<'static>, TlsError> {
+    let file = fs::File::open(path).map_err(|e| file_error(path, e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| file_error(path, e))?
+        .ok_or_else(|| file_error(path, "no private key found"))
+}
+
+fn read_identities(path: &Path) -> Result<Vec<ClientIdentity>, TlsError> {
+    let contents = fs::read_to_string(path).map_err(|e| file_error(path, e))?;
+    let file: ClientIdentitiesFile = toml::from_str(&contents).map_err(|e| file_error(path, e))?;
+    for client in &file.client {
+        let matchers = [&client.subject_cn, &client.dns, &client.uri]
+            .iter()
+            .filter(|matcher| matcher.is_some())
+            .count();
+        if matchers != 1 {
+            return Err(file_error(
+                path,
+                format!(
+                    "client {} must set exactly one of subject_cn, dns, uri",
+                    client.identity
+                ),
+            ));
+        }
+    }
+    Ok(file.client)
+}
+
+/// Текущие TLS-настройки UserService, общие для приёма соединений,
+/// задачи перечитывания и AuthInterceptor
+#[derive(Clone)]
+pub struct TlsReloader {
+    config: TlsConfig,
+    state: Arc<RwLock<TlsState>>,
+}
+
+impl TlsReloader {
+    pub fn new(config: TlsConfig) -> Result<Self, TlsError> {
+        let state = TlsState::load(&config)?;
+        Ok(TlsReloader {
+            config,
+            state: Arc::new(RwLock::new(state)),
+        })
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.state.read().unwrap().server_config.clone())
+    }
+
+    /// Вызывающий по сертификату клиента, если сертификат есть в списке разрешённых
+    pub fn identify(&self, certs: &[CertificateDer]) -> Option<Principal> {
+        let names = CertificateNames::parse(certs.first()?)?;
+        self.state
+            .read()
+            .unwrap()
+            .identities
+            .iter()
+            .find(|identity| identity.matches(&names))
+            .map(|identity| Principal {
+                subject: identity.identity.clone(),
+                scopes: identity.scopes.clone(),
+            })
+    }
+
+    /// Без списка разрешённых клиентов принимается любой сертификат, подписанный CA
+    fn is_allowed(&self, certs: Option<&[CertificateDer]>) -> bool {
+        let no_allowlist = self.state.read().unwrap().identities.is_empty();
+        match certs {
+            Some(certs) if !no_allowlist => self.identify(certs).is_some(),
+            _ => true,
+        }
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [
+            Some(&self.config.cert_file),
+            Some(&self.config.key_file),
+            self.config.client_ca_file.as_ref(),
+            self.config.client_identities_file.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+    }
+
+    /// Перечитывает файлы, если изменилось время их модификации.
+    /// При ошибке остаются прежние настройки, а попытка повторится на следующей проверке
+    fn reload_if_changed(&self, modified: &mut Vec<Option<SystemTime>>) -> Result<bool, TlsError> {
+        let current = self.modified();
+        if current == *modified {
+            return Ok(false);
+        }
+        let state = TlsState::load(&self.config)?;
+        *self.state.write().unwrap() = state;
+        *modified = current;
+        Ok(true)
+    }
+}
+
+pub async fn run_reload_task(reloader: TlsReloader, shutdown: Shutdown) {
+    info!(
+        "Watching TLS files for changes every {:?}",
+        reloader.config.reload_interval
+    );
+    let mut modified = reloader.modified();
+    let mut interval = tokio::time::interval(reloader.config.reload_interval);
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.clone().wait() => break,
+        }
+        match reloader.reload_if_changed(&mut modified) {
+            Ok(true) => info!("TLS certificates reloaded"),
+            Ok(false) => {}
+            Err(e) => error!(
+                "Failed to reload TLS certificates, keeping the previous ones: {}",
+                e
+            ),
+        }
+    }
+    info!("TLS reload task stopped");
+}
+
+/// Входящие TLS-соединения для Server::serve_with_incoming_shutdown.
+/// Рукопожатие идёт в отдельной задаче, чтобы медленный клиент не задерживал остальных
+pub fn incoming(
+    listener: TcpListener, reloader: TlsReloader, shutdown: Shutdown,
+) -> ReceiverStream<io::Result<TlsStream<TcpStream>>> {
+    let (sender, receiver) = mpsc::channel(128);
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // Например, исчерпан лимит дескрипторов
+                        warn!("Failed to accept a connection: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                },
+                _ = sender.closed() => break,
+                _ = shutdown.clone().wait() => break,
+            };
+            let reloader = reloader.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                if let Some(stream) = handshake(stream, peer, &reloader).await {
+                    let _ = sender.send(Ok(stream)).await;
+                }
+            });
+        }
+    });
+    ReceiverStream::new(receiver)
+}
+
+async fn handshake(
+    stream: TcpStream, peer: SocketAddr, reloader: &TlsReloader,
+) -> Option<TlsStream<TcpStream>> {
+    let _ = stream.set_nodelay(true);
+    let stream =
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, reloader.acceptor().accept(stream)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                debug!("TLS handshake with {} failed: {}", peer, e);
+                return None;
+            }
+            Err(_) => {
+                debug!("TLS handshake with {} timed out", peer);
+                return None;
+            }
+        };
+    if !reloader.is_allowed(stream.get_ref().1.peer_certificates()) {
+        warn!(
+            "Rejected connection from {}: client certificate is not in the allowlist",
+            peer
+        );
+        return None;
+    }
+    Some(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::auth::{AuthInterceptor, Authentication, Authenticator};
+    use crate::app::shutdown;
+    use crate::config::AuthConfig;
+    use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+    use pretty_assertions::assert_eq;
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use serde_json::json;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::TlsConnector;
+    use tokio_stream::StreamExt;
+    use tonic::service::Interceptor;
+    use tonic::transport::server::Connected;
+    use tonic::Request;
+
+    struct Pki {
+        ca: rcgen::Certificate,
+        ca_key: KeyPair,
+        dir: PathBuf,
+    }
+
+    impl Pki {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("tls-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let ca_key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = params.self_signed(&ca_key).unwrap();
+            Pki { ca, ca_key, dir }
+        }
+
+        /// Сертификат, подписанный CA: (PEM сертификата, PEM ключа)
+        fn issue(&self, cn: &str, dns: &str, usage: ExtendedKeyUsagePurpose) -> (String, String) {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![dns.to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, cn);
+            params.extended_key_usages = vec![usage];
+            let cert = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+            (cert.pem(), key.serialize_pem())
+        }
+
+        fn write(&self, name: &str, contents: &str) -> PathBuf {
+            let path = self.dir.join(name);
+            fs::File::create(&path)
+                .unwrap()
+                .write_all(contents.as_bytes())
+                .unwrap();
+            path
+        }
+
+        fn config(&self) -> TlsConfig {
+            let (cert, key) =
+                self.issue("server", "localhost", ExtendedKeyUsagePurpose::ServerAuth);
+            TlsConfig {
+                cert_file: self.write("server.pem", &cert),
+                key_file: self.write("server.key", &key),
+                client_ca_file: Some(self.write("ca.pem", &self.ca.pem())),
+                client_cert_required: true,
+                client_identities_file: Some(self.write(
+                    "clients.toml",
+                    "[[client]]\ndns = \"chat.internal\"\nidentity = \"chat-service\"\nscopes = [\"users:resolve\"]\n",
+                )),
+                reload_interval: Duration::from_secs(1),
+            }
+        }
+
+        fn client(&self, cn: &str, dns: &str) -> TlsConnector {
+            let (cert, key) = self.issue(cn, dns, ExtendedKeyUsagePurpose::ClientAuth);
+            let mut roots = RootCertStore::empty();
+            roots.add(self.ca.der().clone()).unwrap();
+            let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_client_auth_cert(
+                    rustls_pemfile::certs(&mut cert.as_bytes())
+                        .collect::<Result<_, _>>()
+                        .unwrap(),
+                    rustls_pemfile::private_key(&mut key.as_bytes())
+                        .unwrap()
+                        .unwrap(),
+                )
+                .unwrap();
+            TlsConnector::from(Arc::new(config))
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[tokio::test]
+    async fn incoming_accepts_only_allowlisted_clients() {
+        let pki = Pki::new("incoming");
+        let reloader = TlsReloader::new(pki.config()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_trigger, shutdown) = shutdown::channel();
+        let mut incoming = incoming(listener, reloader.clone(), shutdown);
+
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let stranger = pki.client("stranger", "stranger.internal");
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        // Рукопожатие TLS 1.3 завершается на клиенте раньше проверки списка на сервере
+        let _ = stranger.connect(server_name.clone(), tcp).await;
+
+        let chat = pki.client("chat", "chat.internal");
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let _client = chat.connect(server_name, tcp).await.unwrap();
+
+        let stream = tokio::time::timeout(Duration::from_secs(5), incoming.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let certs = stream.get_ref().1.peer_certificates().unwrap();
+        assert_eq!(
+            reloader.identify(certs),
+            Some(Principal {
+                subject: "chat-service".to_string(),
+                scopes: vec!["users:resolve".to_string()],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn token_takes_precedence_over_client_certificate() {
+        let pki = Pki::new("precedence");
+        let reloader = TlsReloader::new(pki.config()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_trigger, shutdown) = shutdown::channel();
+        let mut incoming = incoming(listener, reloader.clone(), shutdown);
+
+        let chat = pki.client("chat", "chat.internal");
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let _client = chat
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await
+            .unwrap();
+        let stream = tokio::time::timeout(Duration::from_secs(5), incoming.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        let secret = "test-secret-with-at-least-32-bytes!!";
+        let authenticator = Authenticator::new(&AuthConfig {
+            required: true,
+            hs256_secret: Some(secret.to_string()),
+            jwks_file: None,
+            issuer: None,
+            audience: None,
+        })
+        .unwrap();
+        let mut interceptor = AuthInterceptor::new(authenticator, Some(reloader));
+        let request = || {
+            let mut request = Request::new(());
+            request.extensions_mut().insert(stream.connect_info());
+            request
+        };
+        let principal = |request: Request<()>| {
+            request
+                .extensions()
+                .get::<Authentication>()
+                .and_then(|authentication| authentication.principal.clone())
+        };
+
+        // Без токена вызывающий определяется по сертификату
+        assert_eq!(
+            principal(interceptor.call(request()).unwrap()),
+            Some(Principal {
+                subject: "chat-service".to_string(),
+                scopes: vec!["users:resolve".to_string()],
+            })
+        );
+
+        let token = encode(
+            &Header::default(),
+            &json!({"sub": "user-1", "exp": get_current_timestamp() + 60}),
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap();
+        let mut with_token = request();
+        with_token.metadata_mut().insert(
+            "authorization",
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        assert_eq!(
+            principal(interceptor.call(with_token).unwrap()),
+            Some(Principal {
+                subject: "user-1".to_string(),
+                scopes: Vec::new(),
+            })
+        );
+
+        // Неверный токен не заменяется сертификатом
+        let mut forged = request();
+        forged
+            .metadata_mut()
+            .insert("authorization", "Bearer not-a-jwt".parse().unwrap());
+        assert_eq!(
+            interceptor.call(forged).unwrap_err().code(),
+            tonic::Code::Unauthenticated
+        );
+    }
+
+    #[test]
+    fn reload_keeps_previous_state_on_error() {
+        let pki = Pki::new("reload");
+        let config = pki.config();
+        let reloader = TlsReloader::new(config.clone()).unwrap();
+        let mut modified = reloader.modified();
+        assert!(!reloader.reload_if_changed(&mut modified).unwrap());
+
+        // Время модификации меняется не чаще раза в секунду на некоторых ФС
+        std::thread::sleep(Duration::from_millis(1100));
+        pki.write("clients.toml", "[[client]]\nidentity = \"nobody\"\n");
+        assert!(matches!(
+            reloader.reload_if_changed(&mut modified),
+            Err(TlsError::File { .. })
+        ));
+        assert_eq!(reloader.state.read().unwrap().identities.len(), 1);
+
+        pki.write(
+            "clients.toml",
+            "[[client]]\nsubject_cn = \"billing\"\nidentity = \"billing-service\"\n",
+        );
+        assert!(reloader.reload_if_changed(&mut modified).unwrap());
+        assert_eq!(
+            reloader.state.read().unwrap().identities[0].identity,
+            "billing-service"
+        );
+    }
+}
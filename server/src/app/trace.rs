@@ -0,0 +1,116 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+
+use axum::http;
+use opentelemetry::trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{global, KeyValue};
+use tonic::Code;
+use tower::{Layer, Service};
+
+use crate::app::metrics::{response_code, user_service_method};
+use crate::telemetry::{self, SERVICE_NAME};
+
+/// Слой tonic-сервера: серверный спан на каждый вызов UserService, родитель
+/// берётся из traceparent входящего запроса. Обработчик выполняется в контексте
+/// спана, поэтому спаны репозитория и строки лога связаны с ним
+#[derive(Debug, Clone, Default)]
+pub struct RpcTraceLayer;
+
+impl<S> Layer<S> for RpcTraceLayer {
+    type Service = RpcTrace<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcTrace { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RpcTrace<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for RpcTrace<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        let Some(method) = user_service_method(request.uri().path()) else {
+            return Box::pin(self.inner.call(request));
+        };
+
+        let parent = telemetry::extract_context(request.headers());
+        let tracer = global::tracer(SERVICE_NAME);
+        let span = tracer
+            .span_builder(format!("userpb.UserService/{}", method))
+            .with_kind(SpanKind::Server)
+            .with_attributes([
+                KeyValue::new("rpc.system", "grpc"),
+                KeyValue::new("rpc.service", "userpb.UserService"),
+                KeyValue::new("rpc.method", method),
+            ])
+            .start_with_context(&tracer, &parent);
+        let otel_cx = parent.with_span(span);
+
+        let response = {
+            let _guard = otel_cx.clone().attach();
+            self.inner.call(request)
+        };
+        Box::pin(async move {
+            let response = response.with_context(otel_cx.clone()).await;
+            let code = response_code(&response);
+            let span = otel_cx.span();
+            span.set_attribute(KeyValue::new("rpc.grpc.status_code", code as i64));
+            if code != Code::Ok {
+                span.set_status(Status::error(code.description()));
+            }
+            span.end();
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::Context;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use pretty_assertions::assert_eq;
+    use std::convert::Infallible;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn handler_runs_in_request_trace() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let service = RpcTraceLayer.layer(tower::service_fn(|_: http::Request<()>| async {
+            // Имитация обработчика: спан, открытый внутри, наследует трассировку запроса
+            tokio::task::yield_now().await;
+            let trace_id = Context::current()
+                .span()
+                .span_context()
+                .trace_id()
+                .to_string();
+            Ok::<_, Infallible>(http::Response::new(trace_id))
+        }));
+
+        let request = http::Request::builder()
+            .uri("/userpb.UserService/GetUser")
+            .header(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            )
+            .body(())
+            .unwrap();
+        let response = service.oneshot(request).await.unwrap();
+        assert_eq!(response.into_body(), "4bf92f3577b34da6a3ce929d0e0e4736");
+    }
+}
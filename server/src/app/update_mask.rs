@@ -0,0 +1,96 @@
+use crate::errors::GrpcError;
+use lib_rpc::userpb::UpdateUserRequest;
+use log::trace;
+
+/// Поля пользователя, которые можно изменить через UpdateUserData
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdatableField {
+    Username,
+    Email,
+}
+
+impl UpdatableField {
+    fn from_path(path: &str) -> Option<Self> {
+        match path {
+            "username" => Some(UpdatableField::Username),
+            "email" => Some(UpdatableField::Email),
+            _ => None,
+        }
+    }
+}
+
+/// Без маски (или с пустой маской) обновляются только непустые поля запроса,
+/// с маской - ровно перечисленные пути, в том числе пустые значения
+pub fn update_fields(req: &UpdateUserRequest) -> Result<Vec<UpdatableField>, GrpcError> {
+    let paths = match &req.update_mask {
+        Some(mask) if !mask.paths.is_empty() => &mask.paths,
+        _ => {
+            let mut fields = Vec::new();
+            if !req.username.is_empty() {
+                fields.push(UpdatableField::Username);
+            }
+            if !req.email.is_empty() {
+                fields.push(UpdatableField::Email);
+            }
+            return Ok(fields);
+        }
+    };
+
+    let mut fields = Vec::with_capacity(paths.len());
+    for (i, path) in paths.iter().enumerate() {
+        let field = UpdatableField::from_path(path).ok_or_else(|| {
+            trace!("Invalid update mask path: {}", path);
+            GrpcError::invalid_argument(
+                format!("update_mask.paths[{}]", i),
+                "INVALID_UPDATE_MASK",
+                format!("Invalid update mask path: {}", path),
+            )
+        })?;
+        if !fields.contains(&field) {
+            fields.push(field);
+        }
+    }
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use prost_types::FieldMask;
+
+    fn request(username: &str, email: &str, paths: Option<&[&str]>) -> UpdateUserRequest {
+        UpdateUserRequest {
+            username: username.to_string(),
+            email: email.to_string(),
+            update_mask: paths.map(|paths| FieldMask {
+                paths: paths.iter().map(|path| path.to_string()).collect(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_update_fields_without_mask() {
+        let fields = update_fields(&request("name", "", None)).unwrap();
+        assert_eq!(fields, vec![UpdatableField::Username]);
+
+        let fields = update_fields(&request("", "", Some(&[]))).unwrap();
+        assert_eq!(fields, vec![]);
+    }
+
+    #[test]
+    fn test_update_fields_with_mask() {
+        let fields = update_fields(&request("name", "", Some(&["email", "email"]))).unwrap();
+        assert_eq!(fields, vec![UpdatableField::Email]);
+    }
+
+    #[test]
+    fn test_update_fields_unknown_path() {
+        let result = update_fields(&request("", "", Some(&["UUID"])));
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "Invalid argument: Invalid update mask path: UUID"
+        );
+    }
+}
@@ -1,24 +1,59 @@
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use chrono::Utc;
 use log::{error, info};
 use tonic::{Request, Response, Status};
 
 use lib_rpc::userpb::user_service_server::UserService;
 use lib_rpc::userpb::{
-    CreateUserRequest, GetAllUsersRequest, GetAllUsersResponse, GetUserByIdRequest,
-    GetUserByIdResponse, GetUserIdByNicknameResponse, GetUserRequest, GetUserResponse,
+    CreateUserRequest, DeleteUserRequest, GetAllUsersRequest, GetAllUsersResponse,
+    GetUserByIdRequest, GetUserByIdResponse, GetUserIdByNicknameRequest,
+    GetUserIdByNicknameResponse, GetUserRequest, GetUserResponse, GetUsersByIdsRequest,
+    GetUsersByIdsResponse, ResolveUsernamesRequest, ResolveUsernamesResponse, RestoreUserRequest,
     UpdateUserRequest, UpdateUserResponse,
 };
 
-use crate::app::validation::{validate_user_email, validate_user_name, validate_uuid};
+use crate::app::authorization::authorize;
+use crate::app::pagination::{
+    decode_page_token, encode_page_token, is_newest_first, normalize_email_domain,
+    normalize_filter, page_size, time_filter, validate_time_range,
+};
+use crate::app::update_mask::{update_fields, UpdatableField};
+use crate::app::validation::{
+    normalize_username, validate_batch_size, validate_user_email, validate_user_name,
+    validate_uuid, UsernamePolicy,
+};
 use crate::errors::GrpcError;
 use crate::repo::UserRepository;
-use crate::types::User;
+use crate::types::{to_timestamp, User, UsersPageQuery};
+use uuid::Uuid;
 
 #[derive(Clone)]
 pub struct UserServiceCore<R: UserRepository> {
     pub repository: Arc<R>,
+    /// Правила для имён новых и переименованных пользователей
+    pub username_policy: Arc<UsernamePolicy>,
+}
+
+impl<R: UserRepository> UserServiceCore<R> {
+    async fn find_user_id_by_nickname(&self, user_name: &str) -> Result<Uuid, Status> {
+        validate_user_name("username", user_name)?;
+
+        match self
+            .repository
+            .get_user_id_by_nickname(&normalize_username(user_name))
+            .await
+        {
+            Ok(Some(user_id)) => Ok(user_id),
+            Ok(None) => {
+                error!("User with NickName \"{}\" not found", user_name);
+                Err(GrpcError::NotFound("User not found".to_string()).into())
+            }
+            Err(e) => Err(GrpcError::from(e).into()),
+        }
+    }
 }
 
 #[async_trait]
@@ -26,40 +61,33 @@ impl<R: UserRepository + 'static> UserService for UserServiceCore<R> {
     async fn get_user(
         &self, request: Request<GetUserRequest>,
     ) -> Result<Response<GetUserResponse>, Status> {
+        authorize(&request)?;
         info!(
             "Received GetUser request for NickName: \"{}\"",
             request.get_ref().username
         );
         let user_name = request.into_inner().username;
+        let user_id = self.find_user_id_by_nickname(&user_name).await?;
 
-        validate_user_name(&user_name)?;
-
-        match self.repository.get_user_id_by_nickname(&user_name).await {
-            Ok(Some(user_id)) => {
-                let response = GetUserResponse {
-                    uuid: user_id.to_string(),
-                };
-                Ok(Response::new(response))
-            }
-            Ok(None) => {
-                error!("User with NickName \"{}\" not found", user_name);
-                Err(GrpcError::NotFound("User not found".to_string()).into())
-            }
-            Err(e) => Err(GrpcError::from(e).into()),
-        }
+        let response = GetUserResponse {
+            uuid: user_id.to_string(),
+        };
+        Ok(Response::new(response))
     }
 
     async fn create_user(
         &self, request: Request<CreateUserRequest>,
     ) -> Result<Response<()>, Status> {
+        authorize(&request)?;
         info!(
             "Received CreateUser request for UUID: {}",
             request.get_ref().uuid
         );
         let req = request.into_inner();
-        let user_id = validate_uuid(&req.uuid)?;
-        validate_user_name(&req.username)?;
-        validate_user_email(&req.email)?;
+        let user_id = validate_uuid("uuid", &req.uuid)?;
+        let user_name = normalize_username(&req.username);
+        self.username_policy.validate("username", &user_name)?;
+        validate_user_email("email", &req.email)?;
 
         if self
             .repository
@@ -69,15 +97,21 @@ impl<R: UserRepository + 'static> UserService for UserServiceCore<R> {
             .is_some()
         {
             error!("User with UUID {} already exists", user_id);
-            return Err(
-                GrpcError::AlreadyExists("User with this UUID already exists".to_string()).into(),
-            );
+            return Err(GrpcError::AlreadyExists {
+                field: "UUID".to_string(),
+            }
+            .into());
         }
 
+        let now = Utc::now();
         let user = User {
             id: user_id,
-            username: req.username,
+            username: user_name,
             email: req.email,
+            deleted_at: None,
+            version: 1,
+            created_at: now,
+            updated_at: now,
         };
 
         self.repository
@@ -92,12 +126,13 @@ impl<R: UserRepository + 'static> UserService for UserServiceCore<R> {
     async fn get_user_data_by_id(
         &self, request: Request<GetUserByIdRequest>,
     ) -> Result<Response<GetUserByIdResponse>, Status> {
+        authorize(&request)?;
         info!(
             "Received GetUserData request for UUID: {}",
             request.get_ref().uuid
         );
         let user_uuid = request.into_inner().uuid;
-        let user_id = validate_uuid(&user_uuid)?;
+        let user_id = validate_uuid("uuid", &user_uuid)?;
         if let Some(user) = self
             .repository
             .get_user(&user_id)
@@ -107,6 +142,9 @@ impl<R: UserRepository + 'static> UserService for UserServiceCore<R> {
             let reply = GetUserByIdResponse {
                 username: user.username,
                 email: user.email,
+                version: user.version,
+                created_at: Some(to_timestamp(user.created_at)),
+                updated_at: Some(to_timestamp(user.updated_at)),
             };
             info!("User data retrieved for UUID: {}", user_uuid);
             Ok(Response::new(reply))
@@ -119,17 +157,22 @@ impl<R: UserRepository + 'static> UserService for UserServiceCore<R> {
     async fn update_user_data(
         &self, request: Request<UpdateUserRequest>,
     ) -> Result<Response<UpdateUserResponse>, Status> {
+        authorize(&request)?;
         info!(
             "Received UpdateUserData request for UUID: {}",
             request.get_ref().uuid
         );
-        let req = request.into_inner();
-        let user_id = validate_uuid(&req.uuid)?;
-        if !req.username.is_empty() {
-            validate_user_name(&req.username)?;
-        }
-        if !req.email.is_empty() {
-            validate_user_email(&req.email)?;
+        let mut req = request.into_inner();
+        let user_id = validate_uuid("uuid", &req.uuid)?;
+        let fields = update_fields(&req)?;
+        for field in &fields {
+            match field {
+                UpdatableField::Username => {
+                    req.username = normalize_username(&req.username);
+                    self.username_policy.validate("username", &req.username)?
+                }
+                UpdatableField::Email => validate_user_email("email", &req.email)?,
+            }
         }
 
         let mut user = self
@@ -141,12 +184,36 @@ impl<R: UserRepository + 'static> UserService for UserServiceCore<R> {
                 error!("User with UUID {} not found", user_id);
                 GrpcError::NotFound("User not found".to_string())
             })?;
+        if req.expected_version != 0 && req.expected_version != user.version {
+            error!(
+                "User {} has version {}, expected {}",
+                user_id, user.version, req.expected_version
+            );
+            return Err(GrpcError::Aborted("User was modified concurrently".to_string()).into());
+        }
 
-        if !req.username.is_empty() {
-            user.username = req.username;
+        let unchanged = fields.iter().all(|field| match field {
+            UpdatableField::Username => user.username == req.username,
+            UpdatableField::Email => user.email == req.email,
+        });
+        // Запрос без изменений не увеличивает версию, иначе клиенты с той же
+        // версией получили бы ложный конфликт
+        if unchanged {
+            info!("User {} is already up to date", req.uuid);
+            return Ok(Response::new(UpdateUserResponse {
+                message: format!("User {} updated successfully", req.uuid),
+                version: user.version,
+            }));
         }
-        if !req.email.is_empty() {
-            user.email = req.email;
+
+        // Версия прочитанной записи передаётся в репозиторий как ожидаемая,
+        // поэтому параллельное изменение между чтением и записью тоже обнаруживается
+        let new_version = user.version + 1;
+        for field in fields {
+            match field {
+                UpdatableField::Username => user.username = std::mem::take(&mut req.username),
+                UpdatableField::Email => user.email = std::mem::take(&mut req.email),
+            }
         }
 
         self.repository
@@ -157,65 +224,269 @@ impl<R: UserRepository + 'static> UserService for UserServiceCore<R> {
 
         let reply = UpdateUserResponse {
             message: format!("User {} updated successfully", req.uuid),
+            version: new_version,
         };
         Ok(Response::new(reply))
     }
 
     async fn get_all_users(
-        &self, _request: Request<GetAllUsersRequest>,
+        &self, request: Request<GetAllUsersRequest>,
     ) -> Result<Response<GetAllUsersResponse>, Status> {
-        info!("Received GetAllUsers request");
-        let users = self
+        authorize(&request)?;
+        info!(
+            "Received GetAllUsers request with page size {}",
+            request.get_ref().page_size
+        );
+        let req = request.into_inner();
+        let newest_first = is_newest_first(req.sort_order)?;
+        let limit = page_size(req.page_size);
+        let created_after = time_filter("created_after", req.created_after)?;
+        let created_before = time_filter("created_before", req.created_before)?;
+        validate_time_range(created_after, created_before)?;
+        let query = UsersPageQuery {
+            after: decode_page_token(&req.page_token, newest_first)?,
+            // Лишняя запись показывает, есть ли следующая страница
+            limit: limit + 1,
+            username_prefix: normalize_filter(&req.username_prefix),
+            email_domain: normalize_email_domain(&req.email_domain),
+            created_after,
+            created_before,
+            newest_first,
+        };
+
+        let mut users = self
             .repository
-            .get_all_users()
+            .get_all_users(&query)
             .await
             .map_err(GrpcError::from)?;
-        let response_users: Vec<lib_rpc::userpb::User> = users
-            .into_iter()
-            .map(|user| lib_rpc::userpb::User {
-                uuid: user.id.to_string(),
-                username: user.username,
-                email: user.email,
-            })
-            .collect();
+
+        let next_page_token = if users.len() > limit {
+            users.truncate(limit);
+            users
+                .last()
+                .map(|user| encode_page_token(&user.id, newest_first))
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+        let response_users: Vec<lib_rpc::userpb::User> =
+            users.into_iter().map(Into::into).collect();
 
         let response = GetAllUsersResponse {
             users: response_users,
+            next_page_token,
         };
         Ok(Response::new(response))
     }
+
+    async fn delete_user(
+        &self, request: Request<DeleteUserRequest>,
+    ) -> Result<Response<()>, Status> {
+        authorize(&request)?;
+        info!(
+            "Received DeleteUser request for UUID: {}",
+            request.get_ref().uuid
+        );
+        let user_id = validate_uuid("uuid", &request.into_inner().uuid)?;
+
+        self.repository
+            .delete_user(&user_id)
+            .await
+            .map_err(GrpcError::from)?
+            .ok_or_else(|| {
+                error!("User with UUID {} not found", user_id);
+                GrpcError::NotFound("User not found".to_string())
+            })?;
+        info!("User {} deleted successfully", user_id);
+
+        Ok(Response::new(()))
+    }
+
+    async fn restore_user(
+        &self, request: Request<RestoreUserRequest>,
+    ) -> Result<Response<()>, Status> {
+        authorize(&request)?;
+        info!(
+            "Received RestoreUser request for UUID: {}",
+            request.get_ref().uuid
+        );
+        let user_id = validate_uuid("uuid", &request.into_inner().uuid)?;
+
+        self.repository
+            .restore_user(&user_id)
+            .await
+            .map_err(GrpcError::from)?
+            .ok_or_else(|| {
+                error!("Deleted user with UUID {} not found", user_id);
+                GrpcError::NotFound("Deleted user not found".to_string())
+            })?;
+        info!("User {} restored successfully", user_id);
+
+        Ok(Response::new(()))
+    }
+
+    async fn get_user_id_by_nickname(
+        &self, request: Request<GetUserIdByNicknameRequest>,
+    ) -> Result<Response<GetUserIdByNicknameResponse>, Status> {
+        authorize(&request)?;
+        info!(
+            "Received GetUserIdByNickname request for NickName: \"{}\"",
+            request.get_ref().username
+        );
+        let user_name = request.into_inner().username;
+        let user_id = self.find_user_id_by_nickname(&user_name).await?;
+
+        let response = GetUserIdByNicknameResponse {
+            uuid: user_id.to_string(),
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn resolve_usernames(
+        &self, request: Request<ResolveUsernamesRequest>,
+    ) -> Result<Response<ResolveUsernamesResponse>, Status> {
+        authorize(&request)?;
+        info!(
+            "Received ResolveUsernames request for {} usernames",
+            request.get_ref().usernames.len()
+        );
+        let mut requested = request.into_inner().usernames;
+        validate_batch_size("usernames", requested.len())?;
+        for (i, user_name) in requested.iter().enumerate() {
+            validate_user_name(&format!("usernames[{}]", i), user_name)?;
+        }
+        let mut seen = HashSet::new();
+        requested.retain(|user_name| seen.insert(user_name.clone()));
+
+        // Имена хранятся в NFKC, ответ же возвращает их так, как их прислал клиент
+        let normalized: Vec<String> = requested.iter().map(|n| normalize_username(n)).collect();
+        let found: HashMap<String, Uuid> = self
+            .repository
+            .get_users_by_nicknames(&normalized)
+            .await
+            .map_err(GrpcError::from)?
+            .into_iter()
+            .map(|user| (user.username.to_lowercase(), user.id))
+            .collect();
+
+        let mut response = ResolveUsernamesResponse::default();
+        for (user_name, normalized) in requested.into_iter().zip(normalized) {
+            match found.get(&normalized.to_lowercase()) {
+                Some(user_id) => {
+                    response.uuids.insert(user_name, user_id.to_string());
+                }
+                None => response.not_found.push(user_name),
+            }
+        }
+        info!(
+            "Resolved {} usernames, {} not found",
+            response.uuids.len(),
+            response.not_found.len()
+        );
+        Ok(Response::new(response))
+    }
+
+    async fn get_users_by_ids(
+        &self, request: Request<GetUsersByIdsRequest>,
+    ) -> Result<Response<GetUsersByIdsResponse>, Status> {
+        authorize(&request)?;
+        info!(
+            "Received GetUsersByIds request for {} UUIDs",
+            request.get_ref().uuids.len()
+        );
+        let requested = request.into_inner().uuids;
+        validate_batch_size("uuids", requested.len())?;
+        let mut user_ids = Vec::with_capacity(requested.len());
+        let mut seen = HashSet::new();
+        for (i, user_uuid) in requested.iter().enumerate() {
+            let user_id = validate_uuid(&format!("uuids[{}]", i), user_uuid)?;
+            if seen.insert(user_id) {
+                user_ids.push(user_id);
+            }
+        }
+
+        let mut found: HashMap<Uuid, User> = self
+            .repository
+            .get_users_by_ids(&user_ids)
+            .await
+            .map_err(GrpcError::from)?
+            .into_iter()
+            .map(|user| (user.id, user))
+            .collect();
+
+        // Порядок ответа совпадает с порядком запроса
+        let mut response = GetUsersByIdsResponse::default();
+        for user_id in user_ids {
+            match found.remove(&user_id) {
+                Some(user) => response.users.push(user.into()),
+                None => response.not_found.push(user_id.to_string()),
+            }
+        }
+        info!(
+            "Fetched {} users by UUIDs, {} not found",
+            response.users.len(),
+            response.not_found.len()
+        );
+        Ok(Response::new(response))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
 
+    use chrono::{Duration, Utc};
     use pretty_assertions::assert_eq;
+    use prost_types::FieldMask;
     use tonic::Request;
+    use tonic_types::StatusExt;
     use uuid::Uuid;
 
     use app::user_service::UserServiceCore;
     use lib_rpc::userpb::user_service_server::UserService;
-    use lib_rpc::userpb::{CreateUserRequest, GetUserByIdRequest, UpdateUserRequest};
+    use lib_rpc::userpb::{
+        CreateUserRequest, DeleteUserRequest, GetAllUsersRequest, GetUserByIdRequest,
+        GetUserIdByNicknameRequest, GetUserRequest, GetUsersByIdsRequest, ResolveUsernamesRequest,
+        RestoreUserRequest, SortOrder, UpdateUserRequest,
+    };
 
     use crate::app;
+    use crate::app::authorization::tests::{as_admin, with_principal};
+    use crate::app::authorization::{SCOPE_READ, SCOPE_RESOLVE, SCOPE_WRITE};
     use crate::repo::internal::InternalRepository;
     use crate::repo::UserRepository;
-    use crate::types::User;
+    use crate::types::{to_timestamp, User};
+
+    fn service(repository: Arc<InternalRepository>) -> UserServiceCore<InternalRepository> {
+        UserServiceCore {
+            repository,
+            username_policy: Default::default(),
+        }
+    }
+
+    fn user(username: &str) -> User {
+        User {
+            id: Uuid::now_v7(),
+            username: username.to_string(),
+            email: format!("{}@example.com", username),
+            deleted_at: None,
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
 
     //переделать на проверку кода ответа
     #[tokio::test]
     async fn create_user_success() {
         let repo = Arc::new(InternalRepository::new());
 
-        let service = UserServiceCore {
-            repository: repo.clone(),
-        };
+        let service = service(repo.clone());
 
         let user_id = Uuid::now_v7();
-        let request = Request::new(CreateUserRequest {
+        let request = as_admin(CreateUserRequest {
             uuid: user_id.to_string(),
-            username: "New User".to_string(),
+            username: "new_user".to_string(),
             email: "new@example.com".to_string(),
         });
 
@@ -223,7 +494,7 @@ mod tests {
         assert!(response.is_ok(), "Expected Ok response");
 
         let added_user = repo.get_user(&user_id).await.unwrap();
-        assert_eq!(added_user.as_ref().unwrap().username, "New User");
+        assert_eq!(added_user.as_ref().unwrap().username, "new_user");
         assert_eq!(added_user.unwrap().email, "new@example.com");
     }
 
@@ -231,14 +502,12 @@ mod tests {
     async fn create_user_invalid_uuid() {
         let repo = Arc::new(InternalRepository::new());
 
-        let service = UserServiceCore {
-            repository: repo.clone(),
-        };
+        let service = service(repo.clone());
 
         let invalid_uuid = "invalid-uuid";
-        let request = Request::new(CreateUserRequest {
+        let request = as_admin(CreateUserRequest {
             uuid: invalid_uuid.to_string(),
-            username: "New User".to_string(),
+            username: "new_user".to_string(),
             email: "new@example.com".to_string(),
         });
 
@@ -250,24 +519,98 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn create_user_duplicate_uuid() {
+    async fn create_user_invalid_username() {
+        let repo = Arc::new(InternalRepository::new());
+
+        let service = service(repo.clone());
+
+        let request = as_admin(CreateUserRequest {
+            uuid: Uuid::now_v7().to_string(),
+            username: "New  User".to_string(),
+            email: "new@example.com".to_string(),
+        });
+
+        let status = service.create_user(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "Username may contain only letters, digits and separators \"_-.\""
+        );
+        assert_eq!(
+            status.get_details_error_info().unwrap().reason,
+            "USERNAME_INVALID_CHARACTERS"
+        );
+        assert_eq!(
+            status.get_details_bad_request().unwrap().field_violations[0].field,
+            "username"
+        );
+    }
+
+    #[tokio::test]
+    async fn create_user_rejects_confusable_username() {
         let repo = Arc::new(InternalRepository::new());
 
-        let service = UserServiceCore {
-            repository: repo.clone(),
+        let service = service(repo.clone());
+
+        let create = |user_name: &str, user_email: &str| {
+            as_admin(CreateUserRequest {
+                uuid: Uuid::now_v7().to_string(),
+                username: user_name.to_string(),
+                email: user_email.to_string(),
+            })
         };
+        service
+            .create_user(create("ｓｔｒｅａｍｅｒ", "streamer@example.com"))
+            .await
+            .unwrap();
+        let stored = repo
+            .get_users_by_nicknames(&["streamer".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(stored[0].username, "streamer");
+
+        // Кириллические "е" и "а" вместо латинских
+        let status = service
+            .create_user(create("strеаmer", "fake@example.com"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::AlreadyExists);
+        assert_eq!(
+            status.message(),
+            "Username is too similar to an existing one"
+        );
+        assert_eq!(
+            status.get_details_error_info().unwrap().reason,
+            "USERNAME_CONFUSABLE"
+        );
+
+        let status = service
+            .create_user(create("Streamer", "other@example.com"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            status.get_details_error_info().unwrap().reason,
+            "USER_ALREADY_EXISTS"
+        );
+    }
+
+    #[tokio::test]
+    async fn create_user_duplicate_uuid() {
+        let repo = Arc::new(InternalRepository::new());
+
+        let service = service(repo.clone());
 
         let user_id = Uuid::now_v7();
         let put_user_request = CreateUserRequest {
             uuid: user_id.to_string(),
-            username: "New User".to_string(),
+            username: "new_user".to_string(),
             email: "new@example.com".to_string(),
         };
-        let request = Request::new(put_user_request.clone());
+        let request = as_admin(put_user_request.clone());
 
         service.create_user(request).await.unwrap();
 
-        let duplicate_request = Request::new(put_user_request);
+        let duplicate_request = as_admin(put_user_request);
 
         let response = service.create_user(duplicate_request).await;
         assert!(response.is_err());
@@ -282,24 +625,21 @@ mod tests {
         let user_id = Uuid::now_v7();
         let user = User {
             id: user_id,
-            username: "Test User".to_string(),
-            email: "test@example.com".to_string(),
+            ..user("test_user")
         };
         repo.add_user(user).await.unwrap();
 
-        let service = UserServiceCore {
-            repository: repo.clone(),
-        };
+        let service = service(repo.clone());
 
-        let request = Request::new(GetUserByIdRequest {
+        let request = as_admin(GetUserByIdRequest {
             uuid: user_id.to_string(),
         });
 
         let response = service.get_user_data_by_id(request).await.unwrap();
         let response_data = response.into_inner();
 
-        assert_eq!(response_data.username, "Test User");
-        assert_eq!(response_data.email, "test@example.com");
+        assert_eq!(response_data.username, "test_user");
+        assert_eq!(response_data.email, "test_user@example.com");
     }
 
     #[tokio::test]
@@ -308,19 +648,17 @@ mod tests {
         let user_id = Uuid::now_v7();
         let user = User {
             id: user_id,
-            username: "Existing User".to_string(),
-            email: "existing@example.com".to_string(),
+            ..user("existing_user")
         };
         repo.add_user(user).await.unwrap();
 
-        let service = UserServiceCore {
-            repository: repo.clone(),
-        };
+        let service = service(repo.clone());
 
-        let request = Request::new(UpdateUserRequest {
+        let request = as_admin(UpdateUserRequest {
             uuid: user_id.to_string(),
-            username: "Updated User".to_string(),
+            username: "updated_user".to_string(),
             email: "updated@example.com".to_string(),
+            ..Default::default()
         });
 
         let response = service.update_user_data(request).await.unwrap();
@@ -332,61 +670,136 @@ mod tests {
         );
 
         let updated_user = repo.get_user(&user_id).await.unwrap();
-        assert_eq!(updated_user.as_ref().unwrap().username, "Updated User");
+        assert_eq!(updated_user.as_ref().unwrap().username, "updated_user");
         assert_eq!(updated_user.unwrap().email, "updated@example.com");
     }
-    /*
-        #[tokio::test]
-        async fn get_user_id_by_nickname() {
-            let repository = Arc::new(InternalRepository::new());
-            let service = UserServiceCore {
-                repository: repository.clone(),
-            };
+    #[tokio::test]
+    async fn get_user_id_by_nickname() {
+        let repository = Arc::new(InternalRepository::new());
+        let service = service(repository.clone());
+
+        let user = user("test_user");
+
+        repository.add_user(user.clone()).await.unwrap();
+
+        let request = as_admin(GetUserIdByNicknameRequest {
+            username: "test_user".to_string(),
+        });
+
+        let response = service.get_user_id_by_nickname(request).await;
+        assert!(response.is_ok(), "Expected OK response");
+        let user_uuid = response.unwrap().into_inner().uuid;
+        assert_eq!(user_uuid, user.id.to_string(), "User UUID does not match");
+
+        let empty_request = as_admin(GetUserIdByNicknameRequest {
+            username: "".to_string(),
+        });
+
+        let empty_response = service.get_user_id_by_nickname(empty_request).await;
+        assert!(empty_response.is_err(), "Expected error response");
+        let status = empty_response.err().unwrap();
+        assert_eq!(
+            status.code(),
+            tonic::Code::InvalidArgument,
+            "Expected InvalidArgument status"
+        );
+    }
 
+    #[tokio::test]
+    async fn resolve_usernames_batch() {
+        let repo = Arc::new(InternalRepository::new());
+        let first_id = Uuid::now_v7();
+        let second_id = Uuid::now_v7();
+        for (user_id, name) in [(first_id, "streamer"), (second_id, "Viewer")] {
             let user = User {
-                id: Uuid::now_v7(),
-                name: "test_user".to_string(),
-                email: "test_user@example.com".to_string(),
+                id: user_id,
+                ..user(name)
             };
+            repo.add_user(user).await.unwrap();
+        }
 
-            repository.add_user(user.clone()).await.unwrap();
-
-            let request = Request::new(GetUserIdByNicknameRequest {
-                username: "test_user".to_string(),
-            });
+        let service = service(repo.clone());
 
-            let response = service.get_user(request).await;
-            assert!(response.is_ok(), "Expected OK response");
-            let user_uuid = response.unwrap().into_inner().user_uuid;
-            assert_eq!(user_uuid, user.id.to_string(), "User UUID does not match");
+        let request = as_admin(ResolveUsernamesRequest {
+            usernames: vec![
+                "streamer".to_string(),
+                "viewer".to_string(),
+                "streamer".to_string(),
+                "ghost".to_string(),
+            ],
+        });
+        let response = service
+            .resolve_usernames(request)
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.uuids.len(), 2);
+        assert_eq!(response.uuids["streamer"], first_id.to_string());
+        assert_eq!(response.uuids["viewer"], second_id.to_string());
+        assert_eq!(response.not_found, vec!["ghost".to_string()]);
 
-            let empty_request = Request::new(GetUserIdByNicknameRequest {
-                username: "".to_string(),
-            });
+        let request = as_admin(ResolveUsernamesRequest {
+            usernames: vec!["".to_string()],
+        });
+        let status = service.resolve_usernames(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
 
-            let empty_response = service.get_user_id_by_nickname(empty_request).await;
-            assert!(empty_response.is_err(), "Expected error response");
-            let status = empty_response.err().unwrap();
-            assert_eq!(
-                status.code(),
-                tonic::Code::InvalidArgument,
-                "Expected InvalidArgument status"
-            );
+    #[tokio::test]
+    async fn get_users_by_ids_batch() {
+        let repo = Arc::new(InternalRepository::new());
+        let active_id = Uuid::now_v7();
+        let deleted_id = Uuid::now_v7();
+        for (user_id, name) in [(active_id, "active"), (deleted_id, "deleted")] {
+            let user = User {
+                id: user_id,
+                ..user(name)
+            };
+            repo.add_user(user).await.unwrap();
         }
-    */
+        repo.delete_user(&deleted_id).await.unwrap();
+
+        let service = service(repo.clone());
+
+        let request = as_admin(GetUsersByIdsRequest {
+            uuids: vec![deleted_id.to_string(), active_id.to_string()],
+        });
+        let response = service
+            .get_users_by_ids(request)
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.users.len(), 1);
+        assert_eq!(response.users[0].uuid, active_id.to_string());
+        assert_eq!(response.users[0].username, "active");
+        assert_eq!(response.not_found, vec![deleted_id.to_string()]);
+
+        let request = as_admin(GetUsersByIdsRequest {
+            uuids: vec![active_id.to_string(), "invalid-uuid".to_string()],
+        });
+        let status = service.get_users_by_ids(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(status.message(), "Invalid UUID");
+        let violations = status.get_details_bad_request().unwrap().field_violations;
+        assert_eq!(violations[0].field, "uuids[1]");
+        assert_eq!(
+            status.get_details_error_info().unwrap().reason,
+            "INVALID_UUID"
+        );
+    }
+
     #[tokio::test]
     async fn update_user_data_invalid_uuid() {
         let repo = Arc::new(InternalRepository::new());
 
-        let service = UserServiceCore {
-            repository: repo.clone(),
-        };
+        let service = service(repo.clone());
 
         let invalid_uuid = "invalid-uuid".to_string();
-        let request = Request::new(UpdateUserRequest {
+        let request = as_admin(UpdateUserRequest {
             uuid: invalid_uuid,
-            username: "Updated User".to_string(),
+            username: "updated_user".to_string(),
             email: "updated@example.com".to_string(),
+            ..Default::default()
         });
 
         let response = service.update_user_data(request).await;
@@ -400,15 +813,14 @@ mod tests {
     async fn update_user_data_not_found() {
         let repo = Arc::new(InternalRepository::new());
 
-        let service = UserServiceCore {
-            repository: repo.clone(),
-        };
+        let service = service(repo.clone());
 
         let non_existent_uuid = Uuid::now_v7().to_string();
-        let request = Request::new(UpdateUserRequest {
+        let request = as_admin(UpdateUserRequest {
             uuid: non_existent_uuid,
-            username: "Updated User".to_string(),
+            username: "updated_user".to_string(),
             email: "updated@example.com".to_string(),
+            ..Default::default()
         });
 
         let response = service.update_user_data(request).await;
@@ -417,4 +829,529 @@ mod tests {
         assert_eq!(status.code(), tonic::Code::NotFound);
         assert_eq!(status.message(), "User not found");
     }
+
+    #[tokio::test]
+    async fn update_user_data_with_mask() {
+        let repo = Arc::new(InternalRepository::new());
+        let user_id = Uuid::now_v7();
+        let user = User {
+            id: user_id,
+            ..user("existing_user")
+        };
+        repo.add_user(user).await.unwrap();
+
+        let service = service(repo.clone());
+
+        let request = as_admin(UpdateUserRequest {
+            uuid: user_id.to_string(),
+            username: "ignored_user".to_string(),
+            email: "masked@example.com".to_string(),
+            update_mask: Some(FieldMask {
+                paths: vec!["email".to_string()],
+            }),
+            ..Default::default()
+        });
+        service.update_user_data(request).await.unwrap();
+
+        let updated_user = repo.get_user(&user_id).await.unwrap().unwrap();
+        assert_eq!(updated_user.username, "existing_user");
+        assert_eq!(updated_user.email, "masked@example.com");
+
+        let request = as_admin(UpdateUserRequest {
+            uuid: user_id.to_string(),
+            update_mask: Some(FieldMask {
+                paths: vec!["email".to_string()],
+            }),
+            ..Default::default()
+        });
+        let status = service.update_user_data(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let request = as_admin(UpdateUserRequest {
+            uuid: user_id.to_string(),
+            update_mask: Some(FieldMask {
+                paths: vec!["deleted_at".to_string()],
+            }),
+            ..Default::default()
+        });
+        let status = service.update_user_data(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(status.message(), "Invalid update mask path: deleted_at");
+    }
+
+    #[tokio::test]
+    async fn update_user_data_version_conflict() {
+        let repo = Arc::new(InternalRepository::new());
+        let user_id = Uuid::now_v7();
+        let user = User {
+            id: user_id,
+            ..user("versioned_user")
+        };
+        repo.add_user(user).await.unwrap();
+
+        let service = service(repo.clone());
+
+        let request = as_admin(UpdateUserRequest {
+            uuid: user_id.to_string(),
+            email: "first@example.com".to_string(),
+            expected_version: 1,
+            ..Default::default()
+        });
+        let response = service.update_user_data(request).await.unwrap();
+        assert_eq!(response.into_inner().version, 2);
+
+        let request = as_admin(UpdateUserRequest {
+            uuid: user_id.to_string(),
+            email: "second@example.com".to_string(),
+            expected_version: 1,
+            ..Default::default()
+        });
+        let status = service.update_user_data(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Aborted);
+        assert_eq!(status.message(), "User was modified concurrently");
+
+        let request = as_admin(GetUserByIdRequest {
+            uuid: user_id.to_string(),
+        });
+        let response = service.get_user_data_by_id(request).await.unwrap();
+        let response_data = response.into_inner();
+        assert_eq!(response_data.email, "first@example.com");
+        assert_eq!(response_data.version, 2);
+    }
+
+    #[tokio::test]
+    async fn update_user_data_without_changes_keeps_version() {
+        let repo = Arc::new(InternalRepository::new());
+        let user_id = Uuid::now_v7();
+        let user = User {
+            id: user_id,
+            ..user("unchanged_user")
+        };
+        let updated_at = user.updated_at;
+        repo.add_user(user).await.unwrap();
+
+        let service = service(repo.clone());
+
+        let request = as_admin(UpdateUserRequest {
+            uuid: user_id.to_string(),
+            username: "unchanged_user".to_string(),
+            email: "unchanged_user@example.com".to_string(),
+            expected_version: 1,
+            ..Default::default()
+        });
+        let response = service.update_user_data(request).await.unwrap();
+        assert_eq!(response.into_inner().version, 1);
+        let stored = repo.get_user(&user_id).await.unwrap().unwrap();
+        assert_eq!(stored.version, 1);
+        assert_eq!(stored.updated_at, updated_at);
+
+        // Клиент с прежней версией по-прежнему может изменить запись
+        let request = as_admin(UpdateUserRequest {
+            uuid: user_id.to_string(),
+            email: "changed@example.com".to_string(),
+            expected_version: 1,
+            ..Default::default()
+        });
+        let response = service.update_user_data(request).await.unwrap();
+        assert_eq!(response.into_inner().version, 2);
+    }
+
+    #[tokio::test]
+    async fn get_all_users_paginated() {
+        let repo = Arc::new(InternalRepository::new());
+        let mut user_ids = Vec::new();
+        for i in 0..5 {
+            let user_id = Uuid::now_v7();
+            user_ids.push(user_id.to_string());
+            let user = User {
+                id: user_id,
+                ..user(&format!("user{}", i))
+            };
+            repo.add_user(user).await.unwrap();
+        }
+
+        let service = service(repo.clone());
+
+        let mut page_token = String::new();
+        let mut fetched_ids = Vec::new();
+        loop {
+            let request = as_admin(GetAllUsersRequest {
+                page_size: 2,
+                page_token,
+                ..Default::default()
+            });
+            let response = service.get_all_users(request).await.unwrap().into_inner();
+            assert!(response.users.len() <= 2);
+            fetched_ids.extend(response.users.into_iter().map(|u| u.uuid));
+            if response.next_page_token.is_empty() {
+                break;
+            }
+            page_token = response.next_page_token;
+        }
+        assert_eq!(fetched_ids, user_ids);
+
+        let request = as_admin(GetAllUsersRequest {
+            page_size: 1,
+            sort_order: SortOrder::NewestFirst.into(),
+            ..Default::default()
+        });
+        let response = service.get_all_users(request).await.unwrap().into_inner();
+        assert_eq!(response.users[0].uuid, user_ids[4]);
+        assert!(!response.next_page_token.is_empty());
+    }
+
+    #[tokio::test]
+    async fn get_all_users_created_range() {
+        let repo = Arc::new(InternalRepository::new());
+        let now = Utc::now();
+        let mut user_ids = Vec::new();
+        for days_ago in [30, 10, 1] {
+            let user_id = Uuid::now_v7();
+            user_ids.push(user_id.to_string());
+            let created_at = now - Duration::days(days_ago);
+            let user = User {
+                id: user_id,
+                created_at,
+                updated_at: created_at,
+                ..user(&format!("user{}", days_ago))
+            };
+            repo.add_user(user).await.unwrap();
+        }
+
+        let service = service(repo.clone());
+
+        let request = as_admin(GetAllUsersRequest {
+            created_after: Some(to_timestamp(now - Duration::days(20))),
+            created_before: Some(to_timestamp(now - Duration::days(1))),
+            ..Default::default()
+        });
+        let response = service.get_all_users(request).await.unwrap().into_inner();
+        let fetched_ids: Vec<String> = response.users.into_iter().map(|u| u.uuid).collect();
+        assert_eq!(fetched_ids, user_ids[1..2]);
+
+        let request = as_admin(GetAllUsersRequest {
+            created_after: Some(to_timestamp(now)),
+            created_before: Some(to_timestamp(now - Duration::days(1))),
+            ..Default::default()
+        });
+        let status = service.get_all_users(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn get_all_users_invalid_page_token() {
+        let repo = Arc::new(InternalRepository::new());
+
+        let service = service(repo.clone());
+
+        let request = as_admin(GetAllUsersRequest {
+            page_token: "invalid-token".to_string(),
+            ..Default::default()
+        });
+
+        let response = service.get_all_users(request).await;
+        assert!(response.is_err(), "Expected error response");
+        let status = response.err().unwrap();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(status.message(), "Invalid page token");
+    }
+
+    #[tokio::test]
+    async fn delete_and_restore_user() {
+        let repo = Arc::new(InternalRepository::new());
+        let user_id = Uuid::now_v7();
+        let user = User {
+            id: user_id,
+            ..user("deleted_user")
+        };
+        repo.add_user(user).await.unwrap();
+
+        let service = service(repo.clone());
+
+        let request = as_admin(DeleteUserRequest {
+            uuid: user_id.to_string(),
+        });
+        service.delete_user(request).await.unwrap();
+
+        let request = as_admin(GetUserByIdRequest {
+            uuid: user_id.to_string(),
+        });
+        let status = service.get_user_data_by_id(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        let request = as_admin(DeleteUserRequest {
+            uuid: user_id.to_string(),
+        });
+        let status = service.delete_user(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        let request = as_admin(RestoreUserRequest {
+            uuid: user_id.to_string(),
+        });
+        service.restore_user(request).await.unwrap();
+
+        let restored_user = repo.get_user(&user_id).await.unwrap();
+        assert_eq!(restored_user.unwrap().username, "deleted_user");
+    }
+
+    #[tokio::test]
+    async fn restore_user_not_deleted() {
+        let repo = Arc::new(InternalRepository::new());
+        let user_id = Uuid::now_v7();
+        let user = User {
+            id: user_id,
+            ..user("active_user")
+        };
+        repo.add_user(user).await.unwrap();
+
+        let service = service(repo.clone());
+
+        let request = as_admin(RestoreUserRequest {
+            uuid: user_id.to_string(),
+        });
+        let status = service.restore_user(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        assert_eq!(status.message(), "Deleted user not found");
+    }
+
+    #[tokio::test]
+    async fn create_user_duplicate_username_and_email() {
+        let repo = Arc::new(InternalRepository::new());
+
+        let service = service(repo.clone());
+
+        let request = as_admin(CreateUserRequest {
+            uuid: Uuid::now_v7().to_string(),
+            username: "Taken".to_string(),
+            email: "taken@example.com".to_string(),
+        });
+        service.create_user(request).await.unwrap();
+
+        let request = as_admin(CreateUserRequest {
+            uuid: Uuid::now_v7().to_string(),
+            username: "TAKEN".to_string(),
+            email: "other@example.com".to_string(),
+        });
+        let status = service.create_user(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::AlreadyExists);
+        assert_eq!(status.message(), "User with this username already exists");
+
+        let request = as_admin(CreateUserRequest {
+            uuid: Uuid::now_v7().to_string(),
+            username: "Other".to_string(),
+            email: "Taken@Example.com".to_string(),
+        });
+        let status = service.create_user(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::AlreadyExists);
+        assert_eq!(status.message(), "User with this email already exists");
+    }
+
+    #[tokio::test]
+    async fn update_user_data_duplicate_username() {
+        let repo = Arc::new(InternalRepository::new());
+        let user_id = Uuid::now_v7();
+        for (id, name) in [(Uuid::now_v7(), "first"), (user_id, "second")] {
+            let user = User { id, ..user(name) };
+            repo.add_user(user).await.unwrap();
+        }
+
+        let service = service(repo.clone());
+
+        let request = as_admin(UpdateUserRequest {
+            uuid: user_id.to_string(),
+            username: "First".to_string(),
+            email: String::new(),
+            ..Default::default()
+        });
+        let status = service.update_user_data(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::AlreadyExists);
+        assert_eq!(status.message(), "User with this username already exists");
+
+        let request = as_admin(GetUserRequest {
+            username: "FIRST".to_string(),
+        });
+        let response = service.get_user(request).await.unwrap().into_inner();
+        assert_ne!(response.uuid, user_id.to_string());
+    }
+
+    #[tokio::test]
+    async fn anonymous_caller_gets_only_public_methods() {
+        let repository = Arc::new(InternalRepository::new());
+        let service = service(repository.clone());
+        let user = user("public_user");
+        repository.add_user(user.clone()).await.unwrap();
+
+        let response = service
+            .get_user(Request::new(GetUserRequest {
+                username: "public_user".to_string(),
+            }))
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner().uuid, user.id.to_string());
+
+        let status = service
+            .get_user_data_by_id(Request::new(GetUserByIdRequest {
+                uuid: user.id.to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let status = service
+            .get_all_users(Request::new(GetAllUsersRequest::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn user_manages_only_own_record() {
+        let repository = Arc::new(InternalRepository::new());
+        let service = service(repository.clone());
+        let owner = user("owner");
+        let other = user("other");
+        repository.add_user(owner.clone()).await.unwrap();
+        repository.add_user(other.clone()).await.unwrap();
+        let subject = owner.id.to_string();
+        let update = |user: &User| UpdateUserRequest {
+            uuid: user.id.to_string(),
+            username: "renamed".to_string(),
+            update_mask: Some(FieldMask {
+                paths: vec!["username".to_string()],
+            }),
+            ..Default::default()
+        };
+
+        let response = service
+            .get_user_data_by_id(with_principal(
+                &subject,
+                &[],
+                GetUserByIdRequest {
+                    uuid: subject.clone(),
+                },
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner().email, owner.email);
+        service
+            .update_user_data(with_principal(&subject, &[], update(&owner)))
+            .await
+            .unwrap();
+
+        let status = service
+            .get_user_data_by_id(with_principal(
+                &subject,
+                &[],
+                GetUserByIdRequest {
+                    uuid: other.id.to_string(),
+                },
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        let status = service
+            .update_user_data(with_principal(&subject, &[], update(&other)))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        let status = service
+            .get_all_users(with_principal(&subject, &[], GetAllUsersRequest::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        let other_record = repository.get_user(&other.id).await.unwrap().unwrap();
+        assert_eq!(other_record.username, "other");
+    }
+
+    #[tokio::test]
+    async fn moderator_edits_but_cannot_delete() {
+        let repository = Arc::new(InternalRepository::new());
+        let service = service(repository.clone());
+        let target = user("target");
+        repository.add_user(target.clone()).await.unwrap();
+        let scopes = [SCOPE_READ, SCOPE_WRITE];
+
+        service
+            .update_user_data(with_principal(
+                "moderator",
+                &scopes,
+                UpdateUserRequest {
+                    uuid: target.id.to_string(),
+                    username: "moderated".to_string(),
+                    ..Default::default()
+                },
+            ))
+            .await
+            .unwrap();
+        let response = service
+            .get_all_users(with_principal(
+                "moderator",
+                &scopes,
+                GetAllUsersRequest::default(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner().users[0].username, "moderated");
+
+        let status = service
+            .delete_user(with_principal(
+                "moderator",
+                &scopes,
+                DeleteUserRequest {
+                    uuid: target.id.to_string(),
+                },
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        service
+            .delete_user(as_admin(DeleteUserRequest {
+                uuid: target.id.to_string(),
+            }))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn resolve_only_service() {
+        let repository = Arc::new(InternalRepository::new());
+        let service = service(repository.clone());
+        let target = user("resolved");
+        repository.add_user(target.clone()).await.unwrap();
+
+        let response = service
+            .resolve_usernames(with_principal(
+                "chat-service",
+                &[SCOPE_RESOLVE],
+                ResolveUsernamesRequest {
+                    usernames: vec!["resolved".to_string()],
+                },
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.into_inner().uuids["resolved"],
+            target.id.to_string()
+        );
+
+        let status = service
+            .get_users_by_ids(with_principal(
+                "chat-service",
+                &[SCOPE_RESOLVE],
+                GetUsersByIdsRequest {
+                    uuids: vec![target.id.to_string()],
+                },
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        let status = service
+            .resolve_usernames(Request::new(ResolveUsernamesRequest {
+                usernames: vec!["resolved".to_string()],
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
 }
@@ -1,38 +1,347 @@
-use crate::errors::GrpcError;
-use log::{error, trace};
-use uuid::Uuid;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+use crate::config::UsernamePolicyConfig;
+use crate::errors::{GrpcError, UsernamePolicyError};
+use log::trace;
 use regex::Regex;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::skeleton;
+use uuid::Uuid;
 
-pub fn validate_uuid(uuid_str: &str) -> Result<Uuid, GrpcError> {
+/// Ограничение на количество элементов в пакетных запросах
+pub const MAX_BATCH_SIZE: usize = 1000;
+/// Зарезервированные имена по умолчанию, если файл не задан в настройках
+const BUILTIN_RESERVED_USERNAMES: &str = include_str!("../../reserved-usernames.txt");
+
+static EMAIL: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[a-zA-Z0-9_.+-]+@[a-zA-Z0-9-]+\.[a-zA-Z0-9-.]+$").unwrap());
+
+/// field - путь поля в запросе, он попадает в BadRequest ответа
+pub fn validate_uuid(field: &str, uuid_str: &str) -> Result<Uuid, GrpcError> {
     Uuid::parse_str(uuid_str).map_err(|_| {
-        trace!("Invalid UUID: {}", uuid_str);
-        GrpcError::InvalidArgument("Invalid UUID".to_string())
+        trace!("Invalid UUID in {}: {}", field, uuid_str);
+        GrpcError::invalid_argument(field, "INVALID_UUID", "Invalid UUID")
     })
 }
 
-pub fn validate_user_name(name: &str) -> Result<(), GrpcError> {
+/// Имя в форме NFKC: полноширинные буквы, лигатуры и составные символы заменяются
+/// обычными, поэтому одно и то же имя не записать разными кодовыми точками
+pub fn normalize_username(name: &str) -> String {
+    name.nfkc().collect()
+}
+
+/// Скелет имени по Unicode TR39, одинаковый у похожих на вид имён: "admin" и "аdmin"
+/// с кириллической "а", "Ivan" и "lvan". Регистр не учитывается, как в уникальности имён,
+/// поэтому скелет строится ещё раз после перевода в нижний регистр
+pub fn username_skeleton(name: &str) -> String {
+    let skeleton_of_name: String = skeleton(name).collect();
+    skeleton(&skeleton_of_name.to_lowercase()).collect()
+}
+
+pub fn validate_user_name(field: &str, name: &str) -> Result<(), GrpcError> {
     if name.is_empty() {
-        trace!("User name cannot be empty");
-        return Err(GrpcError::InvalidArgument(
-            "User name cannot be empty".to_string(),
+        trace!("User name in {} cannot be empty", field);
+        return Err(GrpcError::invalid_argument(
+            field,
+            "INVALID_USERNAME",
+            "User name cannot be empty",
         ));
     }
     Ok(())
 }
 
-pub fn validate_user_email(email: &str) -> Result<(), GrpcError> {
+/// Класс символов, допустимых в имени пользователя помимо разделителей
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CharacterClass {
+    /// Буквы любого алфавита
+    Letters,
+    /// Только латинские буквы
+    AsciiLetters,
+    /// Цифры 0-9
+    Digits,
+}
+
+impl CharacterClass {
+    fn contains(self, c: char) -> bool {
+        match self {
+            CharacterClass::Letters => c.is_alphabetic(),
+            CharacterClass::AsciiLetters => c.is_ascii_alphabetic(),
+            CharacterClass::Digits => c.is_ascii_digit(),
+        }
+    }
+
+    fn is_letters(self) -> bool {
+        self != CharacterClass::Digits
+    }
+}
+
+impl FromStr for CharacterClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "letters" => Ok(CharacterClass::Letters),
+            "ascii_letters" => Ok(CharacterClass::AsciiLetters),
+            "digits" => Ok(CharacterClass::Digits),
+            _ => Err(format!(
+                "unknown character class \"{}\": must be one of letters, ascii_letters, digits",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CharacterClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CharacterClass::Letters => write!(f, "letters"),
+            CharacterClass::AsciiLetters => write!(f, "ascii_letters"),
+            CharacterClass::Digits => write!(f, "digits"),
+        }
+    }
+}
+
+/// Набор классов символов в формате "letters,digits"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CharacterClasses(Vec<CharacterClass>);
+
+impl CharacterClasses {
+    fn contains(&self, c: char) -> bool {
+        self.0.iter().any(|class| class.contains(c))
+    }
+
+    fn is_letter(&self, c: char) -> bool {
+        self.0
+            .iter()
+            .any(|class| class.is_letters() && class.contains(c))
+    }
+
+    /// Есть ли в наборе буквы - без них имя не может начинаться с буквы
+    pub fn has_letters(&self) -> bool {
+        self.0.iter().any(|class| class.is_letters())
+    }
+
+    /// Описание для сообщения клиенту
+    fn describe(&self) -> Vec<&'static str> {
+        self.0
+            .iter()
+            .map(|class| match class {
+                CharacterClass::Letters => "letters",
+                CharacterClass::AsciiLetters => "Latin letters",
+                CharacterClass::Digits => "digits",
+            })
+            .collect()
+    }
+}
+
+impl FromStr for CharacterClasses {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let classes = s
+            .split(',')
+            .map(str::trim)
+            .filter(|class| !class.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        if classes.is_empty() {
+            return Err("at least one character class is required".to_string());
+        }
+        Ok(CharacterClasses(classes))
+    }
+}
+
+impl fmt::Display for CharacterClasses {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let classes: Vec<String> = self.0.iter().map(ToString::to_string).collect();
+        write!(f, "{}", classes.join(","))
+    }
+}
+
+/// Правила для имён при регистрации и переименовании. Поиск по имени
+/// их не применяет, чтобы находились и пользователи, созданные до них
+#[derive(Debug, Clone)]
+pub struct UsernamePolicy {
+    min_length: usize,
+    max_length: usize,
+    characters: CharacterClasses,
+    separators: Vec<char>,
+    must_start_with_letter: bool,
+    /// Зарезервированные имена в виде reserved_key
+    reserved_words: HashSet<String>,
+}
+
+impl Default for UsernamePolicy {
+    fn default() -> Self {
+        UsernamePolicy::with_reserved_words(
+            &UsernamePolicyConfig::default(),
+            BUILTIN_RESERVED_USERNAMES,
+        )
+    }
+}
+
+impl UsernamePolicy {
+    /// Зарезервированные имена читаются из reserved_words_file или берутся встроенные
+    pub fn new(config: &UsernamePolicyConfig) -> Result<Self, UsernamePolicyError> {
+        let reserved_words = match &config.reserved_words_file {
+            Some(path) => {
+                fs::read_to_string(path).map_err(|e| UsernamePolicyError::ReservedWords {
+                    path: path.display().to_string(),
+                    error: e.to_string(),
+                })?
+            }
+            None => BUILTIN_RESERVED_USERNAMES.to_string(),
+        };
+        Ok(UsernamePolicy::with_reserved_words(config, &reserved_words))
+    }
+
+    /// Слова по одному в строке, после # - комментарий
+    fn with_reserved_words(config: &UsernamePolicyConfig, reserved_words: &str) -> Self {
+        let mut policy = UsernamePolicy {
+            min_length: config.min_length,
+            max_length: config.max_length,
+            characters: config.characters.clone(),
+            separators: config.separators.chars().collect(),
+            must_start_with_letter: config.must_start_with_letter,
+            reserved_words: HashSet::new(),
+        };
+        policy.reserved_words = reserved_words
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|word| !word.is_empty())
+            .map(|word| policy.reserved_key(word))
+            .collect();
+        policy
+    }
+
+    pub fn reserved_words_count(&self) -> usize {
+        self.reserved_words.len()
+    }
+
+    /// Без учёта регистра и разделителей: Admin, ad_min и a.d.m.i.n - одно и то же имя
+    fn reserved_key(&self, name: &str) -> String {
+        name.chars()
+            .filter(|c| !self.separators.contains(c))
+            .flat_map(char::to_lowercase)
+            .collect()
+    }
+
+    /// Проверяет все правила сразу. В описании ошибки перечислены все нарушенные,
+    /// reason - код первого из них
+    pub fn validate(&self, field: &str, name: &str) -> Result<(), GrpcError> {
+        let mut violations: Vec<(&'static str, String)> = Vec::new();
+
+        let length = name.chars().count();
+        if length < self.min_length {
+            violations.push((
+                "USERNAME_TOO_SHORT",
+                format!("must be at least {} characters long", self.min_length),
+            ));
+        }
+        if length > self.max_length {
+            violations.push((
+                "USERNAME_TOO_LONG",
+                format!("must be at most {} characters long", self.max_length),
+            ));
+        }
+        if !name
+            .chars()
+            .all(|c| self.characters.contains(c) || self.separators.contains(&c))
+        {
+            violations.push((
+                "USERNAME_INVALID_CHARACTERS",
+                format!("may contain only {}", self.describe_characters()),
+            ));
+        }
+        if self.must_start_with_letter
+            && !name
+                .chars()
+                .next()
+                .is_some_and(|c| self.characters.is_letter(c))
+        {
+            violations.push((
+                "USERNAME_MUST_START_WITH_LETTER",
+                "must start with a letter".to_string(),
+            ));
+        }
+        let chars: Vec<char> = name.chars().collect();
+        if chars
+            .windows(2)
+            .any(|pair| pair.iter().all(|c| self.separators.contains(c)))
+        {
+            violations.push((
+                "USERNAME_CONSECUTIVE_SEPARATORS",
+                "must not contain consecutive separators".to_string(),
+            ));
+        }
+        if self.reserved_words.contains(&self.reserved_key(name)) {
+            violations.push(("USERNAME_RESERVED", "is reserved".to_string()));
+        }
+
+        match violations.first() {
+            None => Ok(()),
+            Some(&(reason, _)) => {
+                let rules: Vec<&str> = violations.iter().map(|(_, rule)| rule.as_str()).collect();
+                trace!("User name in {} violates {}: {}", field, reason, name);
+                Err(GrpcError::invalid_argument(
+                    field,
+                    reason,
+                    format!("Username {}", rules.join("; ")),
+                ))
+            }
+        }
+    }
+
+    fn describe_characters(&self) -> String {
+        let mut allowed: Vec<String> = self
+            .characters
+            .describe()
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        if !self.separators.is_empty() {
+            let separators: String = self.separators.iter().collect();
+            allowed.push(format!("separators \"{}\"", separators));
+        }
+        match allowed.split_last() {
+            Some((last, rest)) if !rest.is_empty() => format!("{} and {}", rest.join(", "), last),
+            _ => allowed.concat(),
+        }
+    }
+}
+
+pub fn validate_user_email(field: &str, email: &str) -> Result<(), GrpcError> {
     if email.is_empty() {
-        trace!("User email cannot be empty");
-        return Err(GrpcError::InvalidArgument(
-            "User email cannot be empty".to_string(),
+        trace!("User email in {} cannot be empty", field);
+        return Err(GrpcError::invalid_argument(
+            field,
+            "INVALID_EMAIL",
+            "User email cannot be empty",
+        ));
+    }
+    if !EMAIL.is_match(email) {
+        trace!("Invalid email format in {}: {}", field, email);
+        return Err(GrpcError::invalid_argument(
+            field,
+            "INVALID_EMAIL",
+            "Invalid email format",
         ));
     }
-    let email_regex = Regex::new(r"^[a-zA-Z0-9_.+-]+@[a-zA-Z0-9-]+\.[a-zA-Z0-9-.]+$")
-        .map_err(|_| GrpcError::Internal("Failed to compile regex".to_string()))?;
+    Ok(())
+}
 
-    if !email_regex.is_match(email) {
-        trace!("Invalid email format: {}", email);
-        return Err(GrpcError::InvalidArgument("Invalid email format".to_string()));
+pub fn validate_batch_size(field: &str, size: usize) -> Result<(), GrpcError> {
+    if size > MAX_BATCH_SIZE {
+        trace!("Batch size {} exceeds limit {}", size, MAX_BATCH_SIZE);
+        return Err(GrpcError::invalid_argument(
+            field,
+            "BATCH_TOO_LARGE",
+            format!("Batch size cannot exceed {}", MAX_BATCH_SIZE),
+        ));
     }
     Ok(())
 }
@@ -41,16 +350,15 @@ pub fn validate_user_email(email: &str) -> Result<(), GrpcError> {
 mod tests {
     use super::*;
     use uuid::Uuid;
-    use crate::errors::GrpcError;
 
     #[test]
     fn test_validate_uuid() {
         let valid_uuid = Uuid::now_v7().to_string();
-        let result = validate_uuid(&valid_uuid);
+        let result = validate_uuid("uuid", &valid_uuid);
         assert!(result.is_ok());
 
         let invalid_uuid = "invalid-uuid";
-        let result = validate_uuid(invalid_uuid);
+        let result = validate_uuid("uuid", invalid_uuid);
         assert!(result.is_err());
         assert_eq!(result.err().unwrap().to_string(), "Invalid argument: Invalid UUID");
     }
@@ -58,29 +366,166 @@ mod tests {
     #[test]
     fn test_validate_user_name() {
         let valid_name = "testuser";
-        let result = validate_user_name(valid_name);
+        let result = validate_user_name("username", valid_name);
         assert!(result.is_ok());
 
         let invalid_name = "";
-        let result = validate_user_name(invalid_name);
+        let result = validate_user_name("username", invalid_name);
         assert!(result.is_err());
         assert_eq!(result.err().unwrap().to_string(), "Invalid argument: User name cannot be empty");
     }
 
+    fn violation(policy: &UsernamePolicy, name: &str) -> (&'static str, String) {
+        match policy.validate("username", name) {
+            Err(GrpcError::InvalidArgument {
+                reason,
+                description,
+                ..
+            }) => (reason, description),
+            other => panic!("expected InvalidArgument for {:?}, got {:?}", name, other),
+        }
+    }
+
+    #[test]
+    fn test_username_policy() {
+        let policy = UsernamePolicy::default();
+        for name in ["streamer_42", "Anna.K", "Вася-Пупкин", "abc"] {
+            assert!(policy.validate("username", name).is_ok(), "{}", name);
+        }
+
+        assert_eq!(
+            violation(&policy, "ab"),
+            (
+                "USERNAME_TOO_SHORT",
+                "Username must be at least 3 characters long".to_string()
+            )
+        );
+        assert_eq!(violation(&policy, &"a".repeat(5000)).0, "USERNAME_TOO_LONG");
+        assert_eq!(
+            violation(&policy, "New User"),
+            (
+                "USERNAME_INVALID_CHARACTERS",
+                "Username may contain only letters, digits and separators \"_-.\"".to_string()
+            )
+        );
+        assert_eq!(
+            violation(&policy, "fire🔥").0,
+            "USERNAME_INVALID_CHARACTERS"
+        );
+        assert_eq!(
+            violation(&policy, "bad\u{7}name").0,
+            "USERNAME_INVALID_CHARACTERS"
+        );
+        assert_eq!(
+            violation(&policy, "a__b").0,
+            "USERNAME_CONSECUTIVE_SEPARATORS"
+        );
+        assert_eq!(
+            violation(&policy, "_xy"),
+            (
+                "USERNAME_MUST_START_WITH_LETTER",
+                "Username must start with a letter".to_string()
+            )
+        );
+        assert_eq!(
+            violation(&policy, "1."),
+            (
+                "USERNAME_TOO_SHORT",
+                "Username must be at least 3 characters long; must start with a letter".to_string()
+            )
+        );
+        for name in ["admin", "Admin", "ad_min", "a.d.m.i.n", "Модератор"] {
+            assert_eq!(violation(&policy, name).0, "USERNAME_RESERVED", "{}", name);
+        }
+        assert!(policy.validate("username", "admin_anna").is_ok());
+    }
+
+    #[test]
+    fn test_username_policy_from_config() {
+        let path =
+            std::env::temp_dir().join(format!("user-service-reserved-{}.txt", Uuid::now_v7()));
+        fs::write(&path, "# staff\nceo # and cto\n\n").unwrap();
+        let config = UsernamePolicyConfig {
+            min_length: 2,
+            max_length: 8,
+            characters: "ascii_letters".parse().unwrap(),
+            separators: "_".to_string(),
+            must_start_with_letter: false,
+            reserved_words_file: Some(path.clone()),
+        };
+        let policy = UsernamePolicy::new(&config).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(policy.reserved_words_count(), 1);
+        assert!(policy.validate("username", "admin").is_ok());
+        assert!(policy.validate("username", "_ab").is_ok());
+        assert_eq!(violation(&policy, "C_E_O").0, "USERNAME_RESERVED");
+        assert_eq!(
+            violation(&policy, "ab-1"),
+            (
+                "USERNAME_INVALID_CHARACTERS",
+                "Username may contain only Latin letters and separators \"_\"".to_string()
+            )
+        );
+        assert_eq!(violation(&policy, "Вася").0, "USERNAME_INVALID_CHARACTERS");
+
+        assert_eq!(
+            "letters,emoji".parse::<CharacterClasses>().unwrap_err(),
+            "unknown character class \"emoji\": must be one of letters, ascii_letters, digits"
+        );
+        assert!(UsernamePolicy::new(&UsernamePolicyConfig {
+            reserved_words_file: Some(path),
+            ..UsernamePolicyConfig::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_username_skeleton() {
+        assert_eq!(normalize_username("ｓｔｒｅａｍｅｒ"), "streamer");
+        assert_eq!(normalize_username("ﬁsh"), "fish");
+        // "й" из двух кодовых точек и из одной
+        assert_eq!(normalize_username("и\u{306}"), "й");
+
+        let admin = username_skeleton("admin");
+        for lookalike in ["аdmin", "аdmіn", "Admin"] {
+            assert_eq!(username_skeleton(lookalike), admin, "{}", lookalike);
+        }
+        assert_eq!(username_skeleton("АDMIN"), username_skeleton("ADMIN"));
+        assert_eq!(username_skeleton("Ivan"), username_skeleton("lvan"));
+        // Латинские B, a и c в кириллическом имени
+        assert_eq!(username_skeleton("Вася"), username_skeleton("Bacя"));
+        assert_ne!(
+            username_skeleton("streamer"),
+            username_skeleton("streamer2")
+        );
+        assert_ne!(username_skeleton("вася"), username_skeleton("петя"));
+    }
+
     #[test]
     fn test_validate_user_email() {
         let valid_email = "testuser@example.com";
-        let result = validate_user_email(valid_email);
+        let result = validate_user_email("email", valid_email);
         assert!(result.is_ok());
 
         let invalid_email = "";
-        let result = validate_user_email(invalid_email);
+        let result = validate_user_email("email", invalid_email);
         assert!(result.is_err());
         assert_eq!(result.err().unwrap().to_string(), "Invalid argument: User email cannot be empty");
 
         let invalid_email = "invalid-email";
-        let result = validate_user_email(invalid_email);
+        let result = validate_user_email("email", invalid_email);
         assert!(result.is_err());
         assert_eq!(result.err().unwrap().to_string(), "Invalid argument: Invalid email format");
     }
+
+    #[test]
+    fn test_validate_batch_size() {
+        assert!(validate_batch_size("uuids", 0).is_ok());
+        assert!(validate_batch_size("uuids", MAX_BATCH_SIZE).is_ok());
+
+        let result = validate_batch_size("uuids", MAX_BATCH_SIZE + 1);
+        assert!(result.is_err());
+        assert_eq!(result.err().unwrap().to_string(), "Invalid argument: Batch size cannot exceed 1000");
+    }
 }
@@ -1,24 +1,1037 @@
-use std::env;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::app::rate_limit::{MethodQuotas, Quota};
+use crate::app::validation::CharacterClasses;
+use crate::errors::ConfigError;
+use crate::logging::{LogFormat, LogLevels};
+use crate::telemetry::TracingExporter;
+
+const DEFAULT_SERVER_PORT: u16 = 8080;
+pub const DEFAULT_HEALTH_PORT: u16 = 8081;
+const DEFAULT_METRICS_PORT: u16 = 9090;
+const DEFAULT_PURGE_GRACE_PERIOD_DAYS: u64 = 30;
+const DEFAULT_PURGE_INTERVAL_SECS: u64 = 3600;
+const DEFAULT_DB_POOL_SIZE: u32 = 10;
+const DEFAULT_DB_CONNECTION_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_DB_IDLE_TIMEOUT_SECS: u64 = 600;
+const DEFAULT_DB_CONNECT_MAX_ATTEMPTS: u32 = 10;
+const DEFAULT_DB_CONNECT_MAX_ELAPSED_SECS: u64 = 300;
+const DEFAULT_DB_CONNECT_INITIAL_BACKOFF_MS: u64 = 500;
+const DEFAULT_DB_CONNECT_MAX_BACKOFF_SECS: u64 = 30;
+const DEFAULT_HEALTH_CHECK_INTERVAL_SECS: u64 = 5;
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_OTLP_ENDPOINT: &str = "http://localhost:4317";
+const DEFAULT_LOG_LEVEL: &str = "debug";
+const DEFAULT_TLS_RELOAD_INTERVAL_SECS: u64 = 30;
+const DEFAULT_RATE_LIMIT: &str = "50/100";
+const DEFAULT_RATE_LIMIT_METHODS: &str = "CreateUser=5/10,GetAllUsers=2/5";
+const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 64;
+const DEFAULT_USERNAME_MIN_LENGTH: usize = 3;
+const DEFAULT_USERNAME_MAX_LENGTH: usize = 32;
+const DEFAULT_USERNAME_CHARACTERS: &str = "letters,digits";
+const DEFAULT_USERNAME_SEPARATORS: &str = "_-.";
+/// Минимальная длина общего секрета HS256, как длина подписи SHA-256
+const MIN_HS256_SECRET_LEN: usize = 32;
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Все настройки сервера. Так они называются в TOML-файле,
+/// переменная окружения - то же имя в верхнем регистре
+pub const SETTINGS: &[&str] = &[
+    "database_url",
+    "server_host",
+    "server_port",
+    "health_port",
+    "metrics_enabled",
+    "metrics_port",
+    "health_check_interval_secs",
+    "grpc_reflection",
+    "auto_migrate",
+    "log_format",
+    "log_level",
+    "tracing_exporter",
+    "otlp_endpoint",
+    "auth_required",
+    "auth_hs256_secret",
+    "auth_jwks_file",
+    "auth_issuer",
+    "auth_audience",
+    "tls_cert_file",
+    "tls_key_file",
+    "tls_client_ca_file",
+    "tls_client_cert_required",
+    "tls_client_identities_file",
+    "tls_reload_interval_secs",
+    "rate_limit_enabled",
+    "rate_limit",
+    "rate_limit_methods",
+    "max_concurrent_requests",
+    "username_min_length",
+    "username_max_length",
+    "username_characters",
+    "username_separators",
+    "username_must_start_with_letter",
+    "username_reserved_words_file",
+    "shutdown_timeout_secs",
+    "db_pool_size",
+    "db_connection_timeout_secs",
+    "db_idle_timeout_secs",
+    "db_connect_max_attempts",
+    "db_connect_max_elapsed_secs",
+    "db_connect_initial_backoff_ms",
+    "db_connect_max_backoff_secs",
+    "purge_grace_period_days",
+    "purge_interval_secs",
+];
+
+fn env_name(key: &str) -> String {
+    key.to_uppercase()
+}
+
+/// Откуда взято значение настройки
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    File,
+    Env,
+    Flag,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File => write!(f, "config file"),
+            Source::Env => write!(f, "environment"),
+            Source::Flag => write!(f, "command line"),
+        }
+    }
+}
+
+/// Необработанные значения настроек. Слои применяются по порядку:
+/// файл, окружение, флаги - каждый следующий перекрывает предыдущий.
+/// Ошибки копятся и возвращаются вместе с ошибками проверки значений
+#[derive(Debug, Default)]
+pub struct ConfigSources {
+    values: HashMap<&'static str, (String, Source)>,
+    errors: Vec<String>,
+}
+
+impl ConfigSources {
+    pub fn load(
+        file: Option<&Path>, env: impl Fn(&str) -> Option<String>, flags: &[(String, String)],
+    ) -> Self {
+        let mut sources = ConfigSources::default();
+        if let Some(path) = file {
+            sources.add_file(path);
+        }
+        for &key in SETTINGS {
+            if let Some(value) = env(&env_name(key)) {
+                sources.values.insert(key, (value, Source::Env));
+            }
+        }
+        for (key, value) in flags {
+            sources.set(key, value.clone(), Source::Flag);
+        }
+        sources
+    }
+
+    fn add_file(&mut self, path: &Path) {
+        let table = match fs::read_to_string(path) {
+            Ok(content) => match content.parse::<toml::Table>() {
+                Ok(table) => table,
+                Err(e) => {
+                    self.errors
+                        .push(format!("{}: invalid TOML: {}", path.display(), e.message()));
+                    return;
+                }
+            },
+            Err(e) => {
+                self.errors.push(format!(
+                    "{}: failed to read config file: {}",
+                    path.display(),
+                    e
+                ));
+                return;
+            }
+        };
+
+        for (key, value) in table {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                _ => {
+                    self.errors.push(format!(
+                        "{}: {} must be a string, integer or boolean",
+                        path.display(),
+                        key
+                    ));
+                    continue;
+                }
+            };
+            self.set(&key, value, Source::File);
+        }
+    }
+
+    fn set(&mut self, key: &str, value: String, source: Source) {
+        match SETTINGS.iter().find(|&&known| known == key) {
+            Some(&key) => {
+                self.values.insert(key, (value, source));
+            }
+            None => self
+                .errors
+                .push(format!("Unknown setting {} ({})", key, source)),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|(value, _)| value.as_str())
+    }
+
+    pub fn source(&self, key: &str) -> Source {
+        self.values
+            .get(key)
+            .map(|&(_, source)| source)
+            .unwrap_or(Source::Default)
+    }
+}
+
+/// Разбор значений с накоплением ошибок: при ошибке возвращается значение
+/// по умолчанию, чтобы проверить остальные настройки
+struct SettingsParser<'a> {
+    sources: &'a ConfigSources,
+    errors: Vec<String>,
+}
+
+impl SettingsParser<'_> {
+    fn error(&mut self, key: &str, message: impl fmt::Display) {
+        let source = self.sources.source(key);
+        self.errors.push(format!(
+            "{} ({}, from {}): {}",
+            key,
+            env_name(key),
+            source,
+            message
+        ));
+    }
+
+    fn required(&mut self, key: &str) -> String {
+        match self.sources.get(key) {
+            Some(value) if !value.trim().is_empty() => value.to_string(),
+            _ => {
+                self.error(key, "must be set");
+                String::new()
+            }
+        }
+    }
+
+    fn optional(&self, key: &str) -> Option<String> {
+        self.sources
+            .get(key)
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+    }
+
+    fn parse_or<T>(&mut self, key: &str, default: T) -> T
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        match self.sources.get(key) {
+            Some(value) => value.trim().parse().unwrap_or_else(|e| {
+                self.error(key, format!("invalid value \"{}\": {}", value, e));
+                default
+            }),
+            None => default,
+        }
+    }
+
+    fn positive_or<T>(&mut self, key: &str, default: T) -> T
+    where
+        T: FromStr + PartialOrd + From<u8> + Copy,
+        T::Err: fmt::Display,
+    {
+        let value = self.parse_or(key, default);
+        if value < T::from(1) {
+            self.error(key, "must be positive");
+        }
+        value
+    }
+
+    fn flag_or(&mut self, key: &str, default: bool) -> bool {
+        match self
+            .sources
+            .get(key)
+            .map(|value| value.trim().to_lowercase())
+        {
+            Some(value) => match value.as_str() {
+                "true" | "1" => true,
+                "false" | "0" => false,
+                _ => {
+                    self.error(
+                        key,
+                        format!("invalid value \"{}\": must be true or false", value),
+                    );
+                    default
+                }
+            },
+            None => default,
+        }
+    }
+
+    fn addr(&mut self, host_key: &str, host: &str, port: u16) -> Option<SocketAddr> {
+        if host.is_empty() {
+            return None;
+        }
+        match format!("{}:{}", host, port).parse() {
+            Ok(addr) => Some(addr),
+            Err(e) => {
+                self.error(host_key, format!("invalid address \"{}\": {}", host, e));
+                None
+            }
+        }
+    }
+}
+
+/// Настройки пула соединений с БД
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub max_size: u32,
+    /// Сколько ждать свободное соединение, прежде чем вернуть ошибку
+    pub connection_timeout: Duration,
+    /// Через сколько закрывать простаивающее соединение, None - не закрывать
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_size: DEFAULT_DB_POOL_SIZE,
+            connection_timeout: Duration::from_secs(DEFAULT_DB_CONNECTION_TIMEOUT_SECS),
+            idle_timeout: Some(Duration::from_secs(DEFAULT_DB_IDLE_TIMEOUT_SECS)),
+        }
+    }
+}
+
+impl PoolConfig {
+    fn parse(parser: &mut SettingsParser) -> Self {
+        let max_size = parser.positive_or("db_pool_size", DEFAULT_DB_POOL_SIZE);
+        let connection_timeout_secs = parser.positive_or(
+            "db_connection_timeout_secs",
+            DEFAULT_DB_CONNECTION_TIMEOUT_SECS,
+        );
+        // 0 отключает закрытие простаивающих соединений
+        let idle_timeout_secs =
+            parser.parse_or("db_idle_timeout_secs", DEFAULT_DB_IDLE_TIMEOUT_SECS);
+
+        PoolConfig {
+            max_size,
+            connection_timeout: Duration::from_secs(connection_timeout_secs),
+            idle_timeout: (idle_timeout_secs > 0).then(|| Duration::from_secs(idle_timeout_secs)),
+        }
+    }
+}
+
+/// Повторные попытки подключения к БД при старте.
+/// Попытки прекращаются, когда исчерпано любое из ограничений
+#[derive(Debug, Clone)]
+pub struct RetryConfig {
+    pub max_attempts: u32,
+    pub max_elapsed: Duration,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: DEFAULT_DB_CONNECT_MAX_ATTEMPTS,
+            max_elapsed: Duration::from_secs(DEFAULT_DB_CONNECT_MAX_ELAPSED_SECS),
+            initial_backoff: Duration::from_millis(DEFAULT_DB_CONNECT_INITIAL_BACKOFF_MS),
+            max_backoff: Duration::from_secs(DEFAULT_DB_CONNECT_MAX_BACKOFF_SECS),
+        }
+    }
+}
+
+impl RetryConfig {
+    fn parse(parser: &mut SettingsParser) -> Self {
+        RetryConfig {
+            max_attempts: parser
+                .positive_or("db_connect_max_attempts", DEFAULT_DB_CONNECT_MAX_ATTEMPTS),
+            max_elapsed: Duration::from_secs(parser.parse_or(
+                "db_connect_max_elapsed_secs",
+                DEFAULT_DB_CONNECT_MAX_ELAPSED_SECS,
+            )),
+            initial_backoff: Duration::from_millis(parser.parse_or(
+                "db_connect_initial_backoff_ms",
+                DEFAULT_DB_CONNECT_INITIAL_BACKOFF_MS,
+            )),
+            max_backoff: Duration::from_secs(parser.parse_or(
+                "db_connect_max_backoff_secs",
+                DEFAULT_DB_CONNECT_MAX_BACKOFF_SECS,
+            )),
+        }
+    }
+}
+
+/// Проверка JWT в запросах к UserService
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    /// Отклонять вызовы непубличных методов без токена.
+    /// false допустимо только для локального запуска
+    pub required: bool,
+    /// Общий секрет для токенов HS256
+    pub hs256_secret: Option<String>,
+    /// JWKS с открытыми ключами RS256/ES256
+    pub jwks_file: Option<PathBuf>,
+    /// Ожидаемые claims iss и aud, не проверяются, если не заданы
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+}
+
+impl AuthConfig {
+    fn parse(parser: &mut SettingsParser) -> Self {
+        let required = parser.flag_or("auth_required", true);
+        let hs256_secret = parser.optional("auth_hs256_secret");
+        let jwks_file = parser.optional("auth_jwks_file").map(PathBuf::from);
+        if hs256_secret
+            .as_ref()
+            .is_some_and(|secret| secret.len() < MIN_HS256_SECRET_LEN)
+        {
+            parser.error(
+                "auth_hs256_secret",
+                format!("must be at least {} bytes", MIN_HS256_SECRET_LEN),
+            );
+        }
+        if required && hs256_secret.is_none() && jwks_file.is_none() {
+            parser.error(
+                "auth_required",
+                "requires auth_hs256_secret or auth_jwks_file",
+            );
+        }
+
+        AuthConfig {
+            required,
+            hs256_secret,
+            jwks_file,
+            issuer: parser.optional("auth_issuer"),
+            audience: parser.optional("auth_audience"),
+        }
+    }
+}
+
+/// TLS на порту UserService. Порты проверки здоровья и метрик остаются без TLS
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    /// CA клиентских сертификатов, включает mTLS
+    pub client_ca_file: Option<PathBuf>,
+    /// false - клиент без сертификата тоже подключается и аутентифицируется токеном
+    pub client_cert_required: bool,
+    /// Разрешённые клиентские сертификаты и соответствующие им сервисы
+    pub client_identities_file: Option<PathBuf>,
+    /// Как часто проверять, не изменились ли файлы
+    pub reload_interval: Duration,
+}
+
+impl TlsConfig {
+    fn parse(parser: &mut SettingsParser) -> Option<Self> {
+        let cert_file = parser.optional("tls_cert_file").map(PathBuf::from);
+        let key_file = parser.optional("tls_key_file").map(PathBuf::from);
+        let client_ca_file = parser.optional("tls_client_ca_file").map(PathBuf::from);
+        let client_cert_required = parser.flag_or("tls_client_cert_required", true);
+        let client_identities_file = parser
+            .optional("tls_client_identities_file")
+            .map(PathBuf::from);
+        let reload_interval_secs =
+            parser.positive_or("tls_reload_interval_secs", DEFAULT_TLS_RELOAD_INTERVAL_SECS);
+        if client_identities_file.is_some() && client_ca_file.is_none() {
+            parser.error("tls_client_identities_file", "requires tls_client_ca_file");
+        }
+
+        match (cert_file, key_file) {
+            (Some(cert_file), Some(key_file)) => Some(TlsConfig {
+                cert_file,
+                key_file,
+                client_ca_file,
+                client_cert_required,
+                client_identities_file,
+                reload_interval: Duration::from_secs(reload_interval_secs),
+            }),
+            (None, None) => {
+                if client_ca_file.is_some() {
+                    parser.error(
+                        "tls_client_ca_file",
+                        "requires tls_cert_file and tls_key_file",
+                    );
+                }
+                None
+            }
+            (Some(_), None) => {
+                parser.error("tls_key_file", "must be set together with tls_cert_file");
+                None
+            }
+            (None, Some(_)) => {
+                parser.error("tls_cert_file", "must be set together with tls_key_file");
+                None
+            }
+        }
+    }
+}
+
+/// Ограничения нагрузки на UserService
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Ограничивать частоту запросов каждого клиента
+    pub enabled: bool,
+    /// Квота клиента на методы без отдельной квоты
+    pub default_quota: Quota,
+    /// Отдельные квоты для тяжёлых методов
+    pub method_quotas: MethodQuotas,
+    /// Сколько запросов выполняется одновременно, остальные сразу отклоняются. 0 - без предела
+    pub max_concurrent_requests: usize,
+}
+
+impl RateLimitConfig {
+    fn parse(parser: &mut SettingsParser) -> Self {
+        RateLimitConfig {
+            enabled: parser.flag_or("rate_limit_enabled", true),
+            default_quota: parser.parse_or("rate_limit", DEFAULT_RATE_LIMIT.parse().unwrap()),
+            method_quotas: parser.parse_or(
+                "rate_limit_methods",
+                DEFAULT_RATE_LIMIT_METHODS.parse().unwrap(),
+            ),
+            max_concurrent_requests: parser
+                .parse_or("max_concurrent_requests", DEFAULT_MAX_CONCURRENT_REQUESTS),
+        }
+    }
+}
+
+/// Правила для имён пользователей при регистрации и переименовании
+#[derive(Debug, Clone)]
+pub struct UsernamePolicyConfig {
+    /// Длина в символах, не в байтах
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Допустимые классы символов: letters, ascii_letters, digits
+    pub characters: CharacterClasses,
+    /// Разделители между частями имени, два подряд запрещены
+    pub separators: String,
+    pub must_start_with_letter: bool,
+    /// Зарезервированные имена по одному в строке. None - встроенный список
+    pub reserved_words_file: Option<PathBuf>,
+}
+
+impl Default for UsernamePolicyConfig {
+    fn default() -> Self {
+        UsernamePolicyConfig {
+            min_length: DEFAULT_USERNAME_MIN_LENGTH,
+            max_length: DEFAULT_USERNAME_MAX_LENGTH,
+            characters: DEFAULT_USERNAME_CHARACTERS.parse().unwrap(),
+            separators: DEFAULT_USERNAME_SEPARATORS.to_string(),
+            must_start_with_letter: true,
+            reserved_words_file: None,
+        }
+    }
+}
+
+impl UsernamePolicyConfig {
+    fn parse(parser: &mut SettingsParser) -> Self {
+        let min_length = parser.positive_or("username_min_length", DEFAULT_USERNAME_MIN_LENGTH);
+        let max_length = parser.parse_or("username_max_length", DEFAULT_USERNAME_MAX_LENGTH);
+        if max_length < min_length {
+            parser.error(
+                "username_max_length",
+                "must not be less than username_min_length",
+            );
+        }
+        let characters: CharacterClasses = parser.parse_or(
+            "username_characters",
+            DEFAULT_USERNAME_CHARACTERS.parse().unwrap(),
+        );
+        // Пустая строка - имя без разделителей
+        let separators = parser
+            .sources
+            .get("username_separators")
+            .unwrap_or(DEFAULT_USERNAME_SEPARATORS)
+            .to_string();
+        if separators
+            .chars()
+            .any(|c| c.is_alphanumeric() || c.is_whitespace() || c.is_control())
+        {
+            parser.error(
+                "username_separators",
+                "must contain only punctuation characters",
+            );
+        }
+        let must_start_with_letter = parser.flag_or("username_must_start_with_letter", true);
+        if must_start_with_letter && !characters.has_letters() {
+            parser.error(
+                "username_must_start_with_letter",
+                "requires letters or ascii_letters in username_characters",
+            );
+        }
+
+        UsernamePolicyConfig {
+            min_length,
+            max_length,
+            characters,
+            separators,
+            must_start_with_letter,
+            reserved_words_file: parser
+                .optional("username_reserved_words_file")
+                .map(PathBuf::from),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Config {
     pub database_url: String,
-    pub server_addr: String,
+    pub db_pool: PoolConfig,
+    pub db_connect_retry: RetryConfig,
+    pub server_addr: SocketAddr,
+    /// Адрес проверки здоровья, доступен с самого старта, пока сервис ещё подключается к БД
+    pub health_addr: SocketAddr,
+    pub metrics_enabled: bool,
+    /// Адрес HTTP-эндпоинта /metrics для Prometheus
+    pub metrics_addr: SocketAddr,
+    /// Как часто проверять доступность БД после запуска
+    pub health_check_interval: Duration,
+    /// Сервис рефлексии gRPC, в продакшене можно отключить
+    pub reflection_enabled: bool,
+    /// Применять миграции при старте. При нескольких репликах лучше отключить
+    /// и запускать `migrate up` отдельно
+    pub auto_migrate: bool,
+    /// text для чтения человеком или json для сборщика логов
+    pub log_format: LogFormat,
+    /// Уровни логирования, например "info,user_service_server::repo=debug".
+    /// Во время работы меняются через AdminService
+    pub log_levels: LogLevels,
+    /// Экспорт спанов: none, stdout для локального запуска или otlp
+    pub tracing_exporter: TracingExporter,
+    /// Адрес OTLP/gRPC приёмника, используется при tracing_exporter = otlp
+    pub otlp_endpoint: String,
+    pub auth: AuthConfig,
+    /// None - UserService без TLS (h2c)
+    pub tls: Option<TlsConfig>,
+    pub rate_limit: RateLimitConfig,
+    pub username_policy: UsernamePolicyConfig,
+    /// Сколько ждать завершения текущих запросов после сигнала остановки
+    pub shutdown_timeout: Duration,
+    /// Сколько мягко удалённый пользователь может быть восстановлен
+    pub purge_grace_period: Duration,
+    pub purge_interval: Duration,
 }
 
 impl Config {
-    pub fn from_env() -> Self {
-        dotenv::dotenv().ok();
+    /// Проверяет все настройки сразу и возвращает полный список ошибок
+    pub fn from_sources(sources: &ConfigSources) -> Result<Self, ConfigError> {
+        let mut parser = SettingsParser {
+            sources,
+            errors: sources.errors.clone(),
+        };
 
-        //let database_url = env::var("TEST_DATABASE_URL").expect("DATABASE_URL must be set");
-        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        let server_port = env::var("SERVER_PORT").unwrap_or_else(|_| "8080".to_string());
-        let server_host = env::var("SERVER_HOST").expect("SERVER_HOST must be set");
-        let server_addr = format!("{}:{}", server_host, server_port);
+        let database_url = parser.required("database_url");
+        let server_host = parser.required("server_host");
+        let server_port = parser.parse_or("server_port", DEFAULT_SERVER_PORT);
+        let health_port = parser.parse_or("health_port", DEFAULT_HEALTH_PORT);
+        if health_port == server_port {
+            parser.error("health_port", "must differ from server_port");
+        }
+        let metrics_enabled = parser.flag_or("metrics_enabled", true);
+        let metrics_port = parser.parse_or("metrics_port", DEFAULT_METRICS_PORT);
+        if metrics_enabled && (metrics_port == server_port || metrics_port == health_port) {
+            parser.error(
+                "metrics_port",
+                "must differ from server_port and health_port",
+            );
+        }
+        let server_addr = parser.addr("server_host", &server_host, server_port);
+        let health_addr = server_addr.map(|addr| SocketAddr::new(addr.ip(), health_port));
+        let metrics_addr = SocketAddr::new(
+            server_addr.map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |addr| addr.ip()),
+            metrics_port,
+        );
 
-        Config {
-            database_url,
-            server_addr,
+        let db_pool = PoolConfig::parse(&mut parser);
+        let db_connect_retry = RetryConfig::parse(&mut parser);
+        let health_check_interval_secs = parser.positive_or(
+            "health_check_interval_secs",
+            DEFAULT_HEALTH_CHECK_INTERVAL_SECS,
+        );
+        let reflection_enabled = parser.flag_or("grpc_reflection", false);
+        let auto_migrate = parser.flag_or("auto_migrate", true);
+        let log_format = parser.parse_or("log_format", LogFormat::Text);
+        let log_levels = parser.parse_or("log_level", DEFAULT_LOG_LEVEL.parse().unwrap());
+        let tracing_exporter = parser.parse_or("tracing_exporter", TracingExporter::None);
+        let otlp_endpoint = parser
+            .sources
+            .get("otlp_endpoint")
+            .unwrap_or(DEFAULT_OTLP_ENDPOINT)
+            .to_string();
+        if tracing_exporter == TracingExporter::Otlp
+            && !otlp_endpoint.starts_with("http://")
+            && !otlp_endpoint.starts_with("https://")
+        {
+            parser.error("otlp_endpoint", "must start with http:// or https://");
         }
+        let auth = AuthConfig::parse(&mut parser);
+        let tls = TlsConfig::parse(&mut parser);
+        let rate_limit = RateLimitConfig::parse(&mut parser);
+        let username_policy = UsernamePolicyConfig::parse(&mut parser);
+        let shutdown_timeout_secs =
+            parser.parse_or("shutdown_timeout_secs", DEFAULT_SHUTDOWN_TIMEOUT_SECS);
+        let purge_grace_period_days =
+            parser.parse_or("purge_grace_period_days", DEFAULT_PURGE_GRACE_PERIOD_DAYS);
+        // Срок вычитается из текущего времени в chrono, поэтому должен укладываться в его диапазон
+        let purge_grace_period = purge_grace_period_days
+            .checked_mul(SECS_PER_DAY)
+            .map(Duration::from_secs)
+            .filter(|period| chrono::Duration::from_std(*period).is_ok())
+            .unwrap_or_else(|| {
+                parser.error("purge_grace_period_days", "is too large");
+                Duration::ZERO
+            });
+        let purge_interval_secs =
+            parser.positive_or("purge_interval_secs", DEFAULT_PURGE_INTERVAL_SECS);
+
+        match (server_addr, health_addr) {
+            (Some(server_addr), Some(health_addr)) if parser.errors.is_empty() => Ok(Config {
+                database_url,
+                db_pool,
+                db_connect_retry,
+                server_addr,
+                health_addr,
+                metrics_enabled,
+                metrics_addr,
+                health_check_interval: Duration::from_secs(health_check_interval_secs),
+                reflection_enabled,
+                auto_migrate,
+                log_format,
+                log_levels,
+                tracing_exporter,
+                otlp_endpoint,
+                auth,
+                tls,
+                rate_limit,
+                username_policy,
+                shutdown_timeout: Duration::from_secs(shutdown_timeout_secs),
+                purge_grace_period,
+                purge_interval: Duration::from_secs(purge_interval_secs),
+            }),
+            _ => Err(ConfigError(parser.errors)),
+        }
+    }
+
+    /// Итоговые настройки в формате TOML-файла с источником каждого значения.
+    /// Пароль в database_url скрыт
+    pub fn render(&self, sources: &ConfigSources) -> String {
+        let values = [
+            (
+                "database_url",
+                format!("\"{}\"", mask_password(&self.database_url)),
+            ),
+            ("server_host", format!("\"{}\"", self.server_addr.ip())),
+            ("server_port", self.server_addr.port().to_string()),
+            ("health_port", self.health_addr.port().to_string()),
+            ("metrics_enabled", self.metrics_enabled.to_string()),
+            ("metrics_port", self.metrics_addr.port().to_string()),
+            (
+                "health_check_interval_secs",
+                self.health_check_interval.as_secs().to_string(),
+            ),
+            ("grpc_reflection", self.reflection_enabled.to_string()),
+            ("auto_migrate", self.auto_migrate.to_string()),
+            ("log_format", format!("\"{}\"", self.log_format)),
+            ("log_level", format!("\"{}\"", self.log_levels)),
+            ("tracing_exporter", format!("\"{}\"", self.tracing_exporter)),
+            ("otlp_endpoint", format!("\"{}\"", self.otlp_endpoint)),
+            ("auth_required", self.auth.required.to_string()),
+            (
+                "auth_hs256_secret",
+                quoted(self.auth.hs256_secret.as_ref().map(|_| "***")),
+            ),
+            (
+                "auth_jwks_file",
+                quoted(self.auth.jwks_file.as_ref().map(|path| path.display())),
+            ),
+            ("auth_issuer", quoted(self.auth.issuer.as_ref())),
+            ("auth_audience", quoted(self.auth.audience.as_ref())),
+            (
+                "tls_cert_file",
+                quoted(self.tls.as_ref().map(|tls| tls.cert_file.display())),
+            ),
+            (
+                "tls_key_file",
+                quoted(self.tls.as_ref().map(|tls| tls.key_file.display())),
+            ),
+            (
+                "tls_client_ca_file",
+                quoted(
+                    self.tls
+                        .as_ref()
+                        .and_then(|tls| tls.client_ca_file.as_ref())
+                        .map(|path| path.display()),
+                ),
+            ),
+            (
+                "tls_client_cert_required",
+                self.tls
+                    .as_ref()
+                    .is_none_or(|tls| tls.client_cert_required)
+                    .to_string(),
+            ),
+            (
+                "tls_client_identities_file",
+                quoted(
+                    self.tls
+                        .as_ref()
+                        .and_then(|tls| tls.client_identities_file.as_ref())
+                        .map(|path| path.display()),
+                ),
+            ),
+            (
+                "tls_reload_interval_secs",
+                self.tls
+                    .as_ref()
+                    .map_or(DEFAULT_TLS_RELOAD_INTERVAL_SECS, |tls| {
+                        tls.reload_interval.as_secs()
+                    })
+                    .to_string(),
+            ),
+            ("rate_limit_enabled", self.rate_limit.enabled.to_string()),
+            (
+                "rate_limit",
+                format!("\"{}\"", self.rate_limit.default_quota),
+            ),
+            (
+                "rate_limit_methods",
+                format!("\"{}\"", self.rate_limit.method_quotas),
+            ),
+            (
+                "max_concurrent_requests",
+                self.rate_limit.max_concurrent_requests.to_string(),
+            ),
+            (
+                "username_min_length",
+                self.username_policy.min_length.to_string(),
+            ),
+            (
+                "username_max_length",
+                self.username_policy.max_length.to_string(),
+            ),
+            (
+                "username_characters",
+                format!("\"{}\"", self.username_policy.characters),
+            ),
+            (
+                "username_separators",
+                format!("\"{}\"", self.username_policy.separators),
+            ),
+            (
+                "username_must_start_with_letter",
+                self.username_policy.must_start_with_letter.to_string(),
+            ),
+            (
+                "username_reserved_words_file",
+                quoted(
+                    self.username_policy
+                        .reserved_words_file
+                        .as_ref()
+                        .map(|path| path.display()),
+                ),
+            ),
+            (
+                "shutdown_timeout_secs",
+                self.shutdown_timeout.as_secs().to_string(),
+            ),
+            ("db_pool_size", self.db_pool.max_size.to_string()),
+            (
+                "db_connection_timeout_secs",
+                self.db_pool.connection_timeout.as_secs().to_string(),
+            ),
+            (
+                "db_idle_timeout_secs",
+                self.db_pool
+                    .idle_timeout
+                    .map_or(0, |timeout| timeout.as_secs())
+                    .to_string(),
+            ),
+            (
+                "db_connect_max_attempts",
+                self.db_connect_retry.max_attempts.to_string(),
+            ),
+            (
+                "db_connect_max_elapsed_secs",
+                self.db_connect_retry.max_elapsed.as_secs().to_string(),
+            ),
+            (
+                "db_connect_initial_backoff_ms",
+                self.db_connect_retry
+                    .initial_backoff
+                    .as_millis()
+                    .to_string(),
+            ),
+            (
+                "db_connect_max_backoff_secs",
+                self.db_connect_retry.max_backoff.as_secs().to_string(),
+            ),
+            (
+                "purge_grace_period_days",
+                (self.purge_grace_period.as_secs() / SECS_PER_DAY).to_string(),
+            ),
+            (
+                "purge_interval_secs",
+                self.purge_interval.as_secs().to_string(),
+            ),
+        ];
+
+        values
+            .iter()
+            .map(|(key, value)| format!("{} = {} # {}\n", key, value, sources.source(key)))
+            .collect()
+    }
+}
+
+fn quoted(value: Option<impl fmt::Display>) -> String {
+    value.map_or_else(|| "\"\"".to_string(), |value| format!("\"{}\"", value))
+}
+
+fn mask_password(url: &str) -> String {
+    let Some(scheme_end) = url.find("://").map(|i| i + 3) else {
+        return url.to_string();
+    };
+    let Some(at) = url[scheme_end..].rfind('@').map(|i| i + scheme_end) else {
+        return url.to_string();
+    };
+    match url[scheme_end..at].find(':').map(|i| i + scheme_end) {
+        Some(colon) => format!("{}***{}", &url[..colon + 1], &url[at..]),
+        None => url.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::Write;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_layers_override_in_order() {
+        let mut file = tempfile();
+        writeln!(
+            file.1,
+            "server_host = \"127.0.0.1\"\nserver_port = 9000\ndb_pool_size = 3\ngrpc_reflection = true\nauth_required = false"
+        )
+        .unwrap();
+
+        let sources = ConfigSources::load(
+            Some(&file.0),
+            env(&[("DATABASE_URL", "postgres://db"), ("SERVER_PORT", "9001")]),
+            &[("server_port".to_string(), "9002".to_string())],
+        );
+        let config = Config::from_sources(&sources).unwrap();
+
+        assert_eq!(config.server_addr, "127.0.0.1:9002".parse().unwrap());
+        assert_eq!(config.health_addr, "127.0.0.1:8081".parse().unwrap());
+        assert_eq!(config.db_pool.max_size, 3);
+        assert!(config.reflection_enabled);
+        assert_eq!(sources.source("server_port"), Source::Flag);
+        assert_eq!(sources.source("database_url"), Source::Env);
+        assert_eq!(sources.source("db_pool_size"), Source::File);
+        assert_eq!(sources.source("purge_interval_secs"), Source::Default);
+        fs::remove_file(&file.0).unwrap();
+    }
+
+    #[test]
+    fn test_all_errors_reported() {
+        let sources = ConfigSources::load(
+            None,
+            env(&[
+                ("SERVER_PORT", "http"),
+                ("DB_POOL_SIZE", "0"),
+                ("GRPC_REFLECTION", "maybe"),
+                ("LOG_LEVEL", "info,user_service_server=chatty"),
+                ("TRACING_EXPORTER", "jaeger"),
+                ("AUTH_HS256_SECRET", "secret"),
+                ("TLS_CERT_FILE", "/etc/tls/server.pem"),
+                ("RATE_LIMIT_METHODS", "CreateUser=5"),
+                ("USERNAME_MIN_LENGTH", "8"),
+                ("USERNAME_MAX_LENGTH", "4"),
+                ("USERNAME_SEPARATORS", "_ "),
+                ("PURGE_GRACE_PERIOD_DAYS", "300000000000000"),
+            ]),
+            &[("no_such_setting".to_string(), "1".to_string())],
+        );
+        let errors = Config::from_sources(&sources).unwrap_err().0;
+
+        assert_eq!(
+            errors,
+            vec![
+                "Unknown setting no_such_setting (command line)",
+                "database_url (DATABASE_URL, from default): must be set",
+                "server_host (SERVER_HOST, from default): must be set",
+                "server_port (SERVER_PORT, from environment): invalid value \"http\": invalid digit found in string",
+                "db_pool_size (DB_POOL_SIZE, from environment): must be positive",
+                "grpc_reflection (GRPC_REFLECTION, from environment): invalid value \"maybe\": must be true or false",
+                "log_level (LOG_LEVEL, from environment): invalid value \"info,user_service_server=chatty\": invalid log level \"chatty\"",
+                "tracing_exporter (TRACING_EXPORTER, from environment): invalid value \"jaeger\": must be one of none, stdout, otlp",
+                "auth_hs256_secret (AUTH_HS256_SECRET, from environment): must be at least 32 bytes",
+                "tls_key_file (TLS_KEY_FILE, from default): must be set together with tls_cert_file",
+                "rate_limit_methods (RATE_LIMIT_METHODS, from environment): invalid value \"CreateUser=5\": invalid quota \"5\": expected <requests per second>/<burst>",
+                "username_max_length (USERNAME_MAX_LENGTH, from environment): must not be less than username_min_length",
+                "username_separators (USERNAME_SEPARATORS, from environment): must contain only punctuation characters",
+                "purge_grace_period_days (PURGE_GRACE_PERIOD_DAYS, from environment): is too large",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_masks_password() {
+        let sources = ConfigSources::load(
+            None,
+            env(&[
+                ("DATABASE_URL", "postgres://user:secret@db:5432/users"),
+                ("SERVER_HOST", "0.0.0.0"),
+                ("AUTH_HS256_SECRET", "0123456789abcdef0123456789abcdef"),
+            ]),
+            &[],
+        );
+        let rendered = Config::from_sources(&sources).unwrap().render(&sources);
+
+        assert!(rendered
+            .starts_with("database_url = \"postgres://user:***@db:5432/users\" # environment\n"));
+        assert!(rendered.contains("server_port = 8080 # default\n"));
+        assert!(rendered.contains("auth_hs256_secret = \"***\" # environment\n"));
+        assert!(rendered.contains("auth_issuer = \"\" # default\n"));
+        assert!(rendered.contains("grpc_reflection = false # default\n"));
+        assert!(rendered
+            .contains("rate_limit_methods = \"CreateUser=5/10,GetAllUsers=2/5\" # default\n"));
+        assert!(rendered.contains("username_characters = \"letters,digits\" # default\n"));
+        assert_eq!(mask_password("postgres://db/users"), "postgres://db/users");
+    }
+
+    fn tempfile() -> (std::path::PathBuf, fs::File) {
+        let path =
+            std::env::temp_dir().join(format!("user-service-config-{}.toml", uuid::Uuid::now_v7()));
+        let file = fs::File::create(&path).unwrap();
+        (path, file)
     }
 }
@@ -1,19 +1,103 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use diesel::r2d2::PoolError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use log::{error, warn};
 use thiserror::Error;
-use tonic::Status;
+use tokio::task::JoinError;
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Status};
+use tonic_types::{ErrorDetails, StatusExt};
+use uuid::Uuid;
+
+use crate::telemetry;
+
+/// Домен ErrorInfo: вместе с reason однозначно определяет ошибку
+pub const ERROR_DOMAIN: &str = "user-service";
+/// Через сколько повторить запрос, если БД временно недоступна
+const UNAVAILABLE_RETRY_AFTER: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Error)]
 pub enum DbError {
+    /// Пул не создан или не выдал соединение за connection_timeout
     #[error("Failed to get a connection from the pool: {0}")]
-    ConnectionError(String),
+    PoolError(#[from] PoolError),
+
+    /// Блокирующая задача подключения завершилась паникой
+    #[error("Failed to connect to the database: {0}")]
+    ConnectionError(#[source] JoinError),
 
     #[error("Failed to run query: {0}")]
-    QueryError(String),
+    QueryError(#[from] DieselError),
+
+    #[error(
+        "Failed to connect to the database after {attempts} attempts in {elapsed:?}: {last_error}"
+    )]
+    RetriesExhausted {
+        attempts: u32,
+        elapsed: Duration,
+        #[source]
+        last_error: Box<DbError>,
+    },
+}
+
+impl DbError {
+    /// Ошибка может пройти при повторе: нет свободного соединения, соединение
+    /// оборвалось или транзакция не прошла из-за конкурентной записи
+    pub fn is_transient(&self) -> bool {
+        match self {
+            DbError::PoolError(_) | DbError::ConnectionError(_) => true,
+            DbError::QueryError(DieselError::DatabaseError(kind, _)) => matches!(
+                kind,
+                DatabaseErrorKind::ClosedConnection | DatabaseErrorKind::SerializationFailure
+            ),
+            DbError::QueryError(_) | DbError::RetriesExhausted { .. } => false,
+        }
+    }
 }
 
+/// Все ошибки конфигурации, найденные при загрузке
+#[derive(Debug, Error)]
+#[error("Invalid configuration:\n  {}", .0.join("\n  "))]
+pub struct ConfigError(pub Vec<String>);
+
 #[derive(Debug, Error)]
 pub enum MigrationError {
     #[error("Failed to run migrations: {0}")]
     MigrationFailed(String),
+
+    /// Автоматические миграции отключены, схему должен обновить `migrate up`
+    #[error("{0} pending migrations, auto-migration is disabled: run `migrate up`")]
+    Pending(usize),
+}
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("Failed to load JWKS from {path}: {error}")]
+    Jwks { path: String, error: String },
+
+    #[error("Invalid token: {0}")]
+    InvalidToken(String),
+
+    /// Алгоритм токена не принимается или для него не настроен ключ
+    #[error("Unsupported token: {0}")]
+    UnsupportedToken(String),
+}
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("Failed to load {path}: {error}")]
+    File { path: String, error: String },
+
+    #[error("Invalid TLS configuration: {0}")]
+    Config(String),
+}
+
+#[derive(Debug, Error)]
+pub enum UsernamePolicyError {
+    #[error("Failed to load reserved usernames from {path}: {error}")]
+    ReservedWords { path: String, error: String },
 }
 
 #[derive(Debug, Error)]
@@ -24,64 +108,276 @@ pub enum RepoError {
     #[error("User not found")]
     UserNotFound,
 
-    #[error("Unknown error: {0}")]
-    Unknown(String),
-}
+    /// Нарушение уникальности, содержит имя конфликтующего поля
+    #[error("User with this {0} already exists")]
+    AlreadyExists(String),
 
-#[derive(Debug, Error)]
-pub enum AppError {
-    #[error("Application Database Error: {0}")]
-    DbError(#[from] DbError),
+    /// Скелет имени совпал со скелетом другого пользователя
+    #[error("Username is confusable with an existing one")]
+    ConfusableUsername,
 
-    #[error("Validation Error: {0}")]
-    ValidationError(String),
+    /// Версия пользователя не совпала с ожидаемой, запись не изменена
+    #[error("User version conflict")]
+    VersionConflict,
 
-    #[error("Other Application Error: {0}")]
-    Other(String),
+    /// Блокирующая задача с запросом завершилась паникой
+    #[error("Database task failed: {0}")]
+    TaskFailed(#[from] JoinError),
 }
 
+/// Ошибки методов UserService. Клиент получает код gRPC, сообщение и детали
+/// google.rpc.Status: ErrorInfo со стабильным reason, BadRequest для полей запроса,
+/// RetryInfo для временных ошибок и RequestInfo с кодом корреляции
 #[derive(Debug, Error)]
 pub enum GrpcError {
-    #[error("Invalid argument: {0}")]
-    InvalidArgument(String),
+    /// Поле запроса не прошло проверку. field - путь поля, например usernames[2]
+    #[error("Invalid argument: {description}")]
+    InvalidArgument {
+        reason: &'static str,
+        field: String,
+        description: String,
+    },
 
     #[error("Not found: {0}")]
     NotFound(String),
 
-    #[error("Already exists: {0}")]
-    AlreadyExists(String),
+    /// Нарушение уникальности по полю field
+    #[error("Already exists: User with this {field} already exists")]
+    AlreadyExists { field: String },
+
+    /// Имя выглядит так же, как имя другого пользователя
+    #[error("Already exists: Username is too similar to an existing one")]
+    ConfusableUsername,
+
+    /// Запись изменена параллельно, клиент должен перечитать её
+    #[error("Aborted: {0}")]
+    Aborted(String),
+
+    #[error("Unauthenticated: {0}")]
+    Unauthenticated(String),
+
+    /// Вызывающему не хватает scope
+    #[error("Permission denied: {message}")]
+    PermissionDenied {
+        scope: &'static str,
+        message: String,
+    },
+
+    /// Превышена квота или сервер перегружен, клиенту стоит повторить через retry_after
+    #[error("Resource exhausted: {message}")]
+    ResourceExhausted {
+        reason: &'static str,
+        message: String,
+        retry_after: Duration,
+    },
 
+    /// Временная ошибка БД, запрос можно повторить
+    #[error("Unavailable: {0}")]
+    Unavailable(#[source] RepoError),
+
+    /// Причина пишется в лог, клиент получает только код корреляции
     #[error("Internal server error: {0}")]
-    Internal(String),
+    Internal(#[source] RepoError),
+}
+
+impl GrpcError {
+    pub fn invalid_argument(
+        field: impl Into<String>, reason: &'static str, description: impl Into<String>,
+    ) -> Self {
+        GrpcError::InvalidArgument {
+            reason,
+            field: field.into(),
+            description: description.into(),
+        }
+    }
+
+    /// Стабильный код ошибки для ErrorInfo, клиенты сравнивают его вместо текста сообщения
+    pub fn reason(&self) -> &'static str {
+        match self {
+            GrpcError::InvalidArgument { reason, .. } => reason,
+            GrpcError::NotFound(_) => "USER_NOT_FOUND",
+            GrpcError::AlreadyExists { .. } => "USER_ALREADY_EXISTS",
+            GrpcError::ConfusableUsername => "USERNAME_CONFUSABLE",
+            GrpcError::Aborted(_) => "VERSION_CONFLICT",
+            GrpcError::Unauthenticated(_) => "UNAUTHENTICATED",
+            GrpcError::PermissionDenied { .. } => "SCOPE_REQUIRED",
+            GrpcError::ResourceExhausted { reason, .. } => reason,
+            GrpcError::Unavailable(_) => "DATABASE_UNAVAILABLE",
+            GrpcError::Internal(_) => "INTERNAL",
+        }
+    }
+}
 
-    #[error("Unknown error: {0}")]
-    Unknown(String),
+/// Код корреляции для RequestInfo - x-request-id запроса, он же есть в строках лога.
+/// Вне запроса (например, в тестах) - новый UUID
+fn correlation_id() -> String {
+    telemetry::current_request_id().unwrap_or_else(|| Uuid::now_v7().to_string())
 }
 
 impl From<GrpcError> for Status {
     fn from(err: GrpcError) -> Self {
-        match err {
-            GrpcError::InvalidArgument(msg) => Status::invalid_argument(msg),
-            GrpcError::NotFound(msg) => Status::not_found(msg),
-            GrpcError::AlreadyExists(msg) => Status::already_exists(msg),
-            GrpcError::Internal(msg) => Status::internal(msg),
-            GrpcError::Unknown(msg) => Status::unknown(msg),
+        let correlation_id = correlation_id();
+        let mut details = ErrorDetails::new();
+        let mut info = HashMap::new();
+        let mut retry_after = None;
+        let (code, message) = match &err {
+            GrpcError::InvalidArgument {
+                field, description, ..
+            } => {
+                details.add_bad_request_violation(field.clone(), description.clone());
+                info.insert("field".to_string(), field.clone());
+                (Code::InvalidArgument, description.clone())
+            }
+            GrpcError::NotFound(message) => (Code::NotFound, message.clone()),
+            GrpcError::AlreadyExists { field } => {
+                info.insert("field".to_string(), field.clone());
+                (
+                    Code::AlreadyExists,
+                    format!("User with this {} already exists", field),
+                )
+            }
+            GrpcError::ConfusableUsername => {
+                info.insert("field".to_string(), "username".to_string());
+                (
+                    Code::AlreadyExists,
+                    "Username is too similar to an existing one".to_string(),
+                )
+            }
+            GrpcError::Aborted(message) => (Code::Aborted, message.clone()),
+            GrpcError::Unauthenticated(message) => (Code::Unauthenticated, message.clone()),
+            GrpcError::PermissionDenied { scope, message } => {
+                info.insert("scope".to_string(), scope.to_string());
+                (Code::PermissionDenied, message.clone())
+            }
+            GrpcError::ResourceExhausted {
+                message,
+                retry_after: delay,
+                ..
+            } => {
+                retry_after = Some(*delay);
+                (Code::ResourceExhausted, message.clone())
+            }
+            GrpcError::Unavailable(e) => {
+                warn!(
+                    "Request {} failed with a transient error: {}",
+                    correlation_id, e
+                );
+                retry_after = Some(UNAVAILABLE_RETRY_AFTER);
+                (
+                    Code::Unavailable,
+                    "Database is temporarily unavailable".to_string(),
+                )
+            }
+            GrpcError::Internal(e) => {
+                error!("Request {} failed: {}", correlation_id, e);
+                (Code::Internal, "Internal server error".to_string())
+            }
+        };
+
+        details.set_error_info(err.reason(), ERROR_DOMAIN, info);
+        details.set_request_info(correlation_id, "");
+        let mut metadata = MetadataMap::new();
+        if let Some(delay) = retry_after {
+            details.set_retry_info(Some(delay));
+            // Целые секунды, как в HTTP Retry-After, с округлением вверх
+            let secs = delay.as_secs() + u64::from(delay.subsec_nanos() > 0);
+            metadata.insert("retry-after", secs.max(1).into());
         }
+        Status::with_error_details_and_metadata(code, message, details, metadata)
     }
 }
-///Переделать
+
 impl From<RepoError> for GrpcError {
     fn from(err: RepoError) -> Self {
         match err {
-            RepoError::DbError(..) => GrpcError::Internal("Что-то пошло не так".parse().unwrap()),
             RepoError::UserNotFound => GrpcError::NotFound("User not found".to_string()),
-            RepoError::Unknown(e) => GrpcError::Unknown(format!("Unknown error: {}", e)),
+            RepoError::AlreadyExists(field) => GrpcError::AlreadyExists { field },
+            RepoError::ConfusableUsername => GrpcError::ConfusableUsername,
+            RepoError::VersionConflict => {
+                GrpcError::Aborted("User was modified concurrently".to_string())
+            }
+            RepoError::DbError(ref e) if e.is_transient() => GrpcError::Unavailable(err),
+            err @ (RepoError::DbError(_) | RepoError::TaskFailed(_)) => GrpcError::Internal(err),
         }
     }
 }
-///Переделать
-impl From<MigrationError> for DbError {
-    fn from(err: MigrationError) -> Self {
-        DbError::ConnectionError(err.to_string())
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_invalid_argument_details() {
+        let status = Status::from(GrpcError::invalid_argument(
+            "uuids[2]",
+            "INVALID_UUID",
+            "Invalid UUID",
+        ));
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), "Invalid UUID");
+
+        let details = status.get_error_details();
+        let violations = details.bad_request().unwrap().field_violations.clone();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].field, "uuids[2]");
+        assert_eq!(violations[0].description, "Invalid UUID");
+        let info = details.error_info().unwrap();
+        assert_eq!(info.reason, "INVALID_UUID");
+        assert_eq!(info.domain, ERROR_DOMAIN);
+        assert!(!details.request_info().unwrap().request_id.is_empty());
+        assert!(details.retry_info().is_none());
+    }
+
+    #[test]
+    fn test_repo_errors_keep_cause() {
+        let status = Status::from(GrpcError::from(RepoError::AlreadyExists(
+            "email".to_string(),
+        )));
+        assert_eq!(status.code(), Code::AlreadyExists);
+        assert_eq!(status.message(), "User with this email already exists");
+        let info = status.get_details_error_info().unwrap();
+        assert_eq!(info.reason, "USER_ALREADY_EXISTS");
+        assert_eq!(info.metadata.get("field").unwrap(), "email");
+
+        // Соединение оборвалось - временная ошибка, клиенту предлагается повтор
+        let closed = DieselError::DatabaseError(
+            DatabaseErrorKind::ClosedConnection,
+            Box::new("server closed the connection".to_string()),
+        );
+        let err = GrpcError::from(RepoError::DbError(DbError::QueryError(closed)));
+        assert!(matches!(err, GrpcError::Unavailable(RepoError::DbError(_))));
+        // Исходная ошибка доступна по цепочке source для логов и трассировки
+        let source = std::error::Error::source(&err).unwrap();
+        assert_eq!(
+            source.to_string(),
+            "Database error: Failed to run query: server closed the connection"
+        );
+        let status = Status::from(err);
+        assert_eq!(status.code(), Code::Unavailable);
+        assert_eq!(
+            status.get_details_retry_info().unwrap().retry_delay,
+            Some(UNAVAILABLE_RETRY_AFTER)
+        );
+        assert_eq!(status.metadata().get("retry-after").unwrap(), "1");
+        assert_eq!(
+            status.get_details_error_info().unwrap().reason,
+            "DATABASE_UNAVAILABLE"
+        );
+
+        // Текст ошибки БД не уходит клиенту
+        let err = GrpcError::from(RepoError::DbError(DbError::QueryError(
+            DieselError::NotFound,
+        )));
+        assert!(matches!(
+            err,
+            GrpcError::Internal(RepoError::DbError(DbError::QueryError(
+                DieselError::NotFound
+            )))
+        ));
+        let status = Status::from(err);
+        assert_eq!(status.code(), Code::Internal);
+        assert_eq!(status.message(), "Internal server error");
+        assert!(status.get_details_retry_info().is_none());
     }
 }
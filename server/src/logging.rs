@@ -0,0 +1,299 @@
+//! Логирование: текстовый или JSON формат, уровни по модулям с изменением
+//! во время работы через AdminService и маскирование персональных данных
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock, RwLock};
+
+use chrono::Local;
+use fern::colors::{Color, ColoredLevelConfig};
+use log::{LevelFilter, Metadata, Record};
+use regex::Regex;
+use serde_json::{Map, Value};
+
+use crate::telemetry;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Цветной текст для чтения человеком
+    Text,
+    /// Одна JSON-запись на строку для сборщика логов
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("must be one of text, json".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Уровень по умолчанию и уровни модулей в формате "info,user_service_server::repo=debug".
+/// Для записи выбирается самый длинный совпавший префикс target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogLevels {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl LogLevels {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| {
+                target == module
+                    || target
+                        .strip_prefix(module.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+impl FromStr for LogLevels {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_level = |level: &str| {
+            level
+                .trim()
+                .parse::<LevelFilter>()
+                .map_err(|_| format!("invalid log level \"{}\"", level.trim()))
+        };
+
+        let mut default = None;
+        let mut modules: Vec<(String, LevelFilter)> = Vec::new();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let module = module.trim().to_string();
+                    let level = parse_level(level)?;
+                    modules.retain(|(existing, _)| *existing != module);
+                    modules.push((module, level));
+                }
+                None => default = Some(parse_level(directive)?),
+            }
+        }
+        // Длинные префиксы проверяются первыми
+        modules.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+
+        Ok(LogLevels {
+            default: default.unwrap_or(LevelFilter::Info),
+            modules,
+        })
+    }
+}
+
+impl fmt::Display for LogLevels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default.as_str().to_lowercase())?;
+        for (module, level) in &self.modules {
+            write!(f, ",{}={}", module, level.as_str().to_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+/// Общие для логгера и AdminService уровни логирования
+#[derive(Debug, Clone)]
+pub struct LogLevelHandle {
+    levels: Arc<RwLock<LogLevels>>,
+}
+
+impl LogLevelHandle {
+    pub fn new(levels: LogLevels) -> Self {
+        LogLevelHandle {
+            levels: Arc::new(RwLock::new(levels)),
+        }
+    }
+
+    pub fn get(&self) -> LogLevels {
+        self.levels.read().unwrap().clone()
+    }
+
+    pub fn set(&self, levels: LogLevels) {
+        log::set_max_level(levels.max_level());
+        *self.levels.write().unwrap() = levels;
+    }
+
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.levels.read().unwrap().level_for(metadata.target())
+    }
+}
+
+static URL_CREDENTIALS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(://[^:/@\s]+):[^\s/]+@").unwrap());
+static EMAIL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"([A-Za-z0-9._%+-])[A-Za-z0-9._%+-]*@([A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,})",
+    )
+    .unwrap()
+});
+
+/// Маскирует адреса почты (a***@example.com) и пароли в URL подключения
+pub fn redact(message: &str) -> Cow<'_, str> {
+    match URL_CREDENTIALS.replace_all(message, "$1:***@") {
+        Cow::Borrowed(message) => EMAIL.replace_all(message, "$1***@$2"),
+        Cow::Owned(message) => Cow::Owned(EMAIL.replace_all(&message, "$1***@$2").into_owned()),
+    }
+}
+
+fn json_line(record: &Record, message: &str) -> String {
+    let mut line = Map::new();
+    line.insert(
+        "timestamp".to_string(),
+        Value::from(Local::now().to_rfc3339()),
+    );
+    line.insert("level".to_string(), Value::from(record.level().as_str()));
+    line.insert("target".to_string(), Value::from(record.target()));
+    line.insert("message".to_string(), Value::from(message));
+    if let Some(request_id) = telemetry::current_request_id() {
+        line.insert("request_id".to_string(), Value::from(request_id));
+    }
+    if let Some((trace_id, span_id)) = telemetry::current_trace_ids() {
+        line.insert("trace_id".to_string(), Value::from(trace_id));
+        line.insert("span_id".to_string(), Value::from(span_id));
+    }
+    Value::Object(line).to_string()
+}
+
+/// Уровень самого логгера - Trace, отбор записей делает фильтр по LogLevelHandle,
+/// а log::max_level отсекает лишние записи ещё до форматирования
+pub fn setup_logger(
+    format: LogFormat, levels: LogLevels,
+) -> Result<LogLevelHandle, fern::InitError> {
+    let handle = LogLevelHandle::new(levels.clone());
+    let filter = handle.clone();
+    let colors = ColoredLevelConfig::new()
+        .trace(Color::White)
+        .debug(Color::White)
+        .error(Color::Red)
+        .warn(Color::Yellow)
+        .info(Color::Cyan);
+
+    fern::Dispatch::new()
+        .format(move |out, message, record| {
+            let message = message.to_string();
+            let message = redact(&message);
+            match format {
+                LogFormat::Json => out.finish(format_args!("{}", json_line(record, &message))),
+                LogFormat::Text => {
+                    let request = telemetry::current_request_id()
+                        .map(|request_id| format!(" request_id={}", request_id))
+                        .unwrap_or_default();
+                    let trace = telemetry::current_trace_ids()
+                        .map(|(trace_id, span_id)| {
+                            format!(" trace_id={} span_id={}", trace_id, span_id)
+                        })
+                        .unwrap_or_default();
+                    out.finish(format_args!(
+                        "[{}] {}{}{} <{}> {}",
+                        Local::now().format("%Y-%m-%dT%H:%M:%S"),
+                        record.target(),
+                        request,
+                        trace,
+                        colors.color(record.level()),
+                        message
+                    ))
+                }
+            }
+        })
+        .level(LevelFilter::Trace)
+        .filter(move |metadata| filter.enabled(metadata))
+        .chain(std::io::stdout())
+        .apply()?;
+    log::set_max_level(levels.max_level());
+    Ok(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_log_levels() {
+        let levels: LogLevels = "warn, user_service_server=info,user_service_server::repo=TRACE"
+            .parse()
+            .unwrap();
+        assert_eq!(levels.level_for("tonic"), LevelFilter::Warn);
+        assert_eq!(levels.level_for("user_service_server"), LevelFilter::Info);
+        assert_eq!(
+            levels.level_for("user_service_server::repo::database"),
+            LevelFilter::Trace
+        );
+        assert_eq!(
+            levels.level_for("user_service_server::repository"),
+            LevelFilter::Info
+        );
+        assert_eq!(levels.max_level(), LevelFilter::Trace);
+        assert_eq!(
+            levels.to_string(),
+            "warn,user_service_server::repo=trace,user_service_server=info"
+        );
+
+        assert_eq!(
+            "info,repo=loud".parse::<LogLevels>().unwrap_err(),
+            "invalid log level \"loud\""
+        );
+    }
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact("User { username: \"test\", email: \"john.doe@mail.example.com\" }"),
+            "User { username: \"test\", email: \"j***@mail.example.com\" }"
+        );
+        assert_eq!(
+            redact("Connecting to postgres://user:s3cr@t@db:5432/users"),
+            "Connecting to postgres://user:***@db:5432/users"
+        );
+        assert_eq!(
+            redact("Nothing to hide: user@localhost"),
+            "Nothing to hide: user@localhost"
+        );
+    }
+
+    #[test]
+    fn test_json_line() {
+        let record = Record::builder()
+            .level(log::Level::Info)
+            .target("user_service_server::app")
+            .args(format_args!("ignored"))
+            .build();
+        let line: Value = serde_json::from_str(&json_line(&record, "Created \"a\"")).unwrap();
+
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["target"], "user_service_server::app");
+        assert_eq!(line["message"], "Created \"a\"");
+        assert!(line.get("trace_id").is_none());
+        assert!(line.get("request_id").is_none());
+
+        let _guard = opentelemetry::Context::current()
+            .with_value(telemetry::RequestId("req-1".to_string()))
+            .attach();
+        let line: Value = serde_json::from_str(&json_line(&record, "Created")).unwrap();
+        assert_eq!(line["request_id"], "req-1");
+    }
+}
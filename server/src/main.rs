@@ -1,80 +1,544 @@
-use chrono::Local;
-use fern::colors::{Color, ColoredLevelConfig};
+use clap::{Parser, Subcommand};
+use lib_rpc::adminpb::admin_service_server::AdminServiceServer;
 use lib_rpc::userpb::user_service_server::UserServiceServer;
-use log::info;
+use log::{error, info, warn};
+use std::env;
+use std::path::PathBuf;
+use std::process;
 use std::sync::Arc;
+use std::time::Duration;
+use tonic::service::interceptor::InterceptedService;
 use tonic::transport::Server;
+use tower::Layer;
 
 mod app;
 
+use crate::adapters::migrations;
 use crate::adapters::postgres::DbRepository;
+use crate::app::admin::AdminServiceCore;
+use crate::app::auth::{AuthInterceptor, Authenticator};
+use crate::app::health::{
+    check_health, health_service, run_readiness_task, HealthState, HealthStatus,
+};
+use crate::app::metrics::{run_metrics_server, RpcMetricsLayer};
+use crate::app::purge::run_purge_task;
+use crate::app::rate_limit::RateLimitLayer;
+use crate::app::request_id::RequestIdLayer;
+use crate::app::shutdown::{self, wait_for_signal};
+use crate::app::tls::{self, run_reload_task, TlsReloader};
+use crate::app::trace::RpcTraceLayer;
 use crate::app::user_service::UserServiceCore;
-use crate::config::Config;
+use crate::app::validation::UsernamePolicy;
+use crate::config::{Config, ConfigSources, DEFAULT_HEALTH_PORT};
+use crate::errors::MigrationError;
+use crate::logging::setup_logger;
+use crate::repo::instrumented::InstrumentedRepository;
+use crate::telemetry::{init_tracing, TracingExporter};
 
 mod adapters;
 mod config;
 mod errors;
+mod logging;
+mod metrics;
 mod repo;
+mod telemetry;
 mod types;
 
-/*
 #[derive(Parser)]
 #[clap(author, version, about = "Типо сервер")]
 struct Args {
-    #[arg(short, long, default_value = "8080")]
-    port: usize,
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    /// По умолчанию serve
+    #[command(subcommand)]
+    command: Option<Command>,
 }
-*/
-fn setup_logger() -> Result<(), fern::InitError> {
-    let colors = ColoredLevelConfig::new()
-        .trace(Color::White)
-        .debug(Color::White)
-        .error(Color::Red)
-        .warn(Color::Yellow)
-        .info(Color::Cyan);
-    fern::Dispatch::new()
-        .format(move |out, message, record| {
-            out.finish(format_args!(
-                "[{}] {} <{}> {}",
-                Local::now().format("%Y-%m-%dT%H:%M:%S"),
-                record.target(),
-                colors.color(record.level()),
-                message
-            ))
-        })
-        .level(log::LevelFilter::Debug)
-        .chain(std::io::stdout())
-        .apply()?;
-    Ok(())
+
+/// Настройки применяются слоями: значения по умолчанию, файл, окружение, флаги
+#[derive(clap::Args)]
+struct ConfigArgs {
+    /// TOML-файл, ключи - имена переменных окружения в нижнем регистре
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+    #[arg(long, global = true)]
+    database_url: Option<String>,
+    #[arg(long, global = true)]
+    server_host: Option<String>,
+    #[arg(long, global = true)]
+    server_port: Option<String>,
+    #[arg(long, global = true)]
+    health_port: Option<String>,
+    /// Любая настройка, например --set db_pool_size=20. Можно повторять
+    #[arg(long = "set", value_name = "KEY=VALUE", global = true, value_parser = parse_setting)]
+    settings: Vec<(String, String)>,
+}
+
+impl ConfigArgs {
+    fn overrides(&self) -> Vec<(String, String)> {
+        let flags = [
+            ("database_url", &self.database_url),
+            ("server_host", &self.server_host),
+            ("server_port", &self.server_port),
+            ("health_port", &self.health_port),
+        ];
+        self.settings
+            .iter()
+            .cloned()
+            .chain(flags.into_iter().filter_map(|(key, value)| {
+                value.as_ref().map(|value| (key.to_string(), value.clone()))
+            }))
+            .collect()
+    }
+}
+
+fn parse_setting(setting: &str) -> Result<(String, String), String> {
+    setting
+        .split_once('=')
+        .map(|(key, value)| (key.trim().to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got \"{}\"", setting))
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Запуск сервера, при auto_migrate применяет миграции при подключении к БД
+    Serve,
+    /// Управление миграциями схемы БД, по умолчанию up
+    Migrate {
+        #[command(subcommand)]
+        action: Option<MigrateAction>,
+    },
+    /// Проверка здоровья запущенного сервера, для HEALTHCHECK контейнера
+    Healthcheck {
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+        /// По умолчанию health_port из настроек или 8081
+        #[arg(short, long)]
+        port: Option<u16>,
+        #[arg(long, default_value_t = 5)]
+        timeout_secs: u64,
+    },
+    /// Вывести итоговые настройки с источником каждого значения
+    PrintConfig,
+}
+
+#[derive(Subcommand, Clone, Copy)]
+enum MigrateAction {
+    /// Список встроенных миграций с отметкой о применении
+    Status,
+    /// Применить ожидающие миграции
+    Up,
+    /// Откатить последние миграции
+    Down {
+        #[arg(long, default_value_t = 1)]
+        steps: u32,
+    },
+    /// Откатить и заново применить последнюю миграцию
+    Redo,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    setup_logger()?;
-    //let args = Args::parse();
+    let args = Args::parse();
+    dotenv::dotenv().ok();
+    let sources = ConfigSources::load(
+        args.config.config.as_deref(),
+        |name| env::var(name).ok(),
+        &args.config.overrides(),
+    );
+
+    let command = args.command.unwrap_or(Command::Serve);
+    if let Command::Healthcheck {
+        host,
+        port,
+        timeout_secs,
+    } = command
+    {
+        let port = port
+            .or_else(|| {
+                sources
+                    .get("health_port")
+                    .and_then(|port| port.parse().ok())
+            })
+            .unwrap_or(DEFAULT_HEALTH_PORT);
+        let addr = format!("http://{}:{}", host, port);
+        match check_health(addr, Duration::from_secs(timeout_secs)).await {
+            Ok(true) => process::exit(0),
+            Ok(false) => process::exit(1),
+            Err(e) => {
+                eprintln!("Health check failed: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let config = match Config::from_sources(&sources) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    match command {
+        Command::PrintConfig => {
+            print!("{}", config.render(&sources));
+            Ok(())
+        }
+        Command::Migrate { action } => migrate(config, action.unwrap_or(MigrateAction::Up)).await,
+        _ => serve(config).await,
+    }
+}
+
+async fn migrate(config: Config, action: MigrateAction) -> Result<(), Box<dyn std::error::Error>> {
+    // Вывод status не смешивается с логами
+    if !matches!(action, MigrateAction::Status) {
+        setup_logger(config.log_format, config.log_levels.clone())?;
+    }
+    let repository = match DbRepository::connect_with_retry(
+        config.database_url,
+        config.db_pool,
+        &config.db_connect_retry,
+        |_| {},
+    )
+    .await
+    {
+        Ok(repository) => repository,
+        Err(e) => {
+            error!("{}", e);
+            eprintln!("Failed to connect to the database: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let result = tokio::task::spawn_blocking(move || {
+        let conn = &mut repository
+            .get_conn()
+            .map_err(|e| MigrationError::MigrationFailed(e.to_string()))?;
+        match action {
+            MigrateAction::Status => {
+                for migration in migrations::status(conn)? {
+                    let mark = if migration.applied { "x" } else { " " };
+                    println!("[{}] {}", mark, migration.name);
+                }
+            }
+            MigrateAction::Up => {
+                let applied = migrations::up(conn)?;
+                if applied.is_empty() {
+                    info!("No pending migrations found");
+                }
+                for migration in applied {
+                    info!("Applied migration: {}", migration);
+                }
+            }
+            MigrateAction::Down { steps } => {
+                let reverted = migrations::down(conn, steps)?;
+                if reverted.is_empty() {
+                    info!("No applied migrations to revert");
+                }
+                for migration in reverted {
+                    info!("Reverted migration: {}", migration);
+                }
+            }
+            MigrateAction::Redo => {
+                info!("Redone migration: {}", migrations::redo(conn)?);
+            }
+        }
+        Ok::<_, MigrationError>(())
+    })
+    .await?;
+
+    if let Err(e) = result {
+        error!("{}", e);
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    Ok(())
+}
 
-    let config = Config::from_env();
+async fn serve(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let log_levels = setup_logger(config.log_format, config.log_levels.clone())?;
+    metrics::init();
+    let tracer_provider = match init_tracing(config.tracing_exporter, &config.otlp_endpoint) {
+        Ok(tracer_provider) => tracer_provider,
+        Err(e) => {
+            error!("Failed to initialize tracing: {}", e);
+            eprintln!("Failed to initialize tracing: {}", e);
+            process::exit(1);
+        }
+    };
+    if config.tracing_exporter != TracingExporter::None {
+        info!("Exporting traces to {}", config.tracing_exporter);
+    }
 
     info!("Initializing the UserServiceServer...");
+    let authenticator = match Authenticator::new(&config.auth) {
+        Ok(authenticator) => authenticator,
+        Err(e) => {
+            error!("{}", e);
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    if !config.auth.required {
+        warn!("Authentication is not required, every UserService method is open to any caller");
+    }
+    let tls_reloader = match config.tls.clone().map(TlsReloader::new).transpose() {
+        Ok(tls_reloader) => tls_reloader,
+        Err(e) => {
+            error!("{}", e);
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let username_policy = match UsernamePolicy::new(&config.username_policy) {
+        Ok(username_policy) => username_policy,
+        Err(e) => {
+            error!("{}", e);
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    info!(
+        "Username policy: {} reserved usernames",
+        username_policy.reserved_words_count()
+    );
+
+    let health = HealthState::new();
+    let (shutdown_trigger, shutdown) = shutdown::channel();
+    {
+        let health = health.clone();
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            // Сначала NOT_SERVING, чтобы балансировщик перестал слать новые запросы
+            health.set(HealthStatus::ShuttingDown);
+            shutdown_trigger.trigger();
+        });
+    }
 
-    ///Переделать
-    let db_repository = DbRepository::new(config.database_url)
-        .map_err(|e| {
-            eprintln!("Failed to create DbRepository: {:?}", e);
-            e
-        })
-        .unwrap();
+    // Сервисы здоровья и метрик останавливаются последними, чтобы во время дренажа
+    // отвечать NOT_SERVING и отдавать метрики
+    let (health_server_trigger, health_server_shutdown) = shutdown::channel();
+    let metrics_shutdown = health_server_shutdown.clone();
+    let health_service = health_service(&health).await;
+    let health_addr = config.health_addr;
+    info!("Health service listening on {}", config.health_addr);
+    let health_server = tokio::spawn({
+        let health_service = health_service.clone();
+        async move {
+            if let Err(e) = Server::builder()
+                .add_service(health_service)
+                .serve_with_shutdown(health_addr, health_server_shutdown.wait())
+                .await
+            {
+                error!("Health service failed: {}", e);
+            }
+        }
+    });
+
+    let connect = DbRepository::connect_with_retry(
+        config.database_url.clone(),
+        config.db_pool.clone(),
+        &config.db_connect_retry,
+        |status| health.set(HealthStatus::from(status)),
+    );
+    let db_repository = tokio::select! {
+        result = connect => match result {
+            Ok(db_repository) => db_repository,
+            Err(e) => {
+                error!("{}", e);
+                eprintln!("Failed to create DbRepository: {}", e);
+                process::exit(1);
+            }
+        },
+        _ = shutdown.clone().wait() => {
+            info!("Shutdown requested before the database became available");
+            health_server_trigger.trigger();
+            let _ = health_server.await;
+            return Ok(());
+        }
+    };
+
+    let repository = Arc::new(db_repository);
+
+    // Без автоматических миграций реплика ждёт `migrate up`, оставаясь NOT_SERVING
+    loop {
+        let migrated = {
+            let repository = repository.clone();
+            let auto_migrate = config.auto_migrate;
+            tokio::task::spawn_blocking(move || repository.manage_migration(auto_migrate))
+                .await?
+        };
+        match migrated {
+            Ok(()) => break,
+            Err(MigrationError::Pending(pending)) => {
+                if !matches!(health.get(), HealthStatus::MigrationsPending(reported) if reported == pending)
+                {
+                    warn!("{}", MigrationError::Pending(pending));
+                }
+                health.set(HealthStatus::MigrationsPending(pending));
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(config.health_check_interval) => {}
+            _ = shutdown.clone().wait() => {
+                info!("Shutdown requested while waiting for migrations");
+                health_server_trigger.trigger();
+                let _ = health_server.await;
+                return Ok(());
+            }
+        }
+    }
+
+    let metrics_server = if config.metrics_enabled {
+        let pool = repository.pool.clone();
+        let metrics_addr = config.metrics_addr;
+        Some(tokio::spawn(async move {
+            let pool_state = move || {
+                let state = pool.state();
+                (state.connections, state.idle_connections)
+            };
+            if let Err(e) = run_metrics_server(metrics_addr, pool_state, metrics_shutdown).await {
+                error!("Metrics server failed: {}", e);
+            }
+        }))
+    } else {
+        None
+    };
+    let instrumented = Arc::new(InstrumentedRepository::new(repository.clone()));
+
+    // Пул уже выдал соединение для миграций, дальше готовность проверяется периодически
+    health.set(HealthStatus::Serving);
+    let readiness_task = tokio::spawn(run_readiness_task(
+        instrumented.clone(),
+        repository.checkout_failures(),
+        health.clone(),
+        config.health_check_interval,
+        shutdown.clone(),
+    ));
+
+    let purge_task = tokio::spawn(run_purge_task(
+        instrumented.clone(),
+        config.purge_grace_period,
+        config.purge_interval,
+        shutdown.clone(),
+    ));
 
     let user_service = UserServiceCore {
-        repository: Arc::new(db_repository),
+        repository: instrumented,
+        username_policy: Arc::new(username_policy),
+    };
+
+    let (reflection, reflection_v1alpha) = if config.reflection_enabled {
+        info!("gRPC reflection is enabled");
+        let reflection = tonic_reflection::server::Builder::configure()
+            .register_encoded_file_descriptor_set(lib_rpc::FILE_DESCRIPTOR_SET)
+            .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+            .build_v1()?;
+        // Старые версии grpcurl и Postman знают только v1alpha
+        let reflection_v1alpha = tonic_reflection::server::Builder::configure()
+            .register_encoded_file_descriptor_set(lib_rpc::FILE_DESCRIPTOR_SET)
+            .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+            .build_v1alpha()?;
+        (Some(reflection), Some(reflection_v1alpha))
+    } else {
+        (None, None)
+    };
+
+    let tls_reload_task = tls_reloader
+        .clone()
+        .map(|reloader| tokio::spawn(run_reload_task(reloader, shutdown.clone())));
+
+    let auth_interceptor = AuthInterceptor::new(authenticator, tls_reloader.clone());
+    let router = Server::builder()
+        .layer(RpcTraceLayer)
+        .layer(RpcMetricsLayer)
+        .layer(RequestIdLayer)
+        .add_service(health_service.without_details())
+        .add_optional_service(reflection)
+        .add_optional_service(reflection_v1alpha)
+        // Квоты проверяются после AuthInterceptor, чтобы считать их по вызывающему
+        .add_service(InterceptedService::new(
+            RateLimitLayer::new(&config.rate_limit).layer(UserServiceServer::new(user_service)),
+            auth_interceptor.clone(),
+        ))
+        // Административный сервис за тем же TLS и токеном, методы требуют SCOPE_ADMIN
+        .add_service(InterceptedService::new(
+            AdminServiceServer::new(AdminServiceCore { log_levels }),
+            auth_interceptor,
+        ));
+    let drain_signal = async {
+        shutdown.clone().wait().await;
+        info!(
+            "Draining in-flight requests, timeout {:?}",
+            config.shutdown_timeout
+        );
+    };
+    let server = async {
+        match tls_reloader {
+            Some(tls_reloader) => {
+                let listener = tokio::net::TcpListener::bind(config.server_addr).await?;
+                info!(
+                    "UserServiceServer listening on {} with TLS",
+                    config.server_addr
+                );
+                let incoming = tls::incoming(listener, tls_reloader, shutdown.clone());
+                router
+                    .serve_with_incoming_shutdown(incoming, drain_signal)
+                    .await?
+            }
+            None => {
+                info!("UserServiceServer listening on {}", config.server_addr);
+                router
+                    .serve_with_shutdown(config.server_addr, drain_signal)
+                    .await?
+            }
+        }
+        Ok::<_, Box<dyn std::error::Error>>(())
+    };
+    let drain_deadline = async {
+        shutdown.clone().wait().await;
+        tokio::time::sleep(config.shutdown_timeout).await;
     };
+    tokio::select! {
+        result = server => result?,
+        _ = drain_deadline => {
+            warn!(
+                "In-flight requests did not finish within {:?}, aborting them",
+                config.shutdown_timeout
+            );
+        }
+    }
 
-    info!("UserServiceServer listening on {}", config.server_addr);
+    let _ = tokio::join!(readiness_task, purge_task);
+    if let Some(tls_reload_task) = tls_reload_task {
+        let _ = tls_reload_task.await;
+    }
+    health_server_trigger.trigger();
+    let _ = health_server.await;
+    if let Some(metrics_server) = metrics_server {
+        let _ = metrics_server.await;
+    }
 
-    Server::builder()
-        .add_service(UserServiceServer::new(user_service))
-        .serve(config.server_addr.parse().unwrap())
-        .await?;
+    // Последняя ссылка на репозиторий, вместе с ней закрываются соединения пула
+    match Arc::try_unwrap(repository) {
+        Ok(db_repository) => {
+            drop(db_repository);
+            info!("Database pool closed");
+        }
+        Err(_) => warn!("Database pool is still in use by aborted requests"),
+    }
+    // Отправка накопленных спанов
+    if let Some(tracer_provider) = tracer_provider {
+        if let Err(e) = tracer_provider.shutdown() {
+            warn!("Failed to flush traces: {}", e);
+        }
+    }
+    info!("UserServiceServer stopped");
 
     Ok(())
 }
@@ -0,0 +1,190 @@
+//! Метрики Prometheus. Все метрики регистрируются в собственном реестре,
+//! который отдаёт HTTP-сервер из app::metrics
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use log::error;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+
+const NAMESPACE: &str = "user_service";
+
+pub static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(metric: T) -> T {
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("Metric registered twice");
+    metric
+}
+
+pub static RPC_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "rpc_requests_total",
+                "RPC requests by method and gRPC status code",
+            )
+            .namespace(NAMESPACE),
+            &["method", "code"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static RPC_REJECTED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "rpc_rejected_total",
+                "RPC requests rejected by rate limit or concurrency limit",
+            )
+            .namespace(NAMESPACE),
+            &["method", "reason"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static RPC_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new("rpc_duration_seconds", "RPC latency by method")
+                .namespace(NAMESPACE),
+            &["method"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static REPOSITORY_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "repository_duration_seconds",
+                "UserRepository call latency by method and result",
+            )
+            .namespace(NAMESPACE),
+            &["method", "result"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static DB_POOL_CONNECTIONS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new(
+                "db_pool_connections",
+                "Pool connections by state (idle, active)",
+            )
+            .namespace(NAMESPACE),
+            &["state"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static DB_POOL_CHECKOUT_DURATION: LazyLock<prometheus::Histogram> = LazyLock::new(|| {
+    register(
+        prometheus::Histogram::with_opts(
+            HistogramOpts::new(
+                "db_pool_checkout_duration_seconds",
+                "Time spent waiting for a pool connection",
+            )
+            .namespace(NAMESPACE),
+        )
+        .unwrap(),
+    )
+});
+
+pub static DB_POOL_CHECKOUT_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(
+        IntCounter::with_opts(
+            Opts::new(
+                "db_pool_checkout_failures_total",
+                "Failed attempts to get a pool connection",
+            )
+            .namespace(NAMESPACE),
+        )
+        .unwrap(),
+    )
+});
+
+/// Регистрирует все метрики сразу, чтобы они были видны с нулевыми значениями до первого события
+pub fn init() {
+    LazyLock::force(&RPC_REQUESTS);
+    LazyLock::force(&RPC_REJECTED);
+    LazyLock::force(&RPC_DURATION);
+    LazyLock::force(&REPOSITORY_DURATION);
+    LazyLock::force(&DB_POOL_CONNECTIONS);
+    LazyLock::force(&DB_POOL_CHECKOUT_DURATION);
+    LazyLock::force(&DB_POOL_CHECKOUT_FAILURES);
+}
+
+pub fn observe_rpc(method: &str, code: tonic::Code, elapsed: Duration) {
+    RPC_REQUESTS
+        .with_label_values(&[method, &format!("{:?}", code)])
+        .inc();
+    RPC_DURATION
+        .with_label_values(&[method])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// reason: rate_limit - исчерпана квота клиента, overload - превышен предел одновременных запросов
+pub fn observe_rpc_rejected(method: &str, reason: &str) {
+    RPC_REJECTED.with_label_values(&[method, reason]).inc();
+}
+
+pub fn observe_repository(method: &str, ok: bool, elapsed: Duration) {
+    let result = if ok { "ok" } else { "error" };
+    REPOSITORY_DURATION
+        .with_label_values(&[method, result])
+        .observe(elapsed.as_secs_f64());
+}
+
+pub fn set_pool_state(connections: u32, idle_connections: u32) {
+    DB_POOL_CONNECTIONS
+        .with_label_values(&["idle"])
+        .set(idle_connections.into());
+    DB_POOL_CONNECTIONS
+        .with_label_values(&["active"])
+        .set(connections.saturating_sub(idle_connections).into());
+}
+
+/// Текстовый формат Prometheus
+pub fn encode() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        error!("Failed to encode metrics: {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_contains_observed_metrics() {
+        observe_rpc(
+            "TestMethod",
+            tonic::Code::NotFound,
+            Duration::from_millis(5),
+        );
+        observe_repository("test_method", false, Duration::from_millis(1));
+        set_pool_state(5, 2);
+
+        let text = encode();
+        assert!(text.contains(
+            "user_service_rpc_requests_total{code=\"NotFound\",method=\"TestMethod\"} 1"
+        ));
+        assert!(text.contains("user_service_rpc_duration_seconds_count{method=\"TestMethod\"} 1"));
+        assert!(text.contains(
+            "user_service_repository_duration_seconds_count{method=\"test_method\",result=\"error\"} 1"
+        ));
+        assert!(text.contains("user_service_db_pool_connections{state=\"active\"} 3"));
+    }
+}
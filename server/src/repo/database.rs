@@ -1,60 +1,116 @@
 use crate::adapters::postgres::DbRepository;
 use crate::adapters::schema::users::dsl::users;
-use crate::adapters::schema::users::{email, id, username};
+use crate::adapters::schema::users::{
+    created_at, deleted_at, email, id, updated_at, username, username_skeleton, version,
+};
+use crate::app::validation;
 use crate::errors::DbError;
 use crate::repo::{RepoError, UserRepository};
-use crate::types::User;
+use crate::types::{User, UsersPageQuery};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use diesel::associations::HasTable;
-use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel::dsl::sql;
+use diesel::sql_types::{Array, Bool, Text};
+use diesel::{
+    define_sql_function, sql_query, Connection, ExpressionMethods, OptionalExtension, PgConnection,
+    PgTextExpressionMethods, QueryDsl, RunQueryDsl, SelectableHelper,
+};
 use log::{debug, error, trace};
 use uuid::Uuid;
 
+// Регистр сворачивает сама БД с обеих сторон сравнения, как и уникальный индекс по lower(username):
+// to_lowercase в Rust и lower() в Postgres расходятся для не-ASCII букв, если локаль БД - C
+define_sql_function!(fn lower(x: Text) -> Text);
+
 #[async_trait]
 impl UserRepository for DbRepository {
     async fn add_user(&self, user: User) -> Result<(), RepoError> {
         trace!("Adding user: {:?}", user);
-        let conn = &mut self.get_conn()?;
-        let new_user = User {
-            id: user.id,
-            username: user.username,
-            email: user.email,
-        };
-        diesel::insert_into(users::table())
-            .values(&new_user)
-            .execute(conn)
-            .map_err(|e| {
-                error!("Failed to add user: {}", e);
-                RepoError::DbError(DbError::QueryError(e.to_string()))
+        let skeleton = validation::username_skeleton(&user.username);
+        self.run(move |conn| {
+            transaction(conn, |conn| {
+                check_confusable(conn, user.id, &user.username, &skeleton)?;
+                diesel::insert_into(users::table())
+                    .values((&user, username_skeleton.eq(&skeleton)))
+                    .execute(conn)
+                    .map_err(|e| {
+                        error!("Failed to add user: {}", e);
+                        query_error(e)
+                    })?;
+                Ok(())
             })?;
-        debug!("User added successfully: {:?}", new_user);
-        Ok(())
+            debug!("User added successfully: {:?}", user);
+            Ok(())
+        })
+        .await
     }
 
-    async fn get_all_users(&self) -> Result<Vec<User>, RepoError> {
-        debug!("Fetching all users");
-        let conn = &mut self.get_conn()?;
-        let result = users.load::<User>(conn).map_err(|e| {
-            error!("Failed to fetch all users: {}", e);
-            RepoError::DbError(DbError::QueryError(e.to_string()))
-        })?;
-        trace!("Fetched all users successfully: {:?}", result);
-        Ok(result)
+    async fn get_all_users(&self, query: &UsersPageQuery) -> Result<Vec<User>, RepoError> {
+        debug!("Fetching users page: {:?}", query);
+        let query = query.clone();
+        self.run(move |conn| {
+            let mut statement = users
+                .filter(deleted_at.is_null())
+                .select(User::as_select())
+                .into_boxed();
+            if let Some(after) = query.after {
+                statement = if query.newest_first {
+                    statement.filter(id.lt(after))
+                } else {
+                    statement.filter(id.gt(after))
+                };
+            }
+            if let Some(prefix) = &query.username_prefix {
+                statement = statement.filter(username.ilike(format!("{}%", escape_like(prefix))));
+            }
+            if let Some(domain) = &query.email_domain {
+                statement = statement.filter(email.ilike(format!("%@{}", escape_like(domain))));
+            }
+            if let Some(after) = query.created_after {
+                statement = statement.filter(created_at.ge(after));
+            }
+            if let Some(before) = query.created_before {
+                statement = statement.filter(created_at.lt(before));
+            }
+            statement = if query.newest_first {
+                statement.order(id.desc())
+            } else {
+                statement.order(id.asc())
+            };
+
+            let result = statement
+                .limit(query.limit as i64)
+                .load::<User>(conn)
+                .map_err(|e| {
+                    error!("Failed to fetch users page: {}", e);
+                    query_error(e)
+                })?;
+            trace!("Fetched users page successfully: {:?}", result);
+            Ok(result)
+        })
+        .await
     }
 
     async fn get_user(&self, user_id: &Uuid) -> Result<Option<User>, RepoError> {
         debug!("Fetching user with ID: {}", user_id);
-        let conn = &mut self.get_conn()?;
-        let result = users
-            .filter(id.eq(user_id))
-            .first::<User>(conn)
-            .optional()
-            .map_err(|e| {
-                error!("Failed to fetch user: {}", e);
-                RepoError::DbError(DbError::QueryError(e.to_string()))
-            })?;
-        debug!("Fetched user with ID {}: {:?}", user_id, result);
-        Ok(result)
+        let user_id = *user_id;
+        self.run(move |conn| {
+            let result = users
+                .filter(id.eq(user_id))
+                .filter(deleted_at.is_null())
+                .select(User::as_select())
+                .first::<User>(conn)
+                .optional()
+                .map_err(|e| {
+                    error!("Failed to fetch user: {}", e);
+                    query_error(e)
+                })?;
+            debug!("Fetched user with ID {}: {:?}", user_id, result);
+            Ok(result)
+        })
+        .await
     }
 
     async fn get_user_id(&self, user_id: &Uuid) -> Result<Option<Uuid>, RepoError> {
@@ -68,47 +124,135 @@ impl UserRepository for DbRepository {
 
     async fn get_user_id_by_nickname(&self, nickname: &str) -> Result<Option<Uuid>, RepoError> {
         debug!("Fetching user ID with nickname: {}", nickname);
-        let conn = &mut self.get_conn()?;
-        let result = users
-            .filter(username.eq(nickname))
-            .select(id)
-            .first::<Uuid>(conn)
-            .optional()
-            .map_err(|e| {
-                error!("Failed to fetch user ID by nickname: {}", e);
-                RepoError::DbError(DbError::QueryError(e.to_string()))
-            })?;
-        debug!("Fetched user ID by nickname {}: {:?}", nickname, result);
-        Ok(result)
+        let nickname = nickname.to_string();
+        self.run(move |conn| {
+            let result = users
+                .filter(lower(username).eq(lower(&nickname)))
+                .filter(deleted_at.is_null())
+                .select(id)
+                .first::<Uuid>(conn)
+                .optional()
+                .map_err(|e| {
+                    error!("Failed to fetch user ID by nickname: {}", e);
+                    query_error(e)
+                })?;
+            debug!("Fetched user ID by nickname {}: {:?}", nickname, result);
+            Ok(result)
+        })
+        .await
+    }
+
+    async fn get_users_by_nicknames(&self, user_names: &[String]) -> Result<Vec<User>, RepoError> {
+        debug!("Fetching {} users by nicknames", user_names.len());
+        let user_names = user_names.to_vec();
+        self.run(move |conn| {
+            let result = users
+                .filter(
+                    sql::<Bool>("lower(username) = ANY (SELECT lower(name) FROM unnest(")
+                        .bind::<Array<Text>, _>(user_names)
+                        .sql(") AS name)"),
+                )
+                .filter(deleted_at.is_null())
+                .select(User::as_select())
+                .load::<User>(conn)
+                .map_err(|e| {
+                    error!("Failed to fetch users by nicknames: {}", e);
+                    query_error(e)
+                })?;
+            debug!("Fetched {} users by nicknames", result.len());
+            Ok(result)
+        })
+        .await
+    }
+
+    async fn get_users_by_ids(&self, user_ids: &[Uuid]) -> Result<Vec<User>, RepoError> {
+        debug!("Fetching {} users by IDs", user_ids.len());
+        let user_ids = user_ids.to_vec();
+        self.run(move |conn| {
+            let result = users
+                .filter(id.eq_any(user_ids))
+                .filter(deleted_at.is_null())
+                .select(User::as_select())
+                .load::<User>(conn)
+                .map_err(|e| {
+                    error!("Failed to fetch users by IDs: {}", e);
+                    query_error(e)
+                })?;
+            debug!("Fetched {} users by IDs", result.len());
+            Ok(result)
+        })
+        .await
     }
-    ///Переделать
+
     async fn update_user_by_id(
         &self, user_id: &Uuid, updated_user: User,
     ) -> Result<Option<()>, RepoError> {
         debug!("Updating user with ID {}: {:?}", user_id, updated_user);
-        let conn = &mut self.get_conn()?;
-        let target = users.filter(id.eq(user_id));
-        let updated_rows = diesel::update(target)
-            .set((
-                username.eq(updated_user.username),
-                email.eq(updated_user.email),
-            ))
-            .execute(conn)
-            .map_err(|e| {
-                error!("Failed to update user with ID {}: {}", user_id, e);
-                RepoError::DbError(DbError::QueryError(e.to_string()))
-            })?;
+        let user_id = *user_id;
+        let skeleton = validation::username_skeleton(&updated_user.username);
+        self.run(move |conn| {
+            transaction(conn, |conn| {
+                // Скелет проверяется только при смене имени, иначе старые строки,
+                // похожие на чужое имя, нельзя было бы обновить
+                let stored_username = users
+                    .filter(id.eq(user_id))
+                    .filter(deleted_at.is_null())
+                    .select(username)
+                    .first::<String>(conn)
+                    .optional()?;
+                if stored_username.is_some_and(|stored| stored != updated_user.username) {
+                    check_confusable(conn, user_id, &updated_user.username, &skeleton)?;
+                }
+                let target = users
+                    .filter(id.eq(user_id))
+                    .filter(deleted_at.is_null())
+                    .filter(version.eq(updated_user.version));
+                let updated_rows = diesel::update(target)
+                    .set((
+                        username.eq(updated_user.username),
+                        username_skeleton.eq(skeleton),
+                        email.eq(updated_user.email),
+                        version.eq(version + 1),
+                        updated_at.eq(Utc::now()),
+                    ))
+                    .execute(conn)
+                    .map_err(|e| {
+                        error!("Failed to update user with ID {}: {}", user_id, e);
+                        query_error(e)
+                    })?;
 
-        if updated_rows > 0 {
-            debug!("User with ID {} updated successfully", user_id);
-            Ok(Some(()))
-        } else {
-            debug!("No rows updated for user with ID {}", user_id);
-            Ok(None)
-        }
+                if updated_rows > 0 {
+                    debug!("User with ID {} updated successfully", user_id);
+                    return Ok(Some(()));
+                }
+
+                // Строка не обновлена: пользователя нет или его версия уже изменилась
+                let is_active = users
+                    .filter(id.eq(user_id))
+                    .filter(deleted_at.is_null())
+                    .select(id)
+                    .first::<Uuid>(conn)
+                    .optional()
+                    .map_err(|e| {
+                        error!("Failed to fetch user with ID {}: {}", user_id, e);
+                        query_error(e)
+                    })?
+                    .is_some();
+                if is_active {
+                    debug!(
+                        "Version {} of user with ID {} is outdated",
+                        updated_user.version, user_id
+                    );
+                    Err(RepoError::VersionConflict.into())
+                } else {
+                    debug!("No rows updated for user with ID {}", user_id);
+                    Ok(None)
+                }
+            })
+        })
+        .await
     }
 
-    ///Переделать
     async fn update_user_by_nickname(
         &self, nick_name: &str, updated_user: User,
     ) -> Result<Option<()>, RepoError> {
@@ -116,55 +260,210 @@ impl UserRepository for DbRepository {
             "Updating user with nickname {}: {:?}",
             nick_name, updated_user
         );
-        let conn = &mut self.get_conn()?;
-        let target = users.filter(username.eq(nick_name));
-        let updated_rows = diesel::update(target)
-            .set((
-                username.eq(updated_user.username),
-                email.eq(updated_user.email),
-            ))
-            .execute(conn)
-            .map_err(|e| {
-                debug!("Failed to update user with nickname {}: {}", nick_name, e);
-                RepoError::DbError(DbError::QueryError(e.to_string()))
+        match self.get_user_id_by_nickname(nick_name).await? {
+            Some(user_id) => self.update_user_by_id(&user_id, updated_user).await,
+            None => {
+                debug!("No user with nickname {} to update", nick_name);
+                Ok(None)
+            }
+        }
+    }
+
+    async fn delete_user(&self, user_id: &Uuid) -> Result<Option<()>, RepoError> {
+        debug!("Soft deleting user with ID {}", user_id);
+        let user_id = *user_id;
+        self.run(move |conn| {
+            let target = users.filter(id.eq(user_id)).filter(deleted_at.is_null());
+            let updated_rows = diesel::update(target)
+                .set((
+                    deleted_at.eq(Utc::now()),
+                    version.eq(version + 1),
+                    updated_at.eq(Utc::now()),
+                ))
+                .execute(conn)
+                .map_err(|e| {
+                    error!("Failed to delete user with ID {}: {}", user_id, e);
+                    query_error(e)
+                })?;
+
+            if updated_rows > 0 {
+                debug!("User with ID {} deleted successfully", user_id);
+                Ok(Some(()))
+            } else {
+                debug!("No active user with ID {} to delete", user_id);
+                Ok(None)
+            }
+        })
+        .await
+    }
+
+    async fn restore_user(&self, user_id: &Uuid) -> Result<Option<()>, RepoError> {
+        debug!("Restoring user with ID {}", user_id);
+        let user_id = *user_id;
+        self.run(move |conn| {
+            let target = users
+                .filter(id.eq(user_id))
+                .filter(deleted_at.is_not_null());
+            let updated_rows = diesel::update(target)
+                .set((
+                    deleted_at.eq(None::<DateTime<Utc>>),
+                    version.eq(version + 1),
+                    updated_at.eq(Utc::now()),
+                ))
+                .execute(conn)
+                .map_err(|e| {
+                    error!("Failed to restore user with ID {}: {}", user_id, e);
+                    query_error(e)
+                })?;
+
+            if updated_rows > 0 {
+                debug!("User with ID {} restored successfully", user_id);
+                Ok(Some(()))
+            } else {
+                debug!("No deleted user with ID {} to restore", user_id);
+                Ok(None)
+            }
+        })
+        .await
+    }
+
+    async fn purge_deleted_users(&self, deleted_before: DateTime<Utc>) -> Result<usize, RepoError> {
+        debug!("Purging users deleted before {}", deleted_before);
+        self.run(move |conn| {
+            let target = users.filter(deleted_at.lt(deleted_before));
+            let deleted_rows = diesel::delete(target).execute(conn).map_err(|e| {
+                error!("Failed to purge deleted users: {}", e);
+                query_error(e)
             })?;
+            debug!("Purged {} deleted users", deleted_rows);
+            Ok(deleted_rows)
+        })
+        .await
+    }
+
+    async fn ping(&self) -> Result<(), RepoError> {
+        trace!("Pinging database");
+        self.run(|conn| {
+            diesel::sql_query("SELECT 1").execute(conn).map_err(|e| {
+                error!("Database ping failed: {}", e);
+                query_error(e)
+            })?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// Нарушение уникальных индексов превращается в RepoError::AlreadyExists с именем поля
+fn query_error(e: DieselError) -> RepoError {
+    if let DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info) = &e {
+        let field = match info.constraint_name() {
+            Some("users_pkey") => "UUID",
+            Some("users_username_lower_key") => "username",
+            Some("users_email_lower_key") => "email",
+            Some(constraint) => constraint,
+            None => "key",
+        };
+        return RepoError::AlreadyExists(field.to_string());
+    }
+    RepoError::DbError(DbError::QueryError(e))
+}
+
+/// Ошибка внутри транзакции, любая из них её откатывает
+enum TransactionError {
+    Query(DieselError),
+    Repo(RepoError),
+}
+
+impl From<DieselError> for TransactionError {
+    fn from(e: DieselError) -> Self {
+        TransactionError::Query(e)
+    }
+}
+
+impl From<RepoError> for TransactionError {
+    fn from(e: RepoError) -> Self {
+        TransactionError::Repo(e)
+    }
+}
+
+fn transaction<T>(
+    conn: &mut PgConnection, f: impl FnOnce(&mut PgConnection) -> Result<T, TransactionError>,
+) -> Result<T, RepoError> {
+    conn.transaction(f).map_err(|e| match e {
+        TransactionError::Query(e) => query_error(e),
+        TransactionError::Repo(e) => e,
+    })
+}
+
+/// Отклоняет имя, скелет которого совпал со скелетом другого пользователя. Advisory-блокировка
+/// по скелету до конца транзакции не даёт параллельным запросам занять похожие имена.
+/// Совпадение без учёта регистра пропускается - его отклонит уникальный индекс
+fn check_confusable(
+    conn: &mut PgConnection, user_id: Uuid, user_name: &str, skeleton: &str,
+) -> Result<(), TransactionError> {
+    sql_query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind::<Text, _>(skeleton)
+        .execute(conn)?;
+    let confusable_with = users
+        .filter(username_skeleton.eq(skeleton))
+        .filter(id.ne(user_id))
+        .filter(lower(username).ne(lower(user_name)))
+        .select(id)
+        .first::<Uuid>(conn)
+        .optional()?;
+    match confusable_with {
+        Some(other_id) => {
+            debug!(
+                "Username {} is confusable with the username of user {}",
+                user_name, other_id
+            );
+            Err(RepoError::ConfusableUsername.into())
+        }
+        None => Ok(()),
+    }
+}
 
-        if updated_rows > 0 {
-            debug!("User with nickname {} updated successfully", nick_name);
-            Ok(Some(()))
-        } else {
-            debug!("No rows updated for user with nickname {}", nick_name);
-            Ok(None)
+/// Экранирование спецсимволов LIKE, чтобы фильтры сравнивались буквально
+fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
         }
+        escaped.push(c);
     }
+    escaped
 }
 
 #[cfg(test)]
 mod tests {
     use crate::adapters::postgres::{DbRepository, Pool};
     use crate::adapters::schema::users::dsl::users;
+    use crate::app::validation::username_skeleton;
+    use crate::config::PoolConfig;
+    use crate::errors::RepoError;
     use crate::repo::UserRepository;
-    use crate::types::User;
+    use crate::types::{User, UsersPageQuery};
+    use chrono::{Duration, Utc};
     use diesel::prelude::*;
     use diesel::r2d2::{self, ConnectionManager};
     use dotenv::dotenv;
     use pretty_assertions::assert_eq;
     use serial_test::serial;
     use std::env;
-    use tokio;
+    use std::error::Error;
     use uuid::Uuid;
-    use crate::errors::DbError;
 
-    fn setup_test_db() -> Result<r2d2::Pool<ConnectionManager<PgConnection>>, DbError> {
+    fn setup_test_db() -> Result<r2d2::Pool<ConnectionManager<PgConnection>>, Box<dyn Error>> {
         dotenv().ok();
 
-        let database_url = env::var("TEST_DATABASE_URL").map_err(|_| {
-            DbError::ConnectionError("TEST_DATABASE_URL must be set".to_string())
-        })?;
+        let database_url =
+            env::var("TEST_DATABASE_URL").map_err(|_| "TEST_DATABASE_URL must be set")?;
 
-        let db_repo = DbRepository::new(database_url)?;
+        let db_repo = DbRepository::new(database_url, &PoolConfig::default())?;
 
-        db_repo.manage_migration()?;
+        db_repo.manage_migration(true)?;
 
         Ok(db_repo.pool)
     }
@@ -180,38 +479,150 @@ mod tests {
     #[serial]
     async fn test_manage_migration() {
         let pool = setup_test_db().expect("Failed to setup test database");
-        let repo = DbRepository { pool: pool.clone() };
-        let result = repo.manage_migration();
+        let repo = DbRepository::from_pool(pool.clone());
+        let result = repo.manage_migration(true);
         assert!(result.is_ok(), "Migration should run successfully");
     }
 
+    #[tokio::test]
+    #[serial]
+    async fn ping() {
+        let pool = setup_test_db().expect("Failed to setup test database");
+        let repo = DbRepository::from_pool(pool);
+        assert!(repo.ping().await.is_ok(), "Database should be reachable");
+    }
+
     #[tokio::test]
     #[serial]
     async fn add_user() {
         let pool = setup_test_db().expect("Failed to setup test database");
-        let repo = DbRepository { pool: pool.clone() };
+        let repo = DbRepository::from_pool(pool.clone());
         clear_test_db(&pool);
 
         let user = User {
             id: Uuid::parse_str("0189a30a-60c7-7135-b683-7d7f3783d4b7").unwrap(),
             username: "testuser".to_string(),
             email: "testuser@test.com".to_string(),
+            deleted_at: None,
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
         };
         let result = repo.add_user(user.clone()).await;
         assert!(result.is_ok(), "User should be added successfully");
 
-        let all_users = repo.get_all_users().await;
+        let query = UsersPageQuery {
+            limit: 10,
+            ..Default::default()
+        };
+        let all_users = repo.get_all_users(&query).await;
         assert!(all_users.is_ok(), "Should retrieve all users successfully");
         let all_users = all_users.unwrap();
         assert_eq!(all_users.len(), 1);
         assert_eq!(all_users[0].username, "testuser");
     }
 
+    #[tokio::test]
+    #[serial]
+    async fn get_all_users_paginated() {
+        let pool = setup_test_db().expect("Failed to setup test database");
+        let repo = DbRepository::from_pool(pool.clone());
+        clear_test_db(&pool);
+
+        let now = Utc::now();
+        let mut user_ids = Vec::new();
+        for (days_ago, name, mail) in [
+            (30, "alice", "alice@example.com"),
+            (20, "alex", "alex@test.com"),
+            (10, "bob", "bob@example.com"),
+            (0, "al_ice", "al_ice@example.com"),
+        ] {
+            let user_id = Uuid::now_v7();
+            user_ids.push(user_id);
+            let created_at = now - Duration::days(days_ago);
+            let user = User {
+                id: user_id,
+                username: name.to_string(),
+                email: mail.to_string(),
+                deleted_at: None,
+                version: 1,
+                created_at,
+                updated_at: created_at,
+            };
+            repo.add_user(user).await.unwrap();
+        }
+
+        let query = UsersPageQuery {
+            limit: 2,
+            ..Default::default()
+        };
+        let first_page = repo.get_all_users(&query).await.unwrap();
+        assert_eq!(
+            first_page.iter().map(|u| u.id).collect::<Vec<_>>(),
+            user_ids[..2]
+        );
+
+        let query = UsersPageQuery {
+            after: Some(first_page[1].id),
+            limit: 2,
+            ..Default::default()
+        };
+        let second_page = repo.get_all_users(&query).await.unwrap();
+        assert_eq!(
+            second_page.iter().map(|u| u.id).collect::<Vec<_>>(),
+            user_ids[2..]
+        );
+
+        let query = UsersPageQuery {
+            limit: 10,
+            newest_first: true,
+            ..Default::default()
+        };
+        let newest_first = repo.get_all_users(&query).await.unwrap();
+        assert_eq!(newest_first[0].id, user_ids[3]);
+
+        let query = UsersPageQuery {
+            limit: 10,
+            username_prefix: Some("AL".to_string()),
+            email_domain: Some("example.com".to_string()),
+            ..Default::default()
+        };
+        let filtered = repo.get_all_users(&query).await.unwrap();
+        assert_eq!(
+            filtered
+                .iter()
+                .map(|u| u.username.as_str())
+                .collect::<Vec<_>>(),
+            vec!["alice", "al_ice"]
+        );
+
+        let query = UsersPageQuery {
+            limit: 10,
+            username_prefix: Some("al_".to_string()),
+            ..Default::default()
+        };
+        let filtered = repo.get_all_users(&query).await.unwrap();
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].username, "al_ice");
+
+        let query = UsersPageQuery {
+            limit: 10,
+            created_after: Some(now - Duration::days(25)),
+            created_before: Some(now - Duration::days(5)),
+            ..Default::default()
+        };
+        let filtered = repo.get_all_users(&query).await.unwrap();
+        assert_eq!(
+            filtered.iter().map(|u| u.id).collect::<Vec<_>>(),
+            user_ids[1..3]
+        );
+    }
+
     #[tokio::test]
     #[serial]
     async fn get_user_data_by_id() {
         let pool = setup_test_db().expect("Failed to setup test database");
-        let repo = DbRepository { pool: pool.clone() };
+        let repo = DbRepository::from_pool(pool.clone());
         clear_test_db(&pool);
 
         let user_id = Uuid::parse_str("0189a30a-60c7-7135-b683-7d7f3783d4b7").unwrap();
@@ -219,6 +630,10 @@ mod tests {
             id: user_id,
             username: "testuser".to_string(),
             email: "testuser@test.com".to_string(),
+            deleted_at: None,
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
         };
         let result = repo.add_user(user.clone()).await;
         assert!(result.is_ok(), "User should be added successfully");
@@ -234,7 +649,7 @@ mod tests {
     #[serial]
     async fn get_user_id() {
         let pool = setup_test_db().expect("Failed to setup test database");
-        let repo = DbRepository { pool: pool.clone() };
+        let repo = DbRepository::from_pool(pool.clone());
         clear_test_db(&pool);
 
         let user_id = Uuid::parse_str("0189a30a-60c7-7135-b683-7d7f3783d4b7").unwrap();
@@ -242,6 +657,10 @@ mod tests {
             id: user_id,
             username: "testuser".to_string(),
             email: "testuser@test.com".to_string(),
+            deleted_at: None,
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
         };
         let result = repo.add_user(user.clone()).await;
         assert!(result.is_ok(), "User should be added successfully");
@@ -260,7 +679,7 @@ mod tests {
     #[serial]
     async fn get_user_id_by_nickname() {
         let pool = setup_test_db().expect("Failed to setup test database");
-        let repo = DbRepository { pool: pool.clone() };
+        let repo = DbRepository::from_pool(pool.clone());
         clear_test_db(&pool);
 
         let user_id = Uuid::parse_str("0189a30a-60c7-7135-b683-7d7f3783d4b7").unwrap();
@@ -268,6 +687,10 @@ mod tests {
             id: user_id,
             username: "testuser".to_string(),
             email: "testuser@test.com".to_string(),
+            deleted_at: None,
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
         };
         let result = repo.add_user(user.clone()).await;
         assert!(result.is_ok(), "User should be added successfully");
@@ -282,11 +705,51 @@ mod tests {
         assert_eq!(fetched_user_id.unwrap(), user_id);
     }
 
+    #[tokio::test]
+    #[serial]
+    async fn get_users_in_batch() {
+        let pool = setup_test_db().expect("Failed to setup test database");
+        let repo = DbRepository::from_pool(pool.clone());
+        clear_test_db(&pool);
+
+        let active_id = Uuid::now_v7();
+        let deleted_id = Uuid::now_v7();
+        for (user_id, name) in [(active_id, "Streamer"), (deleted_id, "viewer")] {
+            let user = User {
+                id: user_id,
+                username: name.to_string(),
+                email: format!("{}@test.com", name),
+                deleted_at: None,
+                version: 1,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            };
+            repo.add_user(user).await.unwrap();
+        }
+        repo.delete_user(&deleted_id).await.unwrap();
+
+        let names = vec![
+            "streamer".to_string(),
+            "viewer".to_string(),
+            "ghost".to_string(),
+        ];
+        let fetched = repo.get_users_by_nicknames(&names).await.unwrap();
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched[0].id, active_id);
+
+        let fetched = repo
+            .get_users_by_ids(&[active_id, deleted_id, Uuid::now_v7()])
+            .await
+            .unwrap();
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched[0].username, "Streamer");
+    }
+
     #[tokio::test]
     #[serial]
     async fn update_user_by_id() {
         let pool = setup_test_db().expect("Failed to setup test database");
-        let repo = DbRepository { pool: pool.clone() };
+        let repo = DbRepository::from_pool(pool.clone());
         clear_test_db(&pool);
 
         let user_id = Uuid::parse_str("0189a30a-60c7-7135-b683-7d7f3783d4b7").unwrap();
@@ -294,6 +757,10 @@ mod tests {
             id: user_id,
             username: "testuser".to_string(),
             email: "testuser@test.com".to_string(),
+            deleted_at: None,
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
         };
         let result = repo.add_user(user.clone()).await;
         assert!(result.is_ok(), "User should be added successfully");
@@ -302,6 +769,10 @@ mod tests {
             id: user_id,
             username: "updateduser".to_string(),
             email: "updateduser@test.com".to_string(),
+            deleted_at: None,
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
         };
         let result = repo.update_user_by_id(&user_id, updated_user.clone()).await;
         assert!(result.is_ok(), "User should be updated successfully");
@@ -315,11 +786,50 @@ mod tests {
         assert_eq!(fetched_user.unwrap().username, "updateduser");
     }
 
+    #[tokio::test]
+    #[serial]
+    async fn update_user_version_conflict() {
+        let pool = setup_test_db().expect("Failed to setup test database");
+        let repo = DbRepository::from_pool(pool.clone());
+        clear_test_db(&pool);
+
+        let user_id = Uuid::now_v7();
+        let user = User {
+            id: user_id,
+            username: "testuser".to_string(),
+            email: "testuser@test.com".to_string(),
+            deleted_at: None,
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        repo.add_user(user.clone()).await.unwrap();
+
+        let updated_user = User {
+            email: "first@test.com".to_string(),
+            ..user.clone()
+        };
+        let result = repo.update_user_by_id(&user_id, updated_user).await;
+        assert!(matches!(result, Ok(Some(()))));
+
+        let stale_user = User {
+            email: "second@test.com".to_string(),
+            ..user
+        };
+        let result = repo.update_user_by_id(&user_id, stale_user).await;
+        assert!(matches!(result, Err(RepoError::VersionConflict)));
+
+        let fetched_user = repo.get_user(&user_id).await.unwrap().unwrap();
+        assert_eq!(fetched_user.email, "first@test.com");
+        assert_eq!(fetched_user.version, 2);
+        assert!(fetched_user.updated_at > fetched_user.created_at);
+    }
+
     #[tokio::test]
     #[serial]
     async fn update_user_by_nickname() {
         let pool = setup_test_db().expect("Failed to setup test database");
-        let repo = DbRepository { pool: pool.clone() };
+        let repo = DbRepository::from_pool(pool.clone());
         clear_test_db(&pool);
 
         let user_id = Uuid::parse_str("0189a30a-60c7-7135-b683-7d7f3783d4b7").unwrap();
@@ -327,6 +837,10 @@ mod tests {
             id: user_id,
             username: "testuser".to_string(),
             email: "testuser@test.com".to_string(),
+            deleted_at: None,
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
         };
         let result = repo.add_user(user.clone()).await;
         assert!(result.is_ok(), "User should be added successfully");
@@ -335,6 +849,10 @@ mod tests {
             id: user_id,
             username: "updateduser".to_string(),
             email: "updateduser@test.com".to_string(),
+            deleted_at: None,
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
         };
         let result = repo
             .update_user_by_nickname("testuser", updated_user.clone())
@@ -349,5 +867,281 @@ mod tests {
         assert!(fetched_user.is_some());
         assert_eq!(fetched_user.unwrap().username, "updateduser");
     }
-}
 
+    #[tokio::test]
+    #[serial]
+    async fn soft_delete_and_restore_user() {
+        let pool = setup_test_db().expect("Failed to setup test database");
+        let repo = DbRepository::from_pool(pool.clone());
+        clear_test_db(&pool);
+
+        let user_id = Uuid::parse_str("0189a30a-60c7-7135-b683-7d7f3783d4b7").unwrap();
+        let user = User {
+            id: user_id,
+            username: "testuser".to_string(),
+            email: "testuser@test.com".to_string(),
+            deleted_at: None,
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        repo.add_user(user.clone()).await.unwrap();
+
+        let result = repo.delete_user(&user_id).await.unwrap();
+        assert!(result.is_some(), "User should be deleted");
+        assert!(repo.get_user(&user_id).await.unwrap().is_none());
+        assert!(repo
+            .get_user_id_by_nickname("testuser")
+            .await
+            .unwrap()
+            .is_none());
+        let query = UsersPageQuery {
+            limit: 10,
+            ..Default::default()
+        };
+        assert!(repo.get_all_users(&query).await.unwrap().is_empty());
+        assert!(repo
+            .update_user_by_id(&user_id, user.clone())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(repo.delete_user(&user_id).await.unwrap().is_none());
+
+        let result = repo.restore_user(&user_id).await.unwrap();
+        assert!(result.is_some(), "User should be restored");
+        assert!(repo.get_user(&user_id).await.unwrap().is_some());
+        assert!(repo.restore_user(&user_id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn purge_deleted_users() {
+        let pool = setup_test_db().expect("Failed to setup test database");
+        let repo = DbRepository::from_pool(pool.clone());
+        clear_test_db(&pool);
+
+        let deleted_id = Uuid::now_v7();
+        let active_id = Uuid::now_v7();
+        for (user_id, name) in [(deleted_id, "deleted"), (active_id, "active")] {
+            let user = User {
+                id: user_id,
+                username: name.to_string(),
+                email: format!("{}@test.com", name),
+                deleted_at: None,
+                version: 1,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            };
+            repo.add_user(user).await.unwrap();
+        }
+        repo.delete_user(&deleted_id).await.unwrap();
+
+        let purged = repo
+            .purge_deleted_users(Utc::now() - Duration::days(1))
+            .await
+            .unwrap();
+        assert_eq!(purged, 0, "Grace period has not expired yet");
+
+        let purged = repo
+            .purge_deleted_users(Utc::now() + Duration::seconds(1))
+            .await
+            .unwrap();
+        assert_eq!(purged, 1);
+        assert!(repo.restore_user(&deleted_id).await.unwrap().is_none());
+        assert!(repo.get_user(&active_id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn unique_username_and_email() {
+        let pool = setup_test_db().expect("Failed to setup test database");
+        let repo = DbRepository::from_pool(pool.clone());
+        clear_test_db(&pool);
+
+        let user_id = Uuid::now_v7();
+        let user = User {
+            id: user_id,
+            username: "TestUser".to_string(),
+            email: "testuser@test.com".to_string(),
+            deleted_at: None,
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        repo.add_user(user.clone()).await.unwrap();
+
+        let result = repo.add_user(user.clone()).await;
+        assert!(matches!(result, Err(RepoError::AlreadyExists(field)) if field == "UUID"));
+
+        let duplicate_name = User {
+            id: Uuid::now_v7(),
+            username: "testUSER".to_string(),
+            email: "other@test.com".to_string(),
+            deleted_at: None,
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        let result = repo.add_user(duplicate_name).await;
+        assert!(matches!(result, Err(RepoError::AlreadyExists(field)) if field == "username"));
+
+        let other_id = Uuid::now_v7();
+        let other_user = User {
+            id: other_id,
+            username: "other".to_string(),
+            email: "TESTUSER@test.com".to_string(),
+            deleted_at: None,
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        let result = repo.add_user(other_user.clone()).await;
+        assert!(matches!(result, Err(RepoError::AlreadyExists(field)) if field == "email"));
+
+        let other_user = User {
+            email: "other@test.com".to_string(),
+            ..other_user
+        };
+        repo.add_user(other_user.clone()).await.unwrap();
+        let renamed = User {
+            username: "TESTUSER".to_string(),
+            ..other_user
+        };
+        let result = repo.update_user_by_id(&other_id, renamed).await;
+        assert!(matches!(result, Err(RepoError::AlreadyExists(field)) if field == "username"));
+
+        let fetched_user_id = repo.get_user_id_by_nickname("testuser").await.unwrap();
+        assert_eq!(fetched_user_id, Some(user_id));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn nickname_lookup_non_ascii() {
+        let pool = setup_test_db().expect("Failed to setup test database");
+        let repo = DbRepository::from_pool(pool.clone());
+        clear_test_db(&pool);
+
+        let user_id = Uuid::now_v7();
+        let user = User {
+            id: user_id,
+            username: "Вася_Pupkin".to_string(),
+            email: "vasya@test.com".to_string(),
+            deleted_at: None,
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        repo.add_user(user).await.unwrap();
+
+        let fetched_user_id = repo.get_user_id_by_nickname("Вася_Pupkin").await.unwrap();
+        assert_eq!(fetched_user_id, Some(user_id));
+        let fetched_user_id = repo.get_user_id_by_nickname("Вася_PUPKIN").await.unwrap();
+        assert_eq!(fetched_user_id, Some(user_id));
+
+        let fetched = repo
+            .get_users_by_nicknames(&["Вася_pupkin".to_string(), "Петя".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched[0].id, user_id);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn confusable_usernames_rejected() {
+        let pool = setup_test_db().expect("Failed to setup test database");
+        let repo = DbRepository::from_pool(pool.clone());
+        clear_test_db(&pool);
+
+        let user = |user_name: &str, user_email: &str| User {
+            id: Uuid::now_v7(),
+            username: user_name.to_string(),
+            email: user_email.to_string(),
+            deleted_at: None,
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        repo.add_user(user("admin_anna", "anna@test.com"))
+            .await
+            .unwrap();
+
+        // Кириллические "а" вместо латинских
+        let result = repo.add_user(user("аdmin_аnna", "fake@test.com")).await;
+        assert!(matches!(result, Err(RepoError::ConfusableUsername)));
+
+        let other = user("someone", "someone@test.com");
+        repo.add_user(other.clone()).await.unwrap();
+        let renamed = User {
+            username: "Admin_Аnna".to_string(),
+            ..other.clone()
+        };
+        let result = repo.update_user_by_id(&other.id, renamed).await;
+        assert!(matches!(result, Err(RepoError::ConfusableUsername)));
+
+        // Своё имя не конфликтует само с собой
+        let renamed = User {
+            username: "S0meone".to_string(),
+            ..other.clone()
+        };
+        repo.update_user_by_id(&other.id, renamed).await.unwrap();
+
+        let conn = &mut pool.get().unwrap();
+        let skeletons: Vec<Option<String>> = users
+            .select(crate::adapters::schema::users::username_skeleton)
+            .order(crate::adapters::schema::users::id)
+            .load(conn)
+            .unwrap();
+        assert_eq!(
+            skeletons,
+            vec![
+                Some(username_skeleton("admin_anna")),
+                Some(username_skeleton("someone"))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn confusable_legacy_user_keeps_updating() {
+        let pool = setup_test_db().expect("Failed to setup test database");
+        let repo = DbRepository::from_pool(pool.clone());
+        clear_test_db(&pool);
+
+        let user = |user_name: &str, user_email: &str| User {
+            id: Uuid::now_v7(),
+            username: user_name.to_string(),
+            email: user_email.to_string(),
+            deleted_at: None,
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        repo.add_user(user("admin_anna", "anna@test.com"))
+            .await
+            .unwrap();
+        // Строка, созданная до проверки скелетов
+        let legacy = user("аdmin_аnna", "legacy@test.com");
+        diesel::insert_into(users)
+            .values(&legacy)
+            .execute(&mut pool.get().unwrap())
+            .unwrap();
+
+        let new_email = User {
+            email: "legacy_new@test.com".to_string(),
+            ..legacy.clone()
+        };
+        repo.update_user_by_id(&legacy.id, new_email).await.unwrap();
+        let fetched = repo.get_user(&legacy.id).await.unwrap().unwrap();
+        assert_eq!(fetched.email, "legacy_new@test.com");
+        assert_eq!(fetched.version, 2);
+
+        // Смена имени на другое похожее по-прежнему отклоняется
+        let renamed = User {
+            username: "Аdmin_anna".to_string(),
+            ..fetched
+        };
+        let result = repo.update_user_by_id(&legacy.id, renamed).await;
+        assert!(matches!(result, Err(RepoError::ConfusableUsername)));
+    }
+}
@@ -0,0 +1,162 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use opentelemetry::trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{global, Context};
+use tonic::async_trait;
+use uuid::Uuid;
+
+use crate::errors::RepoError;
+use crate::metrics;
+use crate::repo::UserRepository;
+use crate::telemetry::SERVICE_NAME;
+use crate::types::{User, UsersPageQuery};
+
+/// Обёртка над репозиторием: длительность каждого вызова в метриках
+/// и дочерний спан текущей трассировки
+pub struct InstrumentedRepository<R> {
+    inner: Arc<R>,
+}
+
+impl<R: UserRepository> InstrumentedRepository<R> {
+    pub fn new(inner: Arc<R>) -> Self {
+        InstrumentedRepository { inner }
+    }
+}
+
+async fn observe<T>(
+    method: &str, call: impl Future<Output = Result<T, RepoError>>,
+) -> Result<T, RepoError> {
+    // Фоновые задачи (ping, очистка) не порождают отдельных трассировок,
+    // спаны создаются только внутри RPC
+    if !Context::current().has_active_span() {
+        let started = Instant::now();
+        let result = call.await;
+        metrics::observe_repository(method, result.is_ok(), started.elapsed());
+        return result;
+    }
+
+    let tracer = global::tracer(SERVICE_NAME);
+    let span = tracer
+        .span_builder(format!("UserRepository/{}", method))
+        .with_kind(SpanKind::Internal)
+        .start(&tracer);
+    let otel_cx = Context::current_with_span(span);
+
+    let started = Instant::now();
+    let result = call.with_context(otel_cx.clone()).await;
+    metrics::observe_repository(method, result.is_ok(), started.elapsed());
+
+    let span = otel_cx.span();
+    if let Err(e) = &result {
+        span.set_status(Status::error(e.to_string()));
+    }
+    span.end();
+    result
+}
+
+#[async_trait]
+impl<R: UserRepository> UserRepository for InstrumentedRepository<R> {
+    async fn add_user(&self, user: User) -> Result<(), RepoError> {
+        observe("add_user", self.inner.add_user(user)).await
+    }
+
+    async fn get_all_users(&self, query: &UsersPageQuery) -> Result<Vec<User>, RepoError> {
+        observe("get_all_users", self.inner.get_all_users(query)).await
+    }
+
+    async fn get_user(&self, user_id: &Uuid) -> Result<Option<User>, RepoError> {
+        observe("get_user", self.inner.get_user(user_id)).await
+    }
+
+    async fn get_user_id(&self, user_id: &Uuid) -> Result<Option<Uuid>, RepoError> {
+        observe("get_user_id", self.inner.get_user_id(user_id)).await
+    }
+
+    async fn get_user_id_by_nickname(&self, user_name: &str) -> Result<Option<Uuid>, RepoError> {
+        observe(
+            "get_user_id_by_nickname",
+            self.inner.get_user_id_by_nickname(user_name),
+        )
+        .await
+    }
+
+    async fn get_users_by_nicknames(&self, user_names: &[String]) -> Result<Vec<User>, RepoError> {
+        observe(
+            "get_users_by_nicknames",
+            self.inner.get_users_by_nicknames(user_names),
+        )
+        .await
+    }
+
+    async fn get_users_by_ids(&self, user_ids: &[Uuid]) -> Result<Vec<User>, RepoError> {
+        observe("get_users_by_ids", self.inner.get_users_by_ids(user_ids)).await
+    }
+
+    async fn update_user_by_id(
+        &self, user_id: &Uuid, updated_user: User,
+    ) -> Result<Option<()>, RepoError> {
+        observe(
+            "update_user_by_id",
+            self.inner.update_user_by_id(user_id, updated_user),
+        )
+        .await
+    }
+
+    async fn update_user_by_nickname(
+        &self, nick_name: &str, updated_user: User,
+    ) -> Result<Option<()>, RepoError> {
+        observe(
+            "update_user_by_nickname",
+            self.inner.update_user_by_nickname(nick_name, updated_user),
+        )
+        .await
+    }
+
+    async fn delete_user(&self, user_id: &Uuid) -> Result<Option<()>, RepoError> {
+        observe("delete_user", self.inner.delete_user(user_id)).await
+    }
+
+    async fn restore_user(&self, user_id: &Uuid) -> Result<Option<()>, RepoError> {
+        observe("restore_user", self.inner.restore_user(user_id)).await
+    }
+
+    async fn purge_deleted_users(&self, deleted_before: DateTime<Utc>) -> Result<usize, RepoError> {
+        observe(
+            "purge_deleted_users",
+            self.inner.purge_deleted_users(deleted_before),
+        )
+        .await
+    }
+
+    async fn ping(&self) -> Result<(), RepoError> {
+        observe("ping", self.inner.ping()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::internal::InternalRepository;
+
+    #[tokio::test]
+    async fn records_repository_calls() {
+        let repository = InstrumentedRepository::new(Arc::new(InternalRepository::new()));
+        repository.ping().await.unwrap();
+        assert!(repository
+            .delete_user(&Uuid::now_v7())
+            .await
+            .unwrap()
+            .is_none());
+
+        let text = metrics::encode();
+        assert!(text.contains(
+            "user_service_repository_duration_seconds_count{method=\"ping\",result=\"ok\"} 1"
+        ));
+        assert!(text.contains(
+            "user_service_repository_duration_seconds_count{method=\"delete_user\",result=\"ok\"} 1"
+        ));
+    }
+}
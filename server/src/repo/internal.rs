@@ -1,58 +1,186 @@
+use crate::app::validation::username_skeleton;
 use crate::repo::{RepoError, UserRepository};
-use crate::types::User;
+use crate::types::{User, UsersPageQuery};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
-use std::sync::Arc;
+use std::cmp::Reverse;
+use std::sync::{Arc, Mutex};
 use uuid::Uuid;
 
 pub struct InternalRepository {
     storage: Arc<DashMap<Uuid, User>>,
+    /// Сериализует проверку уникальности и запись, как это делают индексы в БД
+    write_lock: Mutex<()>,
 }
 
 impl InternalRepository {
     pub fn new() -> Self {
         InternalRepository {
             storage: Arc::new(DashMap::new()),
+            write_lock: Mutex::new(()),
         }
     }
+
+    /// Аналог уникальных индексов по lower(username) и lower(email) и проверки
+    /// скелета имени, мягко удалённые пользователи тоже учитываются.
+    /// Скелет проверяется только для нового или изменённого имени
+    fn check_unique(
+        &self, user_id: &Uuid, user_name: &str, user_email: &str, check_skeleton: bool,
+    ) -> Result<(), RepoError> {
+        let skeleton = username_skeleton(user_name);
+        let user_name = user_name.to_lowercase();
+        let user_email = user_email.to_lowercase();
+        for kv in self.storage.iter().filter(|kv| kv.key() != user_id) {
+            if kv.value().username.to_lowercase() == user_name {
+                return Err(RepoError::AlreadyExists("username".to_string()));
+            }
+            if kv.value().email.to_lowercase() == user_email {
+                return Err(RepoError::AlreadyExists("email".to_string()));
+            }
+        }
+        if check_skeleton
+            && self
+                .storage
+                .iter()
+                .filter(|kv| kv.key() != user_id)
+                .any(|kv| username_skeleton(&kv.value().username) == skeleton)
+        {
+            return Err(RepoError::ConfusableUsername);
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
 impl UserRepository for InternalRepository {
     async fn add_user(&self, user: User) -> Result<(), RepoError> {
+        let _guard = self.write_lock.lock().unwrap();
+        if self.storage.contains_key(&user.id) {
+            return Err(RepoError::AlreadyExists("UUID".to_string()));
+        }
+        self.check_unique(&user.id, &user.username, &user.email, true)?;
         self.storage.insert(user.id, user);
         Ok(())
     }
 
-    async fn get_all_users(&self) -> Result<Vec<User>, RepoError> {
-        let users: Vec<User> = self.storage.iter().map(|kv| kv.value().clone()).collect();
+    async fn get_all_users(&self, query: &UsersPageQuery) -> Result<Vec<User>, RepoError> {
+        let username_prefix = query.username_prefix.as_ref().map(|p| p.to_lowercase());
+        let email_suffix = query
+            .email_domain
+            .as_ref()
+            .map(|d| format!("@{}", d.to_lowercase()));
+
+        let mut users: Vec<User> = self
+            .storage
+            .iter()
+            .filter(|kv| kv.value().deleted_at.is_none())
+            .map(|kv| kv.value().clone())
+            .filter(|user| match query.after {
+                Some(after) if query.newest_first => user.id < after,
+                Some(after) => user.id > after,
+                None => true,
+            })
+            .filter(|user| {
+                username_prefix
+                    .as_ref()
+                    .is_none_or(|p| user.username.to_lowercase().starts_with(p))
+            })
+            .filter(|user| {
+                email_suffix
+                    .as_ref()
+                    .is_none_or(|s| user.email.to_lowercase().ends_with(s))
+            })
+            .filter(|user| query.created_after.is_none_or(|at| user.created_at >= at))
+            .filter(|user| query.created_before.is_none_or(|at| user.created_at < at))
+            .collect();
+
+        if query.newest_first {
+            users.sort_by_key(|user| Reverse(user.id));
+        } else {
+            users.sort_by_key(|user| user.id);
+        }
+        users.truncate(query.limit);
         Ok(users)
     }
 
     async fn get_user(&self, user_id: &Uuid) -> Result<Option<User>, RepoError> {
-        Ok(self.storage.get(user_id).map(|user| user.clone()))
+        Ok(self
+            .storage
+            .get(user_id)
+            .filter(|user| user.deleted_at.is_none())
+            .map(|user| user.clone()))
     }
 
     async fn get_user_id(&self, user_id: &Uuid) -> Result<Option<Uuid>, RepoError> {
-        Ok(self.storage.get(user_id).map(|kv| *kv.key()))
+        Ok(self
+            .storage
+            .get(user_id)
+            .filter(|kv| kv.value().deleted_at.is_none())
+            .map(|kv| *kv.key()))
     }
 
     async fn get_user_id_by_nickname(&self, user_name: &str) -> Result<Option<Uuid>, RepoError> {
         Ok(self
             .storage
             .iter()
-            .find(|kv| kv.value().username == user_name)
+            .find(|kv| {
+                kv.value().deleted_at.is_none()
+                    && kv.value().username.to_lowercase() == user_name.to_lowercase()
+            })
             .map(|kv| *kv.key()))
     }
 
+    async fn get_users_by_nicknames(&self, user_names: &[String]) -> Result<Vec<User>, RepoError> {
+        let lowercase_names: Vec<String> = user_names.iter().map(|n| n.to_lowercase()).collect();
+        Ok(self
+            .storage
+            .iter()
+            .filter(|kv| {
+                kv.value().deleted_at.is_none()
+                    && lowercase_names.contains(&kv.value().username.to_lowercase())
+            })
+            .map(|kv| kv.value().clone())
+            .collect())
+    }
+
+    async fn get_users_by_ids(&self, user_ids: &[Uuid]) -> Result<Vec<User>, RepoError> {
+        Ok(user_ids
+            .iter()
+            .filter_map(|user_id| self.storage.get(user_id))
+            .filter(|user| user.deleted_at.is_none())
+            .map(|user| user.clone())
+            .collect())
+    }
+
     async fn update_user_by_id(
         &self, user_id: &Uuid, updated_user: User,
     ) -> Result<Option<()>, RepoError> {
-        if self.storage.contains_key(user_id) {
-            self.storage.insert(*user_id, updated_user);
-            Ok(Some(()))
-        } else {
-            Ok(None)
+        let _guard = self.write_lock.lock().unwrap();
+        let (current_version, username_changed) = match self.storage.get(user_id) {
+            Some(user) if user.deleted_at.is_none() => {
+                (user.version, user.username != updated_user.username)
+            }
+            _ => return Ok(None),
+        };
+        if current_version != updated_user.version {
+            return Err(RepoError::VersionConflict);
+        }
+        self.check_unique(
+            user_id,
+            &updated_user.username,
+            &updated_user.email,
+            username_changed,
+        )?;
+        match self.storage.get_mut(user_id) {
+            Some(mut user) if user.deleted_at.is_none() => {
+                user.username = updated_user.username;
+                user.email = updated_user.email;
+                user.version += 1;
+                user.updated_at = Utc::now();
+                Ok(Some(()))
+            }
+            _ => Ok(None),
         }
     }
 
@@ -65,4 +193,44 @@ impl UserRepository for InternalRepository {
             Ok(None)
         }
     }
+
+    async fn delete_user(&self, user_id: &Uuid) -> Result<Option<()>, RepoError> {
+        match self.storage.get_mut(user_id) {
+            Some(mut user) if user.deleted_at.is_none() => {
+                user.deleted_at = Some(Utc::now());
+                user.version += 1;
+                user.updated_at = Utc::now();
+                Ok(Some(()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn restore_user(&self, user_id: &Uuid) -> Result<Option<()>, RepoError> {
+        match self.storage.get_mut(user_id) {
+            Some(mut user) if user.deleted_at.is_some() => {
+                user.deleted_at = None;
+                user.version += 1;
+                user.updated_at = Utc::now();
+                Ok(Some(()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn purge_deleted_users(&self, deleted_before: DateTime<Utc>) -> Result<usize, RepoError> {
+        let mut purged = 0;
+        self.storage.retain(|_, user| {
+            let keep = user.deleted_at.is_none_or(|at| at >= deleted_before);
+            if !keep {
+                purged += 1;
+            }
+            keep
+        });
+        Ok(purged)
+    }
+
+    async fn ping(&self) -> Result<(), RepoError> {
+        Ok(())
+    }
 }
@@ -1,8 +1,12 @@
-use crate::types::User;
+use crate::types::{User, UsersPageQuery};
+use chrono::{DateTime, Utc};
 use tonic::async_trait;
 use uuid::Uuid;
 
 mod database;
+pub mod instrumented;
+// Хранилище в памяти для тестов
+#[cfg(test)]
 pub mod internal;
 
 use crate::errors::RepoError;
@@ -11,10 +15,29 @@ use crate::errors::RepoError;
 #[async_trait]
 pub trait UserRepository: Send + Sync {
     async fn add_user(&self, user: User) -> Result<(), RepoError>;
-    async fn get_all_users(&self) -> Result<Vec<User>, RepoError>;
+    async fn get_all_users(&self, query: &UsersPageQuery) -> Result<Vec<User>, RepoError>;
     async fn get_user(&self, user_id: &Uuid) -> Result<Option<User>, RepoError>;
     async fn get_user_id(&self, user_id: &Uuid) -> Result<Option<Uuid>, RepoError>;
     async fn get_user_id_by_nickname(&self, user_name: &str) -> Result<Option<Uuid>, RepoError>;
-    async fn update_user_by_id(&self, user_id: &Uuid, updated_user: User, ) -> Result<Option<()>, RepoError>;
-    async fn update_user_by_nickname(&self, nick_name: &str, updated_user: User) -> Result<Option<()>, RepoError>;
+    /// Пакетный поиск по именам без учёта регистра, ненайденные имена пропускаются
+    async fn get_users_by_nicknames(&self, user_names: &[String]) -> Result<Vec<User>, RepoError>;
+    /// Пакетный поиск по ID, ненайденные ID пропускаются
+    async fn get_users_by_ids(&self, user_ids: &[Uuid]) -> Result<Vec<User>, RepoError>;
+    /// Обновление с проверкой версии: updated_user.version - ожидаемая текущая версия,
+    /// при несовпадении возвращается RepoError::VersionConflict
+    async fn update_user_by_id(
+        &self, user_id: &Uuid, updated_user: User,
+    ) -> Result<Option<()>, RepoError>;
+    #[allow(dead_code)]
+    async fn update_user_by_nickname(
+        &self, nick_name: &str, updated_user: User,
+    ) -> Result<Option<()>, RepoError>;
+    /// Мягкое удаление, None - активный пользователь не найден
+    async fn delete_user(&self, user_id: &Uuid) -> Result<Option<()>, RepoError>;
+    /// Восстановление мягко удалённого пользователя, None - удалённый пользователь не найден
+    async fn restore_user(&self, user_id: &Uuid) -> Result<Option<()>, RepoError>;
+    /// Окончательное удаление пользователей, удалённых раньше deleted_before
+    async fn purge_deleted_users(&self, deleted_before: DateTime<Utc>) -> Result<usize, RepoError>;
+    /// Проверка доступности хранилища для проверки готовности
+    async fn ping(&self) -> Result<(), RepoError>;
 }
@@ -0,0 +1,149 @@
+//! Трассировка OpenTelemetry. Спаны создаются через глобальный трейсер,
+//! без настроенного экспортёра он ничего не записывает, но контекст входящего
+//! traceparent всё равно распространяется и попадает в логи
+use std::fmt;
+use std::str::FromStr;
+
+use axum::http::HeaderMap;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::{TraceContextExt, TraceError};
+use opentelemetry::{global, Context};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+
+/// Имя сервиса в ресурсе спанов и имя трейсера
+pub const SERVICE_NAME: &str = "user-service";
+
+/// Куда отправлять спаны
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TracingExporter {
+    None,
+    /// Печать спанов в stdout, для локального запуска
+    Stdout,
+    /// OTLP/gRPC, например OpenTelemetry Collector или Jaeger
+    Otlp,
+}
+
+impl FromStr for TracingExporter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(TracingExporter::None),
+            "stdout" => Ok(TracingExporter::Stdout),
+            "otlp" => Ok(TracingExporter::Otlp),
+            _ => Err("must be one of none, stdout, otlp".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for TracingExporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TracingExporter::None => write!(f, "none"),
+            TracingExporter::Stdout => write!(f, "stdout"),
+            TracingExporter::Otlp => write!(f, "otlp"),
+        }
+    }
+}
+
+/// Настраивает распространение W3C traceparent и глобальный трейсер.
+/// Возвращённый провайдер нужно остановить при выходе, чтобы отправить накопленные спаны
+pub fn init_tracing(
+    exporter: TracingExporter, otlp_endpoint: &str,
+) -> Result<Option<SdkTracerProvider>, TraceError> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let builder = SdkTracerProvider::builder()
+        .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build());
+    let provider = match exporter {
+        TracingExporter::None => return Ok(None),
+        TracingExporter::Stdout => builder
+            .with_simple_exporter(opentelemetry_stdout::SpanExporter::default())
+            .build(),
+        TracingExporter::Otlp => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_tonic()
+                .with_endpoint(otlp_endpoint)
+                .build()?;
+            builder.with_batch_exporter(exporter).build()
+        }
+    };
+    global::set_tracer_provider(provider.clone());
+    Ok(Some(provider))
+}
+
+/// Контекст трассировки из заголовков входящего запроса (traceparent, tracestate)
+pub fn extract_context(headers: &HeaderMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Идентификаторы (trace_id, span_id) текущего спана для строки лога, None вне трассировки
+pub fn current_trace_ids() -> Option<(String, String)> {
+    let context = Context::current();
+    let span_context = context.span().span_context().clone();
+    span_context.is_valid().then(|| {
+        (
+            span_context.trace_id().to_string(),
+            span_context.span_id().to_string(),
+        )
+    })
+}
+
+/// Идентификатор запроса (x-request-id) в контексте OpenTelemetry: контекст уже
+/// переносится в обработчик и в потоки запросов к БД, поэтому идентификатор виден в их логах
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+pub fn current_request_id() -> Option<String> {
+    Context::current()
+        .get::<RequestId>()
+        .map(|request_id| request_id.0.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_extract_traceparent() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                .parse()
+                .unwrap(),
+        );
+
+        let context = extract_context(&headers);
+        let _guard = context.attach();
+        assert_eq!(
+            current_trace_ids(),
+            Some((
+                "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+                "00f067aa0ba902b7".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_no_trace_ids_outside_span() {
+        assert_eq!(current_trace_ids(), None);
+    }
+}
@@ -1,11 +1,54 @@
 use crate::adapters::schema::users;
-use diesel::{Insertable, Queryable};
+use chrono::{DateTime, Utc};
+use diesel::{Insertable, Queryable, Selectable};
+use lib_rpc::userpb;
+use prost_types::Timestamp;
+use std::time::SystemTime;
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Queryable, Insertable)]
+/// Скелет имени хранится только в БД, его вычисляет репозиторий
+#[derive(Debug, Clone, Queryable, Selectable, Insertable)]
 #[diesel(table_name = users)]
 pub struct User {
     pub id: Uuid,
     pub username: String,
     pub email: String,
+    /// Время мягкого удаления, None - активный пользователь
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Версия строки, увеличивается при каждом изменении пользователя
+    pub version: i64,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<User> for userpb::User {
+    fn from(user: User) -> Self {
+        userpb::User {
+            uuid: user.id.to_string(),
+            username: user.username,
+            email: user.email,
+            version: user.version,
+            created_at: Some(to_timestamp(user.created_at)),
+            updated_at: Some(to_timestamp(user.updated_at)),
+        }
+    }
+}
+
+pub fn to_timestamp(time: DateTime<Utc>) -> Timestamp {
+    SystemTime::from(time).into()
+}
+
+/// Параметры постраничной выборки пользователей.
+/// ID - UUIDv7, поэтому сортировка по ID совпадает с порядком регистрации,
+/// а курсором служит ID последнего пользователя предыдущей страницы
+#[derive(Debug, Clone, Default)]
+pub struct UsersPageQuery {
+    pub after: Option<Uuid>,
+    pub limit: usize,
+    pub username_prefix: Option<String>,
+    pub email_domain: Option<String>,
+    /// Диапазон времени регистрации: created_after включительно, created_before - нет
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub newest_first: bool,
 }
@@ -1,13 +1,23 @@
+use std::fs;
+use std::path::PathBuf;
+
 use clap::{Parser, ValueEnum};
 use futures::future::join_all;
 use lib_rpc::userpb::user_service_client::UserServiceClient;
 use lib_rpc::userpb::{
-    CreateUserRequest, GetAllUsersRequest, GetUserByIdRequest, GetUserRequest, UpdateUserRequest,
+    CreateUserRequest, DeleteUserRequest, GetAllUsersRequest, GetUserByIdRequest,
+    GetUserIdByNicknameRequest, GetUserRequest, GetUsersByIdsRequest, ResolveUsernamesRequest,
+    RestoreUserRequest, SortOrder, UpdateUserRequest,
 };
+use prost_types::{FieldMask, Timestamp};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use tokio::task;
-use tonic::transport::Channel;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
+use tonic::{Request, Status};
 use uuid::Uuid;
 
 #[derive(Debug, ValueEnum, Clone)]
@@ -16,8 +26,13 @@ enum Actions {
     CreateUser,
     Update,
     GetAll,
+    GetUser,
     GetUserIdByNickname,
+    ResolveUsernames,
+    GetUsersByIds,
     Generate,
+    Delete,
+    Restore,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -42,10 +57,109 @@ struct Args {
 
     #[arg(short = 'g', long, default_value_t = 1)]
     generate_count: usize,
+
+    #[arg(long, default_value_t = 100)]
+    page_size: u32,
+
+    #[arg(long, default_value_t = String::new())]
+    username_prefix: String,
+
+    #[arg(long, default_value_t = String::new())]
+    email_domain: String,
+
+    #[arg(long)]
+    newest_first: bool,
+
+    /// Unix-время в секундах
+    #[arg(long)]
+    created_after: Option<i64>,
+
+    /// Unix-время в секундах
+    #[arg(long)]
+    created_before: Option<i64>,
+
+    #[arg(long, value_delimiter = ',')]
+    usernames: Vec<String>,
+
+    #[arg(long, value_delimiter = ',')]
+    uuids: Vec<String>,
+
+    #[arg(long, value_delimiter = ',')]
+    update_mask: Vec<String>,
+
+    #[arg(long, default_value_t = 0)]
+    expected_version: i64,
+
+    /// JWT, отправляется в заголовке authorization: Bearer <token>
+    #[arg(long)]
+    token: Option<String>,
+
+    /// x-request-id, по нему запрос находится в логах сервера. Без него сервер создаёт свой
+    #[arg(long)]
+    request_id: Option<String>,
+
+    /// CA сертификата сервера в PEM, включает TLS
+    #[arg(long)]
+    ca_cert: Option<PathBuf>,
+
+    /// Сертификат клиента в PEM для mTLS
+    #[arg(long, requires_all = ["client_key", "ca_cert"])]
+    client_cert: Option<PathBuf>,
+
+    /// Ключ клиента в PEM для mTLS
+    #[arg(long, requires = "client_cert")]
+    client_key: Option<PathBuf>,
+
+    /// Имя сервера для проверки сертификата, по умолчанию --host
+    #[arg(long, requires = "ca_cert")]
+    tls_domain: Option<String>,
+}
+
+async fn connect(args: &Args) -> Result<Channel, Box<dyn std::error::Error>> {
+    let Some(ca_cert) = &args.ca_cert else {
+        let addr = format!("http://{}:{}", args.target_host, args.target_port);
+        return Ok(Channel::from_shared(addr)?.connect().await?);
+    };
+    let mut tls = ClientTlsConfig::new()
+        .ca_certificate(Certificate::from_pem(fs::read(ca_cert)?))
+        .domain_name(args.tls_domain.as_ref().unwrap_or(&args.target_host));
+    if let (Some(cert), Some(key)) = (&args.client_cert, &args.client_key) {
+        tls = tls.identity(Identity::from_pem(fs::read(cert)?, fs::read(key)?));
+    }
+    let addr = format!("https://{}:{}", args.target_host, args.target_port);
+    Ok(Channel::from_shared(addr)?
+        .tls_config(tls)?
+        .connect()
+        .await?)
+}
+
+type Client = UserServiceClient<InterceptedService<Channel, RequestMetadata>>;
+
+/// Добавляет токен и x-request-id к каждому запросу
+#[derive(Clone)]
+struct RequestMetadata {
+    token: Option<MetadataValue<Ascii>>,
+    request_id: Option<MetadataValue<Ascii>>,
+}
+
+impl Interceptor for RequestMetadata {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(token) = &self.token {
+            request
+                .metadata_mut()
+                .insert("authorization", token.clone());
+        }
+        if let Some(request_id) = &self.request_id {
+            request
+                .metadata_mut()
+                .insert("x-request-id", request_id.clone());
+        }
+        Ok(request)
+    }
 }
 
 async fn create_user(
-    client: &mut UserServiceClient<Channel>, user_uuid: &Uuid, user_name: &str, user_email: &str,
+    client: &mut Client, user_uuid: &Uuid, user_name: &str, user_email: &str,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let request = tonic::Request::new(CreateUserRequest {
         uuid: user_uuid.to_string(),
@@ -60,7 +174,7 @@ async fn create_user(
 }
 
 async fn get_user_data_by_id(
-    client: &mut UserServiceClient<Channel>, user_uuid: &Uuid,
+    client: &mut Client, user_uuid: &Uuid,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let request = tonic::Request::new(GetUserByIdRequest {
         uuid: user_uuid.to_string(),
@@ -73,24 +187,61 @@ async fn get_user_data_by_id(
 }
 
 async fn get_user(
-    client: &mut UserServiceClient<Channel>, username: String,
+    client: &mut Client, username: String,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let request = tonic::Request::new(GetUserRequest { username });
 
     let response = client.get_user(request).await?;
+    println!("GetUser={:?}", response);
+
+    Ok(())
+}
+
+async fn get_user_id_by_nickname(
+    client: &mut Client, username: String,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let request = tonic::Request::new(GetUserIdByNicknameRequest { username });
+
+    let response = client.get_user_id_by_nickname(request).await?;
     println!("GetUserIdByNickname={:?}", response);
 
     Ok(())
 }
 
+async fn resolve_usernames(
+    client: &mut Client, usernames: Vec<String>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let request = tonic::Request::new(ResolveUsernamesRequest { usernames });
+
+    let response = client.resolve_usernames(request).await?;
+    println!("ResolveUsernames={:?}", response);
+
+    Ok(())
+}
+
+async fn get_users_by_ids(
+    client: &mut Client, uuids: Vec<String>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let request = tonic::Request::new(GetUsersByIdsRequest { uuids });
+
+    let response = client.get_users_by_ids(request).await?;
+    println!("GetUsersByIds={:?}", response);
+
+    Ok(())
+}
+
 async fn update_user_data(
-    client: &mut UserServiceClient<Channel>, user_uuid: &Uuid, user_name: Option<&str>,
-    user_email: Option<&str>,
+    client: &mut Client, user_uuid: &Uuid, user_name: Option<&str>, user_email: Option<&str>,
+    update_mask: &[String], expected_version: i64,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let request = tonic::Request::new(UpdateUserRequest {
         uuid: user_uuid.to_string(),
         username: user_name.unwrap_or_default().to_string(),
         email: user_email.unwrap_or_default().to_string(),
+        update_mask: (!update_mask.is_empty()).then(|| FieldMask {
+            paths: update_mask.to_vec(),
+        }),
+        expected_version,
     });
 
     let response = client.update_user_data(request).await?;
@@ -100,24 +251,74 @@ async fn update_user_data(
 }
 
 async fn get_all_users(
-    client: &mut UserServiceClient<Channel>,
+    client: &mut Client, args: &Args,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let request = tonic::Request::new(GetAllUsersRequest {});
-    let response = client.get_all_users(request).await?;
-    println!("GetAllUsers={:?}", response);
+    let sort_order = if args.newest_first {
+        SortOrder::NewestFirst
+    } else {
+        SortOrder::OldestFirst
+    };
+    let mut page_token = String::new();
+    loop {
+        let request = tonic::Request::new(GetAllUsersRequest {
+            page_size: args.page_size,
+            page_token,
+            username_prefix: args.username_prefix.clone(),
+            email_domain: args.email_domain.clone(),
+            sort_order: sort_order.into(),
+            created_after: args
+                .created_after
+                .map(|seconds| Timestamp { seconds, nanos: 0 }),
+            created_before: args
+                .created_before
+                .map(|seconds| Timestamp { seconds, nanos: 0 }),
+        });
+        let response = client.get_all_users(request).await?;
+        println!("GetAllUsers={:?}", response);
 
-    let users = response.into_inner().users;
+        let response = response.into_inner();
+        for user in response.users {
+            println!(
+                "UUID: {}, Name: {}, Email: {}",
+                user.uuid, user.username, user.email
+            );
+        }
 
-    for user in users {
-        println!(
-            "UUID: {}, Name: {}, Email: {}",
-            user.uuid, user.username, user.email
-        );
+        if response.next_page_token.is_empty() {
+            break;
+        }
+        page_token = response.next_page_token;
     }
 
     Ok(())
 }
 
+async fn delete_user(
+    client: &mut Client, user_uuid: &Uuid,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let request = tonic::Request::new(DeleteUserRequest {
+        uuid: user_uuid.to_string(),
+    });
+
+    let response = client.delete_user(request).await?;
+    println!("DeleteUser={:?}", response);
+
+    Ok(())
+}
+
+async fn restore_user(
+    client: &mut Client, user_uuid: &Uuid,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let request = tonic::Request::new(RestoreUserRequest {
+        uuid: user_uuid.to_string(),
+    });
+
+    let response = client.restore_user(request).await?;
+    println!("RestoreUser={:?}", response);
+
+    Ok(())
+}
+
 fn generate_random_user() -> (Uuid, String, String) {
     let user_id = Uuid::now_v7();
     let user_name: String = thread_rng()
@@ -130,7 +331,7 @@ fn generate_random_user() -> (Uuid, String, String) {
 }
 
 async fn generate_users(
-    client: UserServiceClient<Channel>, count: usize,
+    client: Client, count: usize,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let mut futures = Vec::new();
     for _ in 0..count {
@@ -150,12 +351,23 @@ async fn generate_users(
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    let addr = format!("http://{}:{}", args.target_host, args.target_port);
 
-    let client = UserServiceClient::connect(addr.clone()).await?;
+    let token = args
+        .token
+        .as_ref()
+        .map(|token| format!("Bearer {}", token).parse())
+        .transpose()?;
+    let request_id = args.request_id.as_deref().map(str::parse).transpose()?;
+    let channel = connect(&args).await?;
+    let client =
+        UserServiceClient::with_interceptor(channel, RequestMetadata { token, request_id });
 
     match args.action {
-        Actions::CreateUser | Actions::GetUserDataById | Actions::Update => {
+        Actions::CreateUser
+        | Actions::GetUserDataById
+        | Actions::Update
+        | Actions::Delete
+        | Actions::Restore => {
             let user_uuid_str = args
                 .uuid
                 .as_deref()
@@ -179,19 +391,42 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         &user_uuid,
                         Some(&args.username),
                         Some(&args.email),
+                        &args.update_mask,
+                        args.expected_version,
                     )
                     .await
                     .unwrap();
                 }
+                Actions::Delete => {
+                    delete_user(&mut client.clone(), &user_uuid).await.unwrap();
+                }
+                Actions::Restore => {
+                    restore_user(&mut client.clone(), &user_uuid).await.unwrap();
+                }
                 _ => unreachable!(),
             }
         }
         Actions::GetAll => {
-            get_all_users(&mut client.clone()).await.unwrap();
+            get_all_users(&mut client.clone(), &args).await.unwrap();
         }
-        Actions::GetUserIdByNickname => {
+        Actions::GetUser => {
             get_user(&mut client.clone(), args.username).await.unwrap();
         }
+        Actions::GetUserIdByNickname => {
+            get_user_id_by_nickname(&mut client.clone(), args.username)
+                .await
+                .unwrap();
+        }
+        Actions::ResolveUsernames => {
+            resolve_usernames(&mut client.clone(), args.usernames)
+                .await
+                .unwrap();
+        }
+        Actions::GetUsersByIds => {
+            get_users_by_ids(&mut client.clone(), args.uuids)
+                .await
+                .unwrap();
+        }
         Actions::Generate => {
             generate_users(client, args.generate_count).await.unwrap();
         }